@@ -4,6 +4,9 @@ use std::{env, process::Command};
 fn main() {
     let out_dir = env::var("OUT_DIR").unwrap();
 
+    // Included by the .asm files, the loop below only tells cargo about those themselves
+    println!("cargo:rerun-if-changed=src/x86/gdt.inc");
+
     for entry in glob("src/**/*.asm").unwrap() {
         match entry {
             Ok(path) => {
@@ -12,7 +15,7 @@ fn main() {
                 let obj_path = format!("{out_dir}/{file_stem}.o");
 
                 let status = Command::new("nasm")
-                    .args(["-felf32", asm_path, "-o", &obj_path])
+                    .args(["-felf32", "-Isrc/x86/", asm_path, "-o", &obj_path])
                     .status()
                     .expect("nasm failed");
 
@@ -30,7 +30,11 @@ impl<'a> TagIterator<'a> {
         let tag_type = (*self.tag).type_;
         match tag_type {
             bindings::MULTIBOOT_TAG_TYPE_END => Some(MultibootTag::End),
-            bindings::MULTIBOOT_TAG_TYPE_CMDLINE => Some(MultibootTag::CmdLine),
+            bindings::MULTIBOOT_TAG_TYPE_CMDLINE => {
+                let string_tag = tag as *const bindings::multiboot_tag_string;
+                let cmdline = unsafe { core::ffi::CStr::from_ptr((*string_tag).string.as_ptr()) };
+                Some(MultibootTag::CmdLine(cmdline.to_str().unwrap_or("")))
+            }
             bindings::MULTIBOOT_TAG_TYPE_BOOT_LOADER_NAME => Some(MultibootTag::BootLoaderName),
             bindings::MULTIBOOT_TAG_TYPE_MODULE => Some(MultibootTag::Module),
             bindings::MULTIBOOT_TAG_TYPE_BASIC_MEMINFO => Some(MultibootTag::BasicMemInfo),
@@ -131,7 +135,7 @@ impl core::fmt::Debug for _MultibootMmapPart {
 #[derive(Debug)]
 pub enum MultibootTag<'a> {
     End,
-    CmdLine,
+    CmdLine(&'a str),
     BootLoaderName,
     Module,
     BasicMemInfo,
@@ -1,3 +1,5 @@
 pub mod e9;
+pub mod pci;
+pub mod rtc;
 pub mod serial;
 pub mod vga;
@@ -0,0 +1,420 @@
+use alloc::{ collections::btree_map::BTreeMap, vec::Vec };
+use core::ptr::read_unaligned;
+
+use uacpi::uacpi_table;
+
+use crate::{
+    debug,
+    info,
+    misc::{ acpi::find_table, isituninit::IsItUninit },
+    mm::{ virt_page_alloc, vmm },
+    sync::mutex::Mutex,
+    trace,
+    x86::ioport::{ inb, inl, inw, outb, outl, outw },
+};
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+// Every function gets 4KB of configuration space with ECAM, so every bus gets 32 devices * 8
+// functions * 4KB = 1MB
+const ECAM_BUS_SIZE: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        Self { segment, bus, device, function }
+    }
+
+    fn legacy_address(&self, offset: u16) -> u32 {
+        ((self.bus as u32) << 16) |
+            (((self.device as u32) & 0x1f) << 11) |
+            (((self.function as u32) & 0x7) << 8) |
+            ((offset as u32) & 0xfc) |
+            0x80000000
+    }
+}
+
+impl core::fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:04x}:{:02x}:{:02x}.{}", self.segment, self.bus, self.device, self.function)
+    }
+}
+
+#[derive(Debug)]
+pub enum PciError {
+    // The legacy mechanism can only reach segment 0 and the first 256 bytes of every function
+    Unreachable,
+    Unaligned,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+struct McfgEntry {
+    base_address: u64,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+    reserved: u32,
+}
+
+struct EcamRegion {
+    base_address: u64,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+pub struct PciConfigSpace {
+    ecam_regions: Vec<EcamRegion>,
+    // (segment, bus) -> virtual address of the mapped ECAM window of that bus
+    mapped_buses: BTreeMap<(u16, u8), usize>,
+}
+
+impl PciConfigSpace {
+    pub fn new() -> Self {
+        let mut ecam_regions = Vec::new();
+        let mut mcfg_table = uacpi_table::default();
+
+        if find_table(b"MCFG", &mut mcfg_table) {
+            let mcfg_address = unsafe { mcfg_table.__bindgen_anon_1.ptr as usize };
+            let length = unsafe { read_unaligned((mcfg_address + 4) as *const u32) } as usize;
+
+            // The entries start after the SDT header and 8 reserved bytes
+            let mut offset = 0x2c;
+            while offset + core::mem::size_of::<McfgEntry>() <= length {
+                let entry = unsafe { read_unaligned((mcfg_address + offset) as *const McfgEntry) };
+                let base_address = entry.base_address;
+                let segment = entry.segment;
+
+                debug!(
+                    "ECAM region at {base_address:#010X} (segment {segment}, buses {}-{})",
+                    entry.start_bus,
+                    entry.end_bus
+                );
+
+                if base_address > (u32::MAX as u64) {
+                    debug!("Skipping ECAM region above 4GB");
+                } else {
+                    ecam_regions.push(EcamRegion {
+                        base_address,
+                        segment,
+                        start_bus: entry.start_bus,
+                        end_bus: entry.end_bus,
+                    });
+                }
+
+                offset += core::mem::size_of::<McfgEntry>();
+            }
+        } else {
+            debug!("No MCFG table found, using legacy PCI configuration mechanism");
+        }
+
+        Self {
+            ecam_regions,
+            mapped_buses: BTreeMap::new(),
+        }
+    }
+
+    fn ecam_function(&mut self, addr: PciAddress) -> Option<usize> {
+        let bus_base = if let Some(virt) = self.mapped_buses.get(&(addr.segment, addr.bus)) {
+            *virt
+        } else {
+            let region = self.ecam_regions
+                .iter()
+                .find(|x| {
+                    x.segment == addr.segment && addr.bus >= x.start_bus && addr.bus <= x.end_bus
+                })?;
+
+            let phys =
+                (region.base_address as usize) +
+                ((addr.bus - region.start_bus) as usize) * ECAM_BUS_SIZE;
+            let page_count = ECAM_BUS_SIZE / 4096;
+            let virt = virt_page_alloc
+                ::allocate(page_count)
+                .expect("Could not allocate virtual pages for ECAM");
+
+            for i in 0..page_count as u32 {
+                vmm::map((phys as u32) + i * 4096, virt + i * 4096, false, true, true, true);
+            }
+
+            trace!(
+                "Mapped ECAM window of bus {:04x}:{:02x} ({phys:#010X} -> {virt:#010X})",
+                addr.segment,
+                addr.bus
+            );
+
+            self.mapped_buses.insert((addr.segment, addr.bus), virt as usize);
+            virt as usize
+        };
+
+        Some(
+            bus_base +
+                (((addr.device as usize) & 0x1f) << 15) +
+                (((addr.function as usize) & 0x7) << 12)
+        )
+    }
+
+    fn check_access(addr: PciAddress, offset: u16, size: u16) -> Result<(), PciError> {
+        if !offset.is_multiple_of(size) {
+            return Err(PciError::Unaligned);
+        }
+
+        if offset >= 4096 || (addr.device >= 32) || (addr.function >= 8) {
+            return Err(PciError::Unreachable);
+        }
+
+        Ok(())
+    }
+
+    fn check_legacy(addr: PciAddress, offset: u16) -> Result<(), PciError> {
+        if addr.segment != 0 || offset >= 256 {
+            Err(PciError::Unreachable)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn read8(&mut self, addr: PciAddress, offset: u16) -> Result<u8, PciError> {
+        Self::check_access(addr, offset, 1)?;
+
+        if let Some(func) = self.ecam_function(addr) {
+            return Ok(unsafe { core::ptr::read_volatile((func + (offset as usize)) as *const u8) });
+        }
+
+        Self::check_legacy(addr, offset)?;
+        outl(CONFIG_ADDRESS, addr.legacy_address(offset));
+        Ok(inb(CONFIG_DATA + (offset & 3)))
+    }
+
+    pub fn read16(&mut self, addr: PciAddress, offset: u16) -> Result<u16, PciError> {
+        Self::check_access(addr, offset, 2)?;
+
+        if let Some(func) = self.ecam_function(addr) {
+            return Ok(unsafe { core::ptr::read_volatile((func + (offset as usize)) as *const u16) });
+        }
+
+        Self::check_legacy(addr, offset)?;
+        outl(CONFIG_ADDRESS, addr.legacy_address(offset));
+        Ok(inw(CONFIG_DATA + (offset & 2)))
+    }
+
+    pub fn read32(&mut self, addr: PciAddress, offset: u16) -> Result<u32, PciError> {
+        Self::check_access(addr, offset, 4)?;
+
+        if let Some(func) = self.ecam_function(addr) {
+            return Ok(unsafe { core::ptr::read_volatile((func + (offset as usize)) as *const u32) });
+        }
+
+        Self::check_legacy(addr, offset)?;
+        outl(CONFIG_ADDRESS, addr.legacy_address(offset));
+        Ok(inl(CONFIG_DATA))
+    }
+
+    pub fn write8(&mut self, addr: PciAddress, offset: u16, val: u8) -> Result<(), PciError> {
+        Self::check_access(addr, offset, 1)?;
+
+        if let Some(func) = self.ecam_function(addr) {
+            unsafe {
+                core::ptr::write_volatile((func + (offset as usize)) as *mut u8, val);
+            }
+            return Ok(());
+        }
+
+        Self::check_legacy(addr, offset)?;
+        outl(CONFIG_ADDRESS, addr.legacy_address(offset));
+        outb(CONFIG_DATA + (offset & 3), val);
+        Ok(())
+    }
+
+    pub fn write16(&mut self, addr: PciAddress, offset: u16, val: u16) -> Result<(), PciError> {
+        Self::check_access(addr, offset, 2)?;
+
+        if let Some(func) = self.ecam_function(addr) {
+            unsafe {
+                core::ptr::write_volatile((func + (offset as usize)) as *mut u16, val);
+            }
+            return Ok(());
+        }
+
+        Self::check_legacy(addr, offset)?;
+        outl(CONFIG_ADDRESS, addr.legacy_address(offset));
+        outw(CONFIG_DATA + (offset & 2), val);
+        Ok(())
+    }
+
+    pub fn write32(&mut self, addr: PciAddress, offset: u16, val: u32) -> Result<(), PciError> {
+        Self::check_access(addr, offset, 4)?;
+
+        if let Some(func) = self.ecam_function(addr) {
+            unsafe {
+                core::ptr::write_volatile((func + (offset as usize)) as *mut u32, val);
+            }
+            return Ok(());
+        }
+
+        Self::check_legacy(addr, offset)?;
+        outl(CONFIG_ADDRESS, addr.legacy_address(offset));
+        outl(CONFIG_DATA, val);
+        Ok(())
+    }
+}
+
+impl Default for PciConfigSpace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+}
+
+// uACPI may touch the configuration space of the root bus while loading the namespace, which can
+// happen before init() is called. Until then only the legacy mechanism is used
+static CONFIG_SPACE: Mutex<IsItUninit<PciConfigSpace>> = Mutex::named(
+    "pci_config",
+    IsItUninit::uninit()
+);
+static DEVICES: Mutex<Vec<PciDevice>> = Mutex::new(Vec::new());
+
+fn with_config_space<T>(
+    f: impl FnOnce(&mut PciConfigSpace) -> Result<T, PciError>
+) -> Result<T, PciError> {
+    let mut lock = CONFIG_SPACE.lock();
+    if let Some(config_space) = lock.try_get_mut() {
+        f(config_space)
+    } else {
+        f(
+            &mut (PciConfigSpace {
+                ecam_regions: Vec::new(),
+                mapped_buses: BTreeMap::new(),
+            })
+        )
+    }
+}
+
+pub fn read8(addr: PciAddress, offset: u16) -> Result<u8, PciError> {
+    with_config_space(|x| x.read8(addr, offset))
+}
+
+pub fn read16(addr: PciAddress, offset: u16) -> Result<u16, PciError> {
+    with_config_space(|x| x.read16(addr, offset))
+}
+
+pub fn read32(addr: PciAddress, offset: u16) -> Result<u32, PciError> {
+    with_config_space(|x| x.read32(addr, offset))
+}
+
+pub fn write8(addr: PciAddress, offset: u16, val: u8) -> Result<(), PciError> {
+    with_config_space(|x| x.write8(addr, offset, val))
+}
+
+pub fn write16(addr: PciAddress, offset: u16, val: u16) -> Result<(), PciError> {
+    with_config_space(|x| x.write16(addr, offset, val))
+}
+
+pub fn write32(addr: PciAddress, offset: u16, val: u32) -> Result<(), PciError> {
+    with_config_space(|x| x.write32(addr, offset, val))
+}
+
+fn probe_function(addr: PciAddress) -> Option<PciDevice> {
+    let vendor_id = read16(addr, 0x00).ok()?;
+    if vendor_id == 0xffff {
+        return None;
+    }
+
+    let class_reg = read32(addr, 0x08).ok()?;
+
+    Some(PciDevice {
+        address: addr,
+        vendor_id,
+        device_id: read16(addr, 0x02).ok()?,
+        class: (class_reg >> 24) as u8,
+        subclass: (class_reg >> 16) as u8,
+        prog_if: (class_reg >> 8) as u8,
+        revision: class_reg as u8,
+        header_type: read8(addr, 0x0e).ok()?,
+    })
+}
+
+fn scan_bus(segment: u16, bus: u8, devices: &mut Vec<PciDevice>) {
+    for device in 0..32 {
+        let Some(dev) = probe_function(PciAddress::new(segment, bus, device, 0)) else {
+            continue;
+        };
+
+        let function_count = if (dev.header_type & 0x80) != 0 { 8 } else { 1 };
+
+        for function in 0..function_count {
+            let dev = if function == 0 {
+                dev
+            } else if let Some(dev) = probe_function(PciAddress::new(segment, bus, device, function)) {
+                dev
+            } else {
+                continue;
+            };
+
+            debug!(
+                "PCI {}: {:04x}:{:04x} class {:02x}.{:02x}.{:02x}",
+                dev.address,
+                dev.vendor_id,
+                dev.device_id,
+                dev.class,
+                dev.subclass,
+                dev.prog_if
+            );
+
+            devices.push(dev);
+
+            // PCI-to-PCI bridges have their own buses behind them
+            if (dev.header_type & 0x7f) == 0x01 {
+                let secondary_bus = read8(dev.address, 0x19).unwrap_or(0);
+                if secondary_bus > bus {
+                    scan_bus(segment, secondary_bus, devices);
+                }
+            }
+        }
+    }
+}
+
+pub fn init() {
+    let config_space = PciConfigSpace::new();
+    let mut root_buses: Vec<(u16, u8)> = config_space.ecam_regions
+        .iter()
+        .map(|x| (x.segment, x.start_bus))
+        .collect();
+
+    if root_buses.is_empty() {
+        root_buses.push((0, 0));
+    }
+
+    CONFIG_SPACE.lock().write(config_space);
+
+    let mut devices = Vec::new();
+    for (segment, bus) in root_buses {
+        scan_bus(segment, bus, &mut devices);
+    }
+
+    info!("Initialized PCI ({} functions found)", devices.len());
+    *DEVICES.lock() = devices;
+}
+
+pub fn devices() -> Vec<PciDevice> {
+    DEVICES.lock().clone()
+}
@@ -0,0 +1,252 @@
+use core::sync::atomic::{ AtomicBool, AtomicU32, Ordering };
+
+use crate::{
+    debug,
+    info,
+    misc::acpi::{ self, AcpiResource },
+    sync::{ mutex::Mutex, timer::monotonic_ns },
+    warning,
+    x86::ioport::{ inb, outb },
+};
+
+// PNP IDs of the AT RTC and its descendants with more CMOS memory
+const RTC_HIDS: [&str; 3] = ["PNP0B00", "PNP0B01", "PNP0B02"];
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+
+// Status A: an update is in progress and the time registers shouldn't be trusted
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+// In 12 hour mode, bit 7 of the hours register is set for PM
+const HOURS_PM: u8 = 1 << 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    // Days since 1970-01-01, using the days-from-civil algorithm which shifts the year to start
+    // in March so that the leap day is the last day of the year
+    fn days_since_epoch(&self) -> i64 {
+        let year = (self.year as i64) - (if self.month <= 2 { 1 } else { 0 });
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 +
+            (self.day as i64) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+        era * 146097 + day_of_era - 719468
+    }
+
+    pub fn to_unix(&self) -> u64 {
+        let days = self.days_since_epoch().max(0) as u64;
+        days * 86400 + (self.hour as u64) * 3600 + (self.minute as u64) * 60 + (self.second as u64)
+    }
+
+    pub fn from_unix(timestamp: u64) -> Self {
+        let days = (timestamp / 86400) as i64;
+        let seconds = timestamp % 86400;
+
+        // The inverse of days_since_epoch()
+        let days = days + 719468;
+        let era = days.div_euclid(146097);
+        let day_of_era = days - era * 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = year_of_era + era * 400 + (if month <= 2 { 1 } else { 0 });
+
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: ((seconds / 60) % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl core::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year,
+            self.month,
+            self.day,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+}
+
+struct Rtc {
+    port: u16,
+    century_register: Option<u8>,
+}
+
+impl Rtc {
+    fn read(&self, reg: u8) -> u8 {
+        outb(self.port, reg);
+        inb(self.port + 1)
+    }
+
+    fn read_raw(&self) -> [u8; 7] {
+        while (self.read(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS) != 0 {
+            core::hint::spin_loop();
+        }
+
+        [
+            self.read(REG_SECONDS),
+            self.read(REG_MINUTES),
+            self.read(REG_HOURS),
+            self.read(REG_DAY),
+            self.read(REG_MONTH),
+            self.read(REG_YEAR),
+            self.century_register.map(|x| self.read(x)).unwrap_or(0),
+        ]
+    }
+
+    fn read_time(&self) -> DateTime {
+        // An update can still start right after the UIP check, so read until we get the same
+        // values twice in a row
+        let mut raw = self.read_raw();
+        loop {
+            let again = self.read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+
+        let [second, minute, hour, day, month, year, century] = raw;
+        let status_b = self.read(REG_STATUS_B);
+
+        let decode = |x: u8| {
+            if (status_b & STATUS_B_BINARY) != 0 { x } else { (x & 0x0f) + (x >> 4) * 10 }
+        };
+
+        let pm = (hour & HOURS_PM) != 0;
+        let mut hour = decode(hour & !HOURS_PM);
+        if (status_b & STATUS_B_24_HOUR) == 0 {
+            // 12 AM is midnight and 12 PM is noon
+            hour %= 12;
+            if pm {
+                hour += 12;
+            }
+        }
+
+        let year = decode(year) as u16;
+        let year = if self.century_register.is_some() {
+            (decode(century) as u16) * 100 + year
+        } else if year < 70 {
+            2000 + year
+        } else {
+            1900 + year
+        };
+
+        DateTime {
+            year,
+            month: decode(month),
+            day: decode(day),
+            hour,
+            minute: decode(minute),
+            second: decode(second),
+        }
+    }
+}
+
+static RTC: Mutex<Option<Rtc>> = Mutex::new(None);
+
+// Wall clock time minus monotonic time, in nanoseconds. Split in halves since there are no 64-bit
+// atomics on i686, and only written before WALL_CLOCK_VALID is set
+static WALL_OFFSET_LOW: AtomicU32 = AtomicU32::new(0);
+static WALL_OFFSET_HIGH: AtomicU32 = AtomicU32::new(0);
+static WALL_CLOCK_VALID: AtomicBool = AtomicBool::new(false);
+
+pub fn init() {
+    let fadt = acpi::fadt_rtc_info();
+    let device = RTC_HIDS.iter().find_map(|x| acpi::find_device(x));
+
+    if device.is_none() && !fadt.rtc_present {
+        warning!("No CMOS RTC, the wall clock won't be set");
+        return;
+    }
+
+    let port = device
+        .as_ref()
+        .and_then(|x| {
+            x.io_ports().find_map(|x| {
+                match x {
+                    AcpiResource::Io { base, .. } => Some(base),
+                    _ => None,
+                }
+            })
+        })
+        .unwrap_or(0x70);
+
+    let rtc = Rtc {
+        port,
+        century_register: fadt.century_register,
+    };
+
+    let now = rtc.read_time();
+    let mono = monotonic_ns();
+    let wall = now.to_unix() * 1_000_000_000;
+    let offset = wall.wrapping_sub(mono);
+
+    WALL_OFFSET_LOW.store(offset as u32, Ordering::Relaxed);
+    WALL_OFFSET_HIGH.store((offset >> 32) as u32, Ordering::Relaxed);
+    WALL_CLOCK_VALID.store(true, Ordering::Release);
+
+    debug!("CMOS RTC at port {port:#x}, century register {:?}", fadt.century_register);
+    info!("The time is {now} UTC");
+
+    *RTC.lock() = Some(rtc);
+}
+
+// Reads the RTC itself instead of going by the monotonic clock
+pub fn read_time() -> Option<DateTime> {
+    RTC.lock().as_ref().map(|x| x.read_time())
+}
+
+pub fn wall_clock_valid() -> bool {
+    WALL_CLOCK_VALID.load(Ordering::Acquire)
+}
+
+// Nanoseconds since the UNIX epoch. 0 until the RTC has been read
+pub fn wall_clock_ns() -> u64 {
+    if !wall_clock_valid() {
+        return 0;
+    }
+
+    let offset =
+        ((WALL_OFFSET_HIGH.load(Ordering::Relaxed) as u64) << 32) |
+        (WALL_OFFSET_LOW.load(Ordering::Relaxed) as u64);
+    monotonic_ns().wrapping_add(offset)
+}
+
+// Seconds since the UNIX epoch. 0 until the RTC has been read
+pub fn wall_clock() -> u64 {
+    wall_clock_ns() / 1_000_000_000
+}
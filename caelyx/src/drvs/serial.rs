@@ -1,6 +1,6 @@
 use crate::{
     misc::{isituninit::IsItUninit, str_writer::StrWriter},
-    sync::mutex::Mutex,
+    sync::once::Once,
     x86::ioport::{inb, outb},
 };
 
@@ -14,7 +14,8 @@ pub struct SerialPort {
 
 const MAX_SERIAL_PORTS: usize = 4;
 type SerialPortArr = [IsItUninit<SerialPort>; MAX_SERIAL_PORTS];
-static PORTS: Mutex<IsItUninit<SerialPortArr>> = Mutex::new(IsItUninit::uninit());
+// Found once at boot and only read after that, so writing doesn't need a lock
+static PORTS: Once<SerialPortArr> = Once::new();
 
 impl SerialPort {
     pub const fn new(num: u8, port: u16) -> Self {
@@ -89,21 +90,21 @@ impl<'a> Iterator for SerialPortIterator<'a> {
 
 pub fn init() {
     let iter = SerialPortIterator::new(&[(1, 0x3F8), (2, 0x2F8), (3, 0x3E8), (4, 0x2E8)]);
-    let mut lock = PORTS.lock();
-    lock.write(SerialPortArr::default());
-
-    for (i, (idx, port)) in iter.enumerate() {
-        lock.get_mut()[i] = IsItUninit::init(SerialPort::new(idx, port));
-    }
+    PORTS.call_once(|| {
+        let mut ports = SerialPortArr::default();
+        for (i, (idx, port)) in iter.enumerate() {
+            ports[i] = IsItUninit::init(SerialPort::new(idx, port));
+        }
+        ports
+    });
 }
 
 fn write(c: char) {
-    let lock = PORTS.lock();
-    if !lock.initialized() {
+    let Some(ports) = PORTS.get() else {
         return;
-    }
+    };
 
-    for port in lock.get_ref() {
+    for port in ports {
         if let Some(port) = port.try_get_ref() {
             port.write(c);
         }
@@ -182,7 +182,7 @@ impl<'a> VGADriver<'a> {
     }
 }
 
-static INSTANCE: Mutex<IsItUninit<VGADriver>> = Mutex::new(IsItUninit::uninit());
+static INSTANCE: Mutex<IsItUninit<VGADriver>> = Mutex::named("vga", IsItUninit::uninit());
 
 pub fn init() {
     let mut drv = VGADriver::new();
@@ -8,26 +8,46 @@ extern crate alloc;
 
 use crate::{
     boot::multiboot2,
-    drvs::{ e9::init as e9_init, serial::init as serial_init, vga::init as vga_init },
+    drvs::{
+        e9::init as e9_init,
+        pci::init as pci_init,
+        rtc::init as rtc_init,
+        serial::init as serial_init,
+        vga::init as vga_init,
+    },
     misc::{
-        acpi::init as acpi_init,
+        acpi::{ init as acpi_init, init_namespace as acpi_init_namespace },
         output::{ flanterm::init as flanterm_init, logger::init as logger_init },
+        power::init as power_init,
     },
     mm::{
         heap::init as heap_init,
-        pmm::init as pmm_init,
+        pmm::{ init as pmm_init, lock_stats as pmm_lock_stats },
+        tlb::init as tlb_init,
         virt_page_alloc::init as virt_page_alloc_init,
         vmm::init as vmm_init,
     },
-    sync::timer::hpet::{ hpet_sleep, init as hpet_init },
-    x86::{ cpuid::print_cpuid, gdt::init as gdt_init, idt::init as idt_init },
+    sched::{ exit as exit_thread, init as sched_init },
+    sync::{ timer::init as timer_init, workqueue::init as workqueue_init },
+    syscall::init as syscall_init,
+    x86::{
+        cpuid::print_cpuid,
+        gdt::init as gdt_init,
+        idt::init as idt_init,
+        irq::init as irq_init,
+        percpu::init_bsp as percpu_init_bsp,
+        smp::init as smp_init,
+        tss::init as tss_init,
+    },
 };
 
 pub mod boot;
 pub mod drvs;
 pub mod misc;
 pub mod mm;
+pub mod sched;
 pub mod sync;
+pub mod syscall;
 pub mod x86;
 
 #[unsafe(no_mangle)]
@@ -40,7 +60,11 @@ extern "C" fn caelyx_kmain(mb2_info: *const ()) -> ! {
     flanterm_init(&mut tag_iter);
     tag_iter.reset_pos();
     logger_init();
+    power_init(&mut tag_iter);
+    tag_iter.reset_pos();
     gdt_init();
+    percpu_init_bsp();
+    tss_init();
     idt_init();
     pmm_init(&mut tag_iter);
     tag_iter.reset_pos();
@@ -49,9 +73,26 @@ extern "C" fn caelyx_kmain(mb2_info: *const ()) -> ! {
     heap_init();
     print_cpuid();
     acpi_init(&mut tag_iter);
-    hpet_init();
+    irq_init();
+    timer_init();
+    sched_init();
+    tlb_init();
+    syscall_init();
+    smp_init();
+    workqueue_init();
+    pci_init();
+    acpi_init_namespace();
+    rtc_init();
+    #[cfg(feature = "user-demo")]
+    sched::user_demo::start();
 
     tag_iter.reset_pos();
 
-    panic!("Finished all work");
+    info!("Finished initialization");
+
+    // The PMM lock is the one that sees the most contention, which is why it's a ticket lock
+    let stats = pmm_lock_stats();
+    debug!("PMM lock taken {} times, {} of them contended", stats.acquired, stats.contended);
+
+    exit_thread();
 }
@@ -0,0 +1,372 @@
+use alloc::{ string::String, vec::Vec };
+
+use uacpi::{
+    ACPI_STA_RESULT_DEVICE_BATTERY_PRESENT,
+    uacpi_handle,
+    uacpi_install_notify_handler,
+    uacpi_namespace_node,
+    uacpi_status,
+    uacpi_status_UACPI_STATUS_OK,
+};
+
+use crate::{
+    debug,
+    info,
+    misc::acpi::{ devices::find_devices, eval::{ eval_integer, eval_package, eval_status } },
+    sync::workqueue,
+    warning,
+};
+
+const BATTERY_HID: &str = "PNP0C0A";
+const AC_ADAPTER_HID: &str = "ACPI0003";
+
+// Battery and AC adapter notifications, see section 10.2.1 of the ACPI spec
+const NOTIFY_STATUS_CHANGED: u64 = 0x80;
+const NOTIFY_INFO_CHANGED: u64 = 0x81;
+
+// Fields of _BIF and _BST use all ones for "unknown"
+const UNKNOWN: u64 = 0xffffffff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerUnit {
+    // Capacities are in mWh and rates in mW
+    MilliWatt,
+    // Capacities are in mAh and rates in mA
+    MilliAmp,
+}
+
+impl PowerUnit {
+    pub fn capacity_suffix(self) -> &'static str {
+        match self {
+            PowerUnit::MilliWatt => "mWh",
+            PowerUnit::MilliAmp => "mAh",
+        }
+    }
+
+    pub fn rate_suffix(self) -> &'static str {
+        match self {
+            PowerUnit::MilliWatt => "mW",
+            PowerUnit::MilliAmp => "mA",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BatteryInfo {
+    pub power_unit: PowerUnit,
+    pub design_capacity: Option<u32>,
+    pub last_full_capacity: Option<u32>,
+    pub rechargeable: bool,
+    // In mV
+    pub design_voltage: Option<u32>,
+    pub warning_capacity: Option<u32>,
+    pub low_capacity: Option<u32>,
+    // Only reported by _BIX
+    pub cycle_count: Option<u32>,
+    pub model: String,
+    pub serial: String,
+    pub battery_type: String,
+    pub oem: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BatteryStatus {
+    pub discharging: bool,
+    pub charging: bool,
+    pub critical: bool,
+    // In the power unit of the battery
+    pub rate: Option<u32>,
+    pub remaining_capacity: Option<u32>,
+    // In mV
+    pub voltage: Option<u32>,
+}
+
+impl BatteryStatus {
+    // The remaining charge in percent of the last full charge
+    pub fn percentage(&self, info: &BatteryInfo) -> Option<u32> {
+        let full = info.last_full_capacity.or(info.design_capacity)?;
+        if full == 0 {
+            return None;
+        }
+
+        Some((((self.remaining_capacity? as u64) * 100) / (full as u64)).min(100) as u32)
+    }
+}
+
+fn known(value: Option<u64>) -> Option<u32> {
+    value.filter(|x| *x != UNKNOWN).map(|x| x as u32)
+}
+
+#[derive(Debug, Clone)]
+pub struct Battery {
+    pub path: String,
+    node: usize,
+}
+
+impl Battery {
+    pub fn node(&self) -> *mut uacpi_namespace_node {
+        self.node as *mut uacpi_namespace_node
+    }
+
+    // Batteries can be removed at runtime, in which case the device stays but bit 4 of _STA clears
+    pub fn present(&self) -> bool {
+        eval_status(self.node()).is_some_and(|x| (x & ACPI_STA_RESULT_DEVICE_BATTERY_PRESENT) != 0)
+    }
+
+    // Prefers _BIX over the deprecated _BIF, which lacks the cycle count
+    pub fn info(&self) -> Option<BatteryInfo> {
+        if let Some(bix) = eval_package(self.node(), c"_BIX") && bix.len() >= 20 {
+            return Some(BatteryInfo {
+                power_unit: if bix.integer(1)? == 0 {
+                    PowerUnit::MilliWatt
+                } else {
+                    PowerUnit::MilliAmp
+                },
+                design_capacity: known(bix.integer(2)),
+                last_full_capacity: known(bix.integer(3)),
+                rechargeable: bix.integer(4)? == 1,
+                design_voltage: known(bix.integer(5)),
+                warning_capacity: known(bix.integer(6)),
+                low_capacity: known(bix.integer(7)),
+                cycle_count: known(bix.integer(8)),
+                model: bix.string(16).unwrap_or_default(),
+                serial: bix.string(17).unwrap_or_default(),
+                battery_type: bix.string(18).unwrap_or_default(),
+                oem: bix.string(19).unwrap_or_default(),
+            });
+        }
+
+        let bif = eval_package(self.node(), c"_BIF")?;
+        if bif.len() < 13 {
+            return None;
+        }
+
+        Some(BatteryInfo {
+            power_unit: if bif.integer(0)? == 0 {
+                PowerUnit::MilliWatt
+            } else {
+                PowerUnit::MilliAmp
+            },
+            design_capacity: known(bif.integer(1)),
+            last_full_capacity: known(bif.integer(2)),
+            rechargeable: bif.integer(3)? == 1,
+            design_voltage: known(bif.integer(4)),
+            warning_capacity: known(bif.integer(5)),
+            low_capacity: known(bif.integer(6)),
+            cycle_count: None,
+            model: bif.string(9).unwrap_or_default(),
+            serial: bif.string(10).unwrap_or_default(),
+            battery_type: bif.string(11).unwrap_or_default(),
+            oem: bif.string(12).unwrap_or_default(),
+        })
+    }
+
+    pub fn status(&self) -> Option<BatteryStatus> {
+        let bst = eval_package(self.node(), c"_BST")?;
+        if bst.len() < 4 {
+            return None;
+        }
+
+        let state = bst.integer(0)?;
+        Some(BatteryStatus {
+            discharging: (state & 0b001) != 0,
+            charging: (state & 0b010) != 0,
+            critical: (state & 0b100) != 0,
+            rate: known(bst.integer(1)),
+            remaining_capacity: known(bst.integer(2)),
+            voltage: known(bst.integer(3)),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AcAdapter {
+    pub path: String,
+    node: usize,
+}
+
+impl AcAdapter {
+    pub fn node(&self) -> *mut uacpi_namespace_node {
+        self.node as *mut uacpi_namespace_node
+    }
+
+    pub fn online(&self) -> Option<bool> {
+        eval_integer(self.node(), c"_PSR").map(|x| x == 1)
+    }
+}
+
+pub fn batteries() -> Vec<Battery> {
+    find_devices(BATTERY_HID)
+        .into_iter()
+        .map(|x| Battery {
+            path: x.path.clone(),
+            node: x.node() as usize,
+        })
+        .collect()
+}
+
+pub fn ac_adapters() -> Vec<AcAdapter> {
+    find_devices(AC_ADAPTER_HID)
+        .into_iter()
+        .map(|x| AcAdapter {
+            path: x.path.clone(),
+            node: x.node() as usize,
+        })
+        .collect()
+}
+
+// Whether the system runs off an AC adapter. None if there is no AC adapter to ask
+pub fn on_ac_power() -> Option<bool> {
+    let mut result = None;
+    for adapter in ac_adapters() {
+        match adapter.online() {
+            Some(true) => {
+                return Some(true);
+            }
+            Some(false) => {
+                result = Some(false);
+            }
+            None => {}
+        }
+    }
+
+    result
+}
+
+fn log_battery(battery: &Battery) {
+    if !battery.present() {
+        info!("Battery {}: not present", battery.path);
+        return;
+    }
+
+    let Some(info) = battery.info() else {
+        warning!("Battery {}: could not read battery information", battery.path);
+        return;
+    };
+
+    info!(
+        "Battery {}: {} {} ({}), design capacity {}{}",
+        battery.path,
+        info.oem,
+        info.model,
+        info.battery_type,
+        info.design_capacity.unwrap_or(0),
+        info.power_unit.capacity_suffix()
+    );
+
+    let Some(status) = battery.status() else {
+        warning!("Battery {}: could not read battery status", battery.path);
+        return;
+    };
+
+    info!(
+        "Battery {}: {}% ({}{}), {}",
+        battery.path,
+        status.percentage(&info).unwrap_or(0),
+        status.remaining_capacity.unwrap_or(0),
+        info.power_unit.capacity_suffix(),
+        if status.charging {
+            "charging"
+        } else if status.discharging {
+            "discharging"
+        } else {
+            "idle"
+        }
+    );
+
+    if status.critical {
+        warning!("Battery {} is critically low", battery.path);
+    }
+}
+
+fn log_ac_adapter(adapter: &AcAdapter) {
+    match adapter.online() {
+        Some(online) => {
+            info!("AC adapter {}: {}", adapter.path, if online { "online" } else { "offline" });
+        }
+        None => warning!("AC adapter {}: could not read _PSR", adapter.path),
+    }
+}
+
+unsafe extern "C" fn battery_notify_handler(
+    _ctx: uacpi_handle,
+    node: *mut uacpi_namespace_node,
+    value: u64
+) -> uacpi_status {
+    let node = node as usize;
+    match value {
+        NOTIFY_STATUS_CHANGED | NOTIFY_INFO_CHANGED => {
+            workqueue::queue(move || {
+                if let Some(battery) = batteries().into_iter().find(|x| x.node == node) {
+                    log_battery(&battery);
+                }
+            });
+        }
+        _ => debug!("Unhandled battery notification {value:#x}"),
+    }
+
+    uacpi_status_UACPI_STATUS_OK
+}
+
+unsafe extern "C" fn ac_adapter_notify_handler(
+    _ctx: uacpi_handle,
+    node: *mut uacpi_namespace_node,
+    value: u64
+) -> uacpi_status {
+    let node = node as usize;
+    match value {
+        NOTIFY_STATUS_CHANGED => {
+            workqueue::queue(move || {
+                if let Some(adapter) = ac_adapters().into_iter().find(|x| x.node == node) {
+                    log_ac_adapter(&adapter);
+                }
+            });
+        }
+        _ => debug!("Unhandled AC adapter notification {value:#x}"),
+    }
+
+    uacpi_status_UACPI_STATUS_OK
+}
+
+pub fn init() {
+    let batteries = batteries();
+    for battery in &batteries {
+        log_battery(battery);
+
+        if
+            (unsafe {
+                uacpi_install_notify_handler(
+                    battery.node(),
+                    Some(battery_notify_handler),
+                    core::ptr::null_mut()
+                )
+            }) != uacpi_status_UACPI_STATUS_OK
+        {
+            warning!("Could not install notify handler for battery {}", battery.path);
+        }
+    }
+
+    let adapters = ac_adapters();
+    for adapter in &adapters {
+        log_ac_adapter(adapter);
+
+        if
+            (unsafe {
+                uacpi_install_notify_handler(
+                    adapter.node(),
+                    Some(ac_adapter_notify_handler),
+                    core::ptr::null_mut()
+                )
+            }) != uacpi_status_UACPI_STATUS_OK
+        {
+            warning!("Could not install notify handler for AC adapter {}", adapter.path);
+        }
+    }
+
+    info!("Found {} battery(s) and {} AC adapter(s)", batteries.len(), adapters.len());
+    match on_ac_power() {
+        Some(true) => info!("Running on AC power"),
+        Some(false) => info!("Running on battery power"),
+        None => {}
+    }
+}
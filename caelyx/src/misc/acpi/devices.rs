@@ -0,0 +1,481 @@
+use alloc::{ string::String, vec::Vec };
+use core::ffi::{ CStr, c_void };
+
+use uacpi::{
+    ACPI_STA_RESULT_DEVICE_FUNCTIONING,
+    ACPI_STA_RESULT_DEVICE_PRESENT,
+    UACPI_MAX_DEPTH_ANY,
+    UACPI_POLARITY_ACTIVE_LOW,
+    UACPI_RANGE_IO,
+    UACPI_RANGE_MEMORY,
+    UACPI_RESOURCE_TYPE_ADDRESS16,
+    UACPI_RESOURCE_TYPE_ADDRESS32,
+    UACPI_RESOURCE_TYPE_ADDRESS64,
+    UACPI_RESOURCE_TYPE_EXTENDED_IRQ,
+    UACPI_RESOURCE_TYPE_FIXED_IO,
+    UACPI_RESOURCE_TYPE_FIXED_MEMORY32,
+    UACPI_RESOURCE_TYPE_IO,
+    UACPI_RESOURCE_TYPE_IRQ,
+    UACPI_RESOURCE_TYPE_MEMORY24,
+    UACPI_RESOURCE_TYPE_MEMORY32,
+    UACPI_SHARED,
+    UACPI_TRIGGERING_LEVEL,
+    UACPI_WRITABLE,
+    uacpi_eval_cid,
+    uacpi_eval_hid,
+    uacpi_eval_sta,
+    uacpi_eval_uid,
+    uacpi_for_each_resource,
+    uacpi_free_absolute_path,
+    uacpi_free_id_string,
+    uacpi_free_pnp_id_list,
+    uacpi_free_resources,
+    uacpi_get_current_resources,
+    uacpi_id_string,
+    uacpi_iteration_decision,
+    uacpi_iteration_decision_UACPI_ITERATION_DECISION_CONTINUE,
+    uacpi_iteration_decision_UACPI_ITERATION_DECISION_NEXT_PEER,
+    uacpi_namespace_for_each_child,
+    uacpi_namespace_node,
+    uacpi_namespace_node_generate_absolute_path,
+    uacpi_namespace_node_name,
+    uacpi_namespace_node_type,
+    uacpi_namespace_root,
+    uacpi_object_type,
+    uacpi_object_type_UACPI_OBJECT_UNINITIALIZED,
+    uacpi_object_type_bits_UACPI_OBJECT_ANY_BIT,
+    uacpi_object_type_bits_UACPI_OBJECT_DEVICE_BIT,
+    uacpi_object_type_to_string,
+    uacpi_pnp_id_list,
+    uacpi_resource,
+    uacpi_resources,
+    uacpi_status_UACPI_STATUS_OK,
+};
+
+use crate::{ debug, info, sync::mutex::Mutex, warning };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiResource {
+    Irq {
+        irq: u32,
+        level_triggered: bool,
+        active_low: bool,
+        shared: bool,
+    },
+    Io {
+        base: u16,
+        length: u16,
+    },
+    Memory {
+        base: u64,
+        length: u64,
+        writable: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct AcpiDevice {
+    pub path: String,
+    pub hid: Option<String>,
+    pub cids: Vec<String>,
+    pub uid: Option<String>,
+    // The result of _STA, 0xF if the device doesn't have one
+    pub status: u32,
+    pub resources: Vec<AcpiResource>,
+    // Namespace nodes live as long as the namespace does, which is forever for us
+    node: usize,
+}
+
+impl AcpiDevice {
+    pub fn node(&self) -> *mut uacpi_namespace_node {
+        self.node as *mut uacpi_namespace_node
+    }
+
+    pub fn present(&self) -> bool {
+        (self.status & ACPI_STA_RESULT_DEVICE_PRESENT) != 0
+    }
+
+    // Whether the _HID or one of the _CIDs is the given ID
+    pub fn matches(&self, id: &str) -> bool {
+        self.hid.as_deref() == Some(id) || self.cids.iter().any(|x| x == id)
+    }
+
+    pub fn irqs(&self) -> impl Iterator<Item = AcpiResource> + '_ {
+        self.resources
+            .iter()
+            .copied()
+            .filter(|x| matches!(x, AcpiResource::Irq { .. }))
+    }
+
+    pub fn io_ports(&self) -> impl Iterator<Item = AcpiResource> + '_ {
+        self.resources
+            .iter()
+            .copied()
+            .filter(|x| matches!(x, AcpiResource::Io { .. }))
+    }
+
+    pub fn memory_ranges(&self) -> impl Iterator<Item = AcpiResource> + '_ {
+        self.resources
+            .iter()
+            .copied()
+            .filter(|x| matches!(x, AcpiResource::Memory { .. }))
+    }
+}
+
+impl core::fmt::Display for AcpiDevice {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.path)?;
+        if let Some(hid) = &self.hid {
+            write!(f, " HID={hid}")?;
+        }
+        if !self.cids.is_empty() {
+            write!(f, " CID={:?}", self.cids)?;
+        }
+        if let Some(uid) = &self.uid {
+            write!(f, " UID={uid}")?;
+        }
+        write!(f, " STA={:#x}", self.status)
+    }
+}
+
+static DEVICES: Mutex<Vec<AcpiDevice>> = Mutex::new(Vec::new());
+
+fn id_string_to_string(id: &uacpi_id_string) -> String {
+    if id.value.is_null() {
+        return String::new();
+    }
+
+    unsafe { CStr::from_ptr(id.value) }.to_string_lossy().into_owned()
+}
+
+pub(super) fn node_path(node: *mut uacpi_namespace_node) -> String {
+    unsafe {
+        let path = uacpi_namespace_node_generate_absolute_path(node);
+        let string = CStr::from_ptr(path).to_string_lossy().into_owned();
+        uacpi_free_absolute_path(path);
+        string
+    }
+}
+
+fn eval_hid(node: *mut uacpi_namespace_node) -> Option<String> {
+    let mut id: *mut uacpi_id_string = core::ptr::null_mut();
+    unsafe {
+        if uacpi_eval_hid(node, &mut id) != uacpi_status_UACPI_STATUS_OK {
+            return None;
+        }
+
+        let string = id_string_to_string(&*id);
+        uacpi_free_id_string(id);
+        Some(string)
+    }
+}
+
+fn eval_uid(node: *mut uacpi_namespace_node) -> Option<String> {
+    let mut id: *mut uacpi_id_string = core::ptr::null_mut();
+    unsafe {
+        if uacpi_eval_uid(node, &mut id) != uacpi_status_UACPI_STATUS_OK {
+            return None;
+        }
+
+        let string = id_string_to_string(&*id);
+        uacpi_free_id_string(id);
+        Some(string)
+    }
+}
+
+fn eval_cids(node: *mut uacpi_namespace_node) -> Vec<String> {
+    let mut list: *mut uacpi_pnp_id_list = core::ptr::null_mut();
+    unsafe {
+        if uacpi_eval_cid(node, &mut list) != uacpi_status_UACPI_STATUS_OK {
+            return Vec::new();
+        }
+
+        let cids = (*list).ids
+            .as_slice((*list).num_ids as usize)
+            .iter()
+            .map(id_string_to_string)
+            .collect();
+
+        uacpi_free_pnp_id_list(list);
+        cids
+    }
+}
+
+unsafe extern "C" fn collect_resource(
+    user: *mut c_void,
+    resource: *mut uacpi_resource
+) -> uacpi_iteration_decision {
+    let resources = unsafe { &mut *(user as *mut Vec<AcpiResource>) };
+    let resource = unsafe { &*resource };
+
+    unsafe {
+        match resource.type_ {
+            UACPI_RESOURCE_TYPE_IRQ => {
+                let irq = &resource.__bindgen_anon_1.irq;
+                for num in irq.irqs.as_slice(irq.num_irqs as usize) {
+                    resources.push(AcpiResource::Irq {
+                        irq: *num as u32,
+                        level_triggered: (irq.triggering as u32) == UACPI_TRIGGERING_LEVEL,
+                        active_low: (irq.polarity as u32) == UACPI_POLARITY_ACTIVE_LOW,
+                        shared: (irq.sharing as u32) == UACPI_SHARED,
+                    });
+                }
+            }
+            UACPI_RESOURCE_TYPE_EXTENDED_IRQ => {
+                let irq = &resource.__bindgen_anon_1.extended_irq;
+                for num in irq.irqs.as_slice(irq.num_irqs as usize) {
+                    resources.push(AcpiResource::Irq {
+                        irq: *num,
+                        level_triggered: (irq.triggering as u32) == UACPI_TRIGGERING_LEVEL,
+                        active_low: (irq.polarity as u32) == UACPI_POLARITY_ACTIVE_LOW,
+                        shared: (irq.sharing as u32) == UACPI_SHARED,
+                    });
+                }
+            }
+            UACPI_RESOURCE_TYPE_IO => {
+                let io = resource.__bindgen_anon_1.io;
+                resources.push(AcpiResource::Io {
+                    base: io.minimum,
+                    length: io.length as u16,
+                });
+            }
+            UACPI_RESOURCE_TYPE_FIXED_IO => {
+                let io = resource.__bindgen_anon_1.fixed_io;
+                resources.push(AcpiResource::Io {
+                    base: io.address,
+                    length: io.length as u16,
+                });
+            }
+            UACPI_RESOURCE_TYPE_MEMORY24 => {
+                // 24-bit memory descriptors are in units of 256 bytes
+                let mem = resource.__bindgen_anon_1.memory24;
+                resources.push(AcpiResource::Memory {
+                    base: (mem.minimum as u64) << 8,
+                    length: (mem.length as u64) << 8,
+                    writable: (mem.write_status as u32) == UACPI_WRITABLE,
+                });
+            }
+            UACPI_RESOURCE_TYPE_MEMORY32 => {
+                let mem = resource.__bindgen_anon_1.memory32;
+                resources.push(AcpiResource::Memory {
+                    base: mem.minimum as u64,
+                    length: mem.length as u64,
+                    writable: (mem.write_status as u32) == UACPI_WRITABLE,
+                });
+            }
+            UACPI_RESOURCE_TYPE_FIXED_MEMORY32 => {
+                let mem = resource.__bindgen_anon_1.fixed_memory32;
+                resources.push(AcpiResource::Memory {
+                    base: mem.address as u64,
+                    length: mem.length as u64,
+                    writable: (mem.write_status as u32) == UACPI_WRITABLE,
+                });
+            }
+            UACPI_RESOURCE_TYPE_ADDRESS16 => {
+                let addr = resource.__bindgen_anon_1.address16;
+                push_address_range(
+                    resources,
+                    addr.common.type_ as u32,
+                    addr.minimum as u64,
+                    addr.address_length as u64
+                );
+            }
+            UACPI_RESOURCE_TYPE_ADDRESS32 => {
+                let addr = resource.__bindgen_anon_1.address32;
+                push_address_range(
+                    resources,
+                    addr.common.type_ as u32,
+                    addr.minimum as u64,
+                    addr.address_length as u64
+                );
+            }
+            UACPI_RESOURCE_TYPE_ADDRESS64 => {
+                let addr = resource.__bindgen_anon_1.address64;
+                push_address_range(
+                    resources,
+                    addr.common.type_ as u32,
+                    addr.minimum,
+                    addr.address_length
+                );
+            }
+            _ => {}
+        }
+    }
+
+    uacpi_iteration_decision_UACPI_ITERATION_DECISION_CONTINUE
+}
+
+fn push_address_range(resources: &mut Vec<AcpiResource>, range_type: u32, base: u64, length: u64) {
+    if length == 0 {
+        return;
+    }
+
+    match range_type {
+        UACPI_RANGE_MEMORY => {
+            resources.push(AcpiResource::Memory {
+                base,
+                length,
+                writable: true,
+            });
+        }
+        UACPI_RANGE_IO => {
+            resources.push(AcpiResource::Io {
+                base: base as u16,
+                length: length as u16,
+            });
+        }
+        // Bus number ranges aren't interesting to anyone yet
+        _ => {}
+    }
+}
+
+fn eval_resources(node: *mut uacpi_namespace_node) -> Vec<AcpiResource> {
+    let mut resources = Vec::new();
+    let mut crs: *mut uacpi_resources = core::ptr::null_mut();
+
+    unsafe {
+        if uacpi_get_current_resources(node, &mut crs) != uacpi_status_UACPI_STATUS_OK {
+            return resources;
+        }
+
+        uacpi_for_each_resource(
+            crs,
+            Some(collect_resource),
+            &raw mut resources as *mut c_void
+        );
+        uacpi_free_resources(crs);
+    }
+
+    resources
+}
+
+unsafe extern "C" fn collect_device(
+    user: *mut c_void,
+    node: *mut uacpi_namespace_node,
+    _depth: u32
+) -> uacpi_iteration_decision {
+    let devices = unsafe { &mut *(user as *mut Vec<AcpiDevice>) };
+
+    let mut status = 0;
+    let ret = unsafe { uacpi_eval_sta(node, &mut status) };
+    if ret != uacpi_status_UACPI_STATUS_OK {
+        // Same as not having a _STA, so that one broken method doesn't hide the whole subtree
+        warning!("Could not evaluate _STA of {}: {ret}, assuming it's present", node_path(node));
+        status = 0xf;
+    }
+
+    let mut device = AcpiDevice {
+        path: node_path(node),
+        hid: eval_hid(node),
+        cids: eval_cids(node),
+        uid: eval_uid(node),
+        status,
+        resources: Vec::new(),
+        node: node as usize,
+    };
+
+    // Per the spec, children of a device that is neither present nor functioning must be ignored
+    let decision = if
+        (status & (ACPI_STA_RESULT_DEVICE_PRESENT | ACPI_STA_RESULT_DEVICE_FUNCTIONING)) == 0
+    {
+        uacpi_iteration_decision_UACPI_ITERATION_DECISION_NEXT_PEER
+    } else {
+        uacpi_iteration_decision_UACPI_ITERATION_DECISION_CONTINUE
+    };
+
+    if device.present() {
+        device.resources = eval_resources(node);
+    }
+
+    devices.push(device);
+    decision
+}
+
+unsafe extern "C" fn dump_node(
+    _user: *mut c_void,
+    node: *mut uacpi_namespace_node,
+    depth: u32
+) -> uacpi_iteration_decision {
+    let name = unsafe { uacpi_namespace_node_name(node).text };
+    let name: [u8; 4] = name.map(|x| x as u8);
+
+    let mut object_type: uacpi_object_type = uacpi_object_type_UACPI_OBJECT_UNINITIALIZED;
+    unsafe {
+        uacpi_namespace_node_type(node, &mut object_type);
+    }
+
+    let type_name = unsafe { CStr::from_ptr(uacpi_object_type_to_string(object_type)) };
+
+    debug!(
+        "{:indent$}{} ({})",
+        "",
+        core::str::from_utf8(&name).unwrap_or("????"),
+        type_name.to_str().unwrap_or("?"),
+        indent = (depth as usize) * 2
+    );
+
+    uacpi_iteration_decision_UACPI_ITERATION_DECISION_CONTINUE
+}
+
+fn dump_namespace() {
+    debug!("ACPI namespace:");
+    unsafe {
+        uacpi_namespace_for_each_child(
+            uacpi_namespace_root(),
+            Some(dump_node),
+            None,
+            uacpi_object_type_bits_UACPI_OBJECT_ANY_BIT,
+            UACPI_MAX_DEPTH_ANY,
+            core::ptr::null_mut()
+        );
+    }
+}
+
+fn dump_devices(devices: &[AcpiDevice]) {
+    debug!("ACPI devices:");
+    for device in devices {
+        debug!("{device}");
+        for resource in &device.resources {
+            debug!("    {resource:?}");
+        }
+    }
+}
+
+pub fn init() {
+    dump_namespace();
+
+    let mut devices: Vec<AcpiDevice> = Vec::new();
+    unsafe {
+        uacpi_namespace_for_each_child(
+            uacpi_namespace_root(),
+            Some(collect_device),
+            None,
+            uacpi_object_type_bits_UACPI_OBJECT_DEVICE_BIT,
+            UACPI_MAX_DEPTH_ANY,
+            &raw mut devices as *mut c_void
+        );
+    }
+
+    dump_devices(&devices);
+
+    info!("Found {} ACPI devices", devices.len());
+    *DEVICES.lock() = devices;
+}
+
+pub fn devices() -> Vec<AcpiDevice> {
+    DEVICES.lock().clone()
+}
+
+// Returns every present device whose _HID or _CID matches the given ID, e.g. "PNP0B00" for the RTC
+pub fn find_devices(id: &str) -> Vec<AcpiDevice> {
+    DEVICES.lock()
+        .iter()
+        .filter(|x| x.present() && x.matches(id))
+        .cloned()
+        .collect()
+}
+
+pub fn find_device(id: &str) -> Option<AcpiDevice> {
+    find_devices(id).into_iter().next()
+}
+
@@ -0,0 +1,131 @@
+use alloc::{ string::String, vec::Vec };
+use core::ffi::CStr;
+
+use uacpi::{
+    uacpi_data_view,
+    uacpi_eval_simple_integer,
+    uacpi_eval_simple_package,
+    uacpi_eval_sta,
+    uacpi_namespace_node,
+    uacpi_object,
+    uacpi_object_array,
+    uacpi_object_get_integer,
+    uacpi_object_get_package,
+    uacpi_object_get_string_or_buffer,
+    uacpi_object_unref,
+    uacpi_status_UACPI_STATUS_OK,
+};
+
+// Evaluates a method or named object relative to `node` that has to return an integer
+pub(super) fn eval_integer(node: *mut uacpi_namespace_node, path: &CStr) -> Option<u64> {
+    let mut value = 0;
+    if
+        (unsafe { uacpi_eval_simple_integer(node, path.as_ptr(), &mut value) }) !=
+        uacpi_status_UACPI_STATUS_OK
+    {
+        return None;
+    }
+
+    Some(value)
+}
+
+// Evaluates _STA. uACPI reports devices without one as present and functioning
+pub(super) fn eval_status(node: *mut uacpi_namespace_node) -> Option<u32> {
+    let mut status = 0;
+    if (unsafe { uacpi_eval_sta(node, &mut status) }) != uacpi_status_UACPI_STATUS_OK {
+        return None;
+    }
+
+    Some(status)
+}
+
+// A package returned by AML. The reference to the object is dropped along with this
+pub struct Package {
+    object: *mut uacpi_object,
+    elements: Vec<*mut uacpi_object>,
+}
+
+impl Package {
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    pub fn integer(&self, idx: usize) -> Option<u64> {
+        let element = *self.elements.get(idx)?;
+
+        let mut value = 0;
+        if
+            (unsafe { uacpi_object_get_integer(element, &mut value) }) !=
+            uacpi_status_UACPI_STATUS_OK
+        {
+            return None;
+        }
+
+        Some(value)
+    }
+
+    // Strings are NUL-terminated inside the data view, buffers may or may not be
+    pub fn string(&self, idx: usize) -> Option<String> {
+        let element = *self.elements.get(idx)?;
+
+        let mut view: uacpi_data_view = unsafe { core::mem::zeroed() };
+        if
+            (unsafe { uacpi_object_get_string_or_buffer(element, &mut view) }) !=
+            uacpi_status_UACPI_STATUS_OK
+        {
+            return None;
+        }
+
+        if view.length == 0 {
+            return Some(String::new());
+        }
+
+        let bytes = unsafe {
+            core::slice::from_raw_parts(view.__bindgen_anon_1.const_bytes, view.length)
+        };
+        let bytes = bytes.split(|x| *x == 0).next().unwrap_or(bytes);
+
+        Some(String::from_utf8_lossy(bytes).trim().into())
+    }
+}
+
+impl Drop for Package {
+    fn drop(&mut self) {
+        unsafe {
+            uacpi_object_unref(self.object);
+        }
+    }
+}
+
+pub(super) fn eval_package(node: *mut uacpi_namespace_node, path: &CStr) -> Option<Package> {
+    let mut object: *mut uacpi_object = core::ptr::null_mut();
+    if
+        (unsafe { uacpi_eval_simple_package(node, path.as_ptr(), &mut object) }) !=
+        uacpi_status_UACPI_STATUS_OK
+    {
+        return None;
+    }
+
+    let mut array = uacpi_object_array {
+        objects: core::ptr::null_mut(),
+        count: 0,
+    };
+    if (unsafe { uacpi_object_get_package(object, &mut array) }) != uacpi_status_UACPI_STATUS_OK {
+        unsafe {
+            uacpi_object_unref(object);
+        }
+        return None;
+    }
+
+    let elements = if array.count == 0 {
+        Vec::new()
+    } else {
+        unsafe { core::slice::from_raw_parts(array.objects, array.count) }.to_vec()
+    };
+
+    Some(Package { object, elements })
+}
@@ -0,0 +1,938 @@
+use alloc::{ boxed::Box, vec::Vec };
+use core::{
+    alloc::Layout,
+    hint::spin_loop,
+    ptr::{ null_mut, read_unaligned },
+    sync::atomic::AtomicBool,
+    time::Duration,
+};
+
+use uacpi::{
+    UACPI_INTERRUPT_HANDLED,
+    uacpi_bool,
+    uacpi_char,
+    uacpi_cpu_flags,
+    uacpi_finalize_gpe_initialization,
+    uacpi_firmware_request,
+    uacpi_fixed_event_UACPI_FIXED_EVENT_POWER_BUTTON,
+    uacpi_handle,
+    uacpi_initialize,
+    uacpi_install_fixed_event_handler,
+    uacpi_interrupt_handler,
+    uacpi_interrupt_model_UACPI_INTERRUPT_MODEL_IOAPIC,
+    uacpi_interrupt_ret,
+    uacpi_io_addr,
+    uacpi_log_level,
+    uacpi_log_level_UACPI_LOG_DEBUG,
+    uacpi_log_level_UACPI_LOG_ERROR,
+    uacpi_log_level_UACPI_LOG_INFO,
+    uacpi_log_level_UACPI_LOG_TRACE,
+    uacpi_log_level_UACPI_LOG_WARN,
+    uacpi_namespace_initialize,
+    uacpi_namespace_load,
+    uacpi_pci_address,
+    uacpi_phys_addr,
+    uacpi_set_interrupt_model,
+    uacpi_size,
+    uacpi_status,
+    uacpi_status_UACPI_STATUS_INTERNAL_ERROR,
+    uacpi_status_UACPI_STATUS_INVALID_ARGUMENT,
+    uacpi_status_UACPI_STATUS_OK,
+    uacpi_status_UACPI_STATUS_TIMEOUT,
+    uacpi_status_UACPI_STATUS_UNIMPLEMENTED,
+    uacpi_table,
+    uacpi_table_find_by_signature,
+    uacpi_thread_id,
+    uacpi_u8,
+    uacpi_u16,
+    uacpi_u32,
+    uacpi_u64,
+    uacpi_work_handler,
+    uacpi_work_type,
+};
+
+use crate::{
+    boot::multiboot2,
+    debug,
+    drvs::pci::{ self, PciAddress, PciError },
+    error,
+    info,
+    misc::{ power, ptr_align::{ align_ptr_down, align_ptr_up } },
+    mm::{ tlb::TlbBatch, virt_page_alloc, vmm },
+    sched,
+    sync::{ once::Once, semaphore::Semaphore, timer, workqueue },
+    trace,
+    warning,
+    x86::{
+        idt::interrupt_control::{ disable_interrupts, enable_interrupts, interrupts_enabled },
+        ioport::{ inb, inl, inw, outb, outl, outw },
+        irq,
+    },
+};
+
+pub mod battery;
+pub mod devices;
+pub mod eval;
+pub mod thermal;
+
+pub use devices::{ AcpiDevice, AcpiResource, devices, find_device, find_devices };
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct AcpiGAS {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+static RSDP: Once<usize> = Once::new();
+
+fn find_rsdp(tag_iter: &mut multiboot2::TagIterator) -> *const () {
+    let rsdp_mb2 = tag_iter.find(|x| {
+        matches!(x, multiboot2::MultibootTag::AcpiOld(_)) ||
+            matches!(x, multiboot2::MultibootTag::AcpiNew(_))
+    });
+
+    if let Some(tag) = rsdp_mb2 {
+        if let multiboot2::MultibootTag::AcpiNew(rsdp_new) = tag {
+            debug!("Found RSDP at 0x{:08X}", rsdp_new as usize);
+            return rsdp_new;
+        } else if let multiboot2::MultibootTag::AcpiOld(rsdp_old) = tag {
+            debug!("Found RSDP at 0x{:08X}", rsdp_old as usize);
+            return rsdp_old;
+        }
+    }
+
+    let mut addr = 0x000e0000u32;
+    while addr < 0x000fffff {
+        if (unsafe { core::slice::from_raw_parts::<u8>(addr as *const u8, 8) }) == b"RSD PTR " {
+            debug!("Found RSDP at 0x{addr:08X}");
+            return addr as *const ();
+        }
+        addr += 16;
+    }
+
+    panic!("No ACPI RSDP found (required for Caelyx to function properly)");
+}
+
+pub fn init(tag_iter: &mut multiboot2::TagIterator) {
+    RSDP.call_once(|| find_rsdp(tag_iter) as usize);
+
+    unsafe {
+        if uacpi_initialize(0) != uacpi_status_UACPI_STATUS_OK {
+            panic!("uACPI initialization failed");
+        }
+    }
+
+    let mut madt_table: uacpi_table = uacpi_table::default();
+    if !find_table(b"APIC", &mut madt_table) {
+        panic!("No MADT found (required for Caelyx to function properly)");
+    }
+
+    let madt_virtual_address: usize;
+
+    unsafe {
+        madt_virtual_address = madt_table.__bindgen_anon_1.ptr as usize;
+    }
+
+    MADT.call_once(|| parse_madt(madt_virtual_address));
+
+    info!("Initialized ACPI");
+}
+
+// Loading the namespace runs AML which may poke at the PCI configuration space, so this has to
+// happen after the PCI subsystem found the ECAM regions
+pub fn init_namespace() {
+    unsafe {
+        if uacpi_namespace_load() != uacpi_status_UACPI_STATUS_OK {
+            panic!("uACPI namespace load failed");
+        }
+
+        // This evaluates \_PIC so that the firmware hands out IOAPIC routing from _PRT
+        if
+            uacpi_set_interrupt_model(uacpi_interrupt_model_UACPI_INTERRUPT_MODEL_IOAPIC) !=
+            uacpi_status_UACPI_STATUS_OK
+        {
+            warning!("Could not switch the ACPI interrupt model to IOAPIC");
+        }
+
+        if uacpi_namespace_initialize() != uacpi_status_UACPI_STATUS_OK {
+            panic!("uACPI namespace initialization failed");
+        }
+    }
+
+    init_events();
+
+    info!("Initialized ACPI namespace");
+
+    devices::init();
+    thermal::init();
+    battery::init();
+}
+
+unsafe extern "C" fn power_button_handler(_ctx: uacpi_handle) -> uacpi_interrupt_ret {
+    // This runs inside the SCI handler, where evaluating AML (which entering a sleep state
+    // requires) isn't allowed. So leave the shutdown to the work queue
+    info!("Power button pressed");
+    workqueue::queue(|| power::shutdown());
+    UACPI_INTERRUPT_HANDLED
+}
+
+fn init_events() {
+    unsafe {
+        if
+            uacpi_install_fixed_event_handler(
+                uacpi_fixed_event_UACPI_FIXED_EVENT_POWER_BUTTON,
+                Some(power_button_handler),
+                null_mut()
+            ) != uacpi_status_UACPI_STATUS_OK
+        {
+            warning!("Could not install the ACPI power button handler");
+        }
+
+        // This enables every GPE that has a _Lxx/_Exx method now that the namespace is ready
+        if uacpi_finalize_gpe_initialization() != uacpi_status_UACPI_STATUS_OK {
+            warning!("Could not finalize ACPI GPE initialization");
+        }
+    }
+
+    debug!("Enabled ACPI fixed events and GPEs");
+}
+
+pub fn find_table(signature: &[u8; 4], table: &mut uacpi_table) -> bool {
+    unsafe {
+        uacpi_table_find_by_signature(signature.as_ptr() as *const i8, table) ==
+            uacpi_status_UACPI_STATUS_OK
+    }
+}
+
+pub fn get_hpet_table(table: &mut uacpi_table) -> bool {
+    find_table(b"HPET", table)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FadtRtcInfo {
+    // Index of the CMOS register holding the century, if the firmware keeps one
+    pub century_register: Option<u8>,
+    pub rtc_present: bool,
+}
+
+pub fn fadt_rtc_info() -> FadtRtcInfo {
+    let mut info = FadtRtcInfo {
+        century_register: None,
+        rtc_present: true,
+    };
+
+    let mut table = uacpi_table::default();
+    if !find_table(b"FACP", &mut table) {
+        return info;
+    }
+
+    let fadt = unsafe { table.__bindgen_anon_1.ptr as *const u8 };
+    let length = unsafe { read_unaligned(fadt.add(4) as *const u32) };
+
+    // The century register is at offset 108, followed by the IA-PC boot architecture flags. ACPI
+    // 1.0 FADTs end before these
+    if length > 108 {
+        let century = unsafe { *fadt.add(108) };
+        if century != 0 {
+            info.century_register = Some(century);
+        }
+    }
+    if length >= 111 {
+        // Bit 5: CMOS RTC not present
+        let boot_flags = unsafe { read_unaligned(fadt.add(109) as *const u16) };
+        info.rtc_present = (boot_flags & (1 << 5)) == 0;
+    }
+
+    info
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MadtLocalApic {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub flags: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MadtIoApic {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MadtInterruptOverride {
+    pub bus: u8,
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MadtLocalApicNmi {
+    // 0xFF means all processors
+    pub processor_id: u8,
+    pub flags: u16,
+    pub lint: u8,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MadtInfo {
+    pub lapic_address: u64,
+    pub pic_present: bool,
+    pub local_apics: Vec<MadtLocalApic>,
+    pub io_apics: Vec<MadtIoApic>,
+    pub overrides: Vec<MadtInterruptOverride>,
+    pub lapic_nmis: Vec<MadtLocalApicNmi>,
+}
+
+static MADT: Once<MadtInfo> = Once::new();
+
+fn parse_madt(virt_addr: usize) -> MadtInfo {
+    debug!("Parsing MADT...");
+    let length = unsafe { read_unaligned((virt_addr + 4) as *const u32) };
+    let skipped_sdt_addr = virt_addr + 0x24;
+    let lapic_addr = unsafe { read_unaligned(skipped_sdt_addr as *const u32) };
+    let pic_present = ((unsafe { read_unaligned((skipped_sdt_addr + 4) as *const u32) }) & 1) != 0;
+    trace!("Length: {length}");
+    trace!("LAPIC address: {lapic_addr:#08X}");
+    trace!("PICs {}present", if !pic_present { "not " } else { "" });
+
+    let mut info = MadtInfo {
+        lapic_address: lapic_addr as u64,
+        pic_present,
+        ..Default::default()
+    };
+
+    let mut current_length = (skipped_sdt_addr - virt_addr + 8) as u32;
+    while current_length < length {
+        trace!("MADT Entry (offset = {current_length:#x}):");
+        let entry_addr = virt_addr + (current_length as usize);
+        let entry_type = unsafe { *(entry_addr as *const u8) };
+        let entry_length = unsafe { *((entry_addr + 1) as *const u8) };
+        trace!("Entry type: {}", match entry_type {
+            0 => "CPU LAPIC",
+            1 => "IOAPIC",
+            2 => "IOAPIC ISO",
+            3 => "IOAPIC NMI source",
+            4 => "IOAPIC NMIs",
+            5 => "LAPIC 64-bit address",
+            9 => "CPU x2APIC",
+            _ => "?",
+        });
+        trace!("Entry length: {entry_length}");
+
+        let read_u8 = |offset: usize| unsafe { *((entry_addr + offset) as *const u8) };
+        let read_u16 = |offset: usize| unsafe {
+            read_unaligned((entry_addr + offset) as *const u16)
+        };
+        let read_u32 = |offset: usize| unsafe {
+            read_unaligned((entry_addr + offset) as *const u32)
+        };
+
+        match entry_type {
+            0 => {
+                let lapic = MadtLocalApic {
+                    processor_id: read_u8(2),
+                    apic_id: read_u8(3),
+                    flags: read_u32(4),
+                };
+                trace!("{lapic:?}");
+                info.local_apics.push(lapic);
+            }
+            1 => {
+                let io_apic = MadtIoApic {
+                    id: read_u8(2),
+                    address: read_u32(4),
+                    gsi_base: read_u32(8),
+                };
+                trace!("{io_apic:?}");
+                info.io_apics.push(io_apic);
+            }
+            2 => {
+                let iso = MadtInterruptOverride {
+                    bus: read_u8(2),
+                    source: read_u8(3),
+                    gsi: read_u32(4),
+                    flags: read_u16(8),
+                };
+                trace!("{iso:?}");
+                info.overrides.push(iso);
+            }
+            4 => {
+                let nmi = MadtLocalApicNmi {
+                    processor_id: read_u8(2),
+                    flags: read_u16(3),
+                    lint: read_u8(5),
+                };
+                trace!("{nmi:?}");
+                info.lapic_nmis.push(nmi);
+            }
+            5 => {
+                info.lapic_address = unsafe { read_unaligned((entry_addr + 4) as *const u64) };
+                trace!("LAPIC address override: {:#010X}", info.lapic_address);
+            }
+            _ => {}
+        }
+
+        current_length += entry_length as u32;
+    }
+
+    info
+}
+
+pub fn madt() -> &'static MadtInfo {
+    MADT.get().expect("acpi::madt() called before acpi::init()")
+}
+
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn uacpi_kernel_get_rsdp(_out_rsdp_address: *mut uacpi_phys_addr) -> uacpi_status {
+    unsafe {
+        let rsdp = RSDP.get().expect("uACPI asked for the RSDP before it was found");
+        *_out_rsdp_address = *rsdp as u64;
+    }
+
+    uacpi_status_UACPI_STATUS_OK
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_map(
+    _addr: uacpi_phys_addr,
+    _len: uacpi_size
+) -> *mut ::core::ffi::c_void {
+    let first_phys_page = align_ptr_down(_addr as *const u8, 4096) as u32;
+    let last_phys_page = align_ptr_up(
+        (first_phys_page + (align_ptr_up(_len as *const u8, 4096) as u32)) as *const u8,
+        4096
+    ) as u32;
+
+    let page_count = (last_phys_page - first_phys_page) / 4096;
+    let first_virt_page = virt_page_alloc
+        ::allocate(page_count as usize)
+        .expect("Could not allocate virtual pages for uACPI");
+
+    for i in 0..page_count {
+        vmm::map(first_phys_page + i * 4096, first_virt_page + i * 4096, false, true, false, false);
+    }
+
+    (first_virt_page + ((_addr as u32) - first_phys_page)) as *mut core::ffi::c_void
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_unmap(_addr: *mut ::core::ffi::c_void, _len: uacpi_size) {
+    let last_virt_page = align_ptr_up(
+        ((_addr as u32) + (align_ptr_up(_len as *const u8, 4096) as u32)) as *const u8,
+        4096
+    ) as u32;
+
+    let page_count = (last_virt_page - (_addr as u32)) / 4096;
+
+    let first_page = align_ptr_down(_addr as *const u8, 4096) as u32;
+    let mut batch = TlbBatch::new();
+    for i in 0..page_count {
+        vmm::unmap_batched(first_page + i * 4096, &mut batch);
+    }
+    batch.flush();
+
+    virt_page_alloc::free(align_ptr_down(_addr as *const u8, 4096), page_count as usize);
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_log(_arg1: uacpi_log_level, _arg2: *const uacpi_char) {
+    let mut len = 0;
+    let ptr = _arg2 as *const u8;
+    while (unsafe { *ptr.add(len) }) != 0 {
+        len += 1;
+    }
+
+    let _str = (unsafe { core::str::from_raw_parts(ptr, len) }).strip_suffix("\n").unwrap_or("");
+    match _arg1 {
+        #[allow(non_upper_case_globals)]
+        uacpi_log_level_UACPI_LOG_DEBUG => debug!("uACPI: {_str}"),
+        #[allow(non_upper_case_globals)]
+        uacpi_log_level_UACPI_LOG_ERROR => error!("uACPI: {_str}"),
+        #[allow(non_upper_case_globals)]
+        uacpi_log_level_UACPI_LOG_TRACE => trace!("uACPI: {_str}"),
+        #[allow(non_upper_case_globals)]
+        uacpi_log_level_UACPI_LOG_WARN => warning!("uACPI: {_str}"),
+        #[allow(non_upper_case_globals)]
+        uacpi_log_level_UACPI_LOG_INFO => debug!("uACPI: {_str}"), // INFO: heres all the addresses that every person that isnt debugging needs to see
+        _ => unreachable!(),
+    }
+}
+
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn uacpi_kernel_pci_device_open(
+    _address: uacpi_pci_address,
+    _out_handle: *mut uacpi_handle
+) -> uacpi_status {
+    let handle = Box::new(
+        PciAddress::new(_address.segment, _address.bus, _address.device, _address.function)
+    );
+
+    unsafe {
+        *_out_handle = Box::into_raw(handle) as *mut core::ffi::c_void;
+    }
+
+    uacpi_status_UACPI_STATUS_OK
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_pci_device_close(_arg1: uacpi_handle) {
+    drop(unsafe { Box::from_raw(_arg1 as *mut PciAddress) });
+}
+
+fn pci_status<T>(result: Result<T, PciError>, out: *mut T) -> uacpi_status {
+    match result {
+        Ok(val) => {
+            unsafe {
+                *out = val;
+            }
+            uacpi_status_UACPI_STATUS_OK
+        }
+        Err(PciError::Unaligned) => uacpi_status_UACPI_STATUS_INVALID_ARGUMENT,
+        Err(PciError::Unreachable) => uacpi_status_UACPI_STATUS_UNIMPLEMENTED,
+    }
+}
+
+fn pci_write_status(result: Result<(), PciError>) -> uacpi_status {
+    pci_status(result, &mut ())
+}
+
+// Config space is 4096 bytes at most, anything past that would wrap around when narrowed to a u16
+fn pci_offset(offset: uacpi_size) -> Option<u16> {
+    (offset < 4096).then_some(offset as u16)
+}
+
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn uacpi_kernel_pci_read8(
+    _device: uacpi_handle,
+    _offset: uacpi_size,
+    _value: *mut uacpi_u8
+) -> uacpi_status {
+    let Some(offset) = pci_offset(_offset) else {
+        return uacpi_status_UACPI_STATUS_INVALID_ARGUMENT;
+    };
+    let address = unsafe { *(_device as *const PciAddress) };
+    pci_status(pci::read8(address, offset), _value)
+}
+
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn uacpi_kernel_pci_read16(
+    _device: uacpi_handle,
+    _offset: uacpi_size,
+    _value: *mut uacpi_u16
+) -> uacpi_status {
+    let Some(offset) = pci_offset(_offset) else {
+        return uacpi_status_UACPI_STATUS_INVALID_ARGUMENT;
+    };
+    let address = unsafe { *(_device as *const PciAddress) };
+    pci_status(pci::read16(address, offset), _value)
+}
+
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn uacpi_kernel_pci_read32(
+    _device: uacpi_handle,
+    _offset: uacpi_size,
+    _value: *mut uacpi_u32
+) -> uacpi_status {
+    let Some(offset) = pci_offset(_offset) else {
+        return uacpi_status_UACPI_STATUS_INVALID_ARGUMENT;
+    };
+    let address = unsafe { *(_device as *const PciAddress) };
+    pci_status(pci::read32(address, offset), _value)
+}
+
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn uacpi_kernel_pci_write8(
+    _device: uacpi_handle,
+    _offset: uacpi_size,
+    _value: uacpi_u8
+) -> uacpi_status {
+    let Some(offset) = pci_offset(_offset) else {
+        return uacpi_status_UACPI_STATUS_INVALID_ARGUMENT;
+    };
+    let address = unsafe { *(_device as *const PciAddress) };
+    pci_write_status(pci::write8(address, offset, _value))
+}
+
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn uacpi_kernel_pci_write16(
+    _device: uacpi_handle,
+    _offset: uacpi_size,
+    _value: uacpi_u16
+) -> uacpi_status {
+    let Some(offset) = pci_offset(_offset) else {
+        return uacpi_status_UACPI_STATUS_INVALID_ARGUMENT;
+    };
+    let address = unsafe { *(_device as *const PciAddress) };
+    pci_write_status(pci::write16(address, offset, _value))
+}
+
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn uacpi_kernel_pci_write32(
+    _device: uacpi_handle,
+    _offset: uacpi_size,
+    _value: uacpi_u32
+) -> uacpi_status {
+    let Some(offset) = pci_offset(_offset) else {
+        return uacpi_status_UACPI_STATUS_INVALID_ARGUMENT;
+    };
+    let address = unsafe { *(_device as *const PciAddress) };
+    pci_write_status(pci::write32(address, offset, _value))
+}
+
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn uacpi_kernel_io_map(
+    _base: uacpi_io_addr,
+    _len: uacpi_size,
+    _out_handle: *mut uacpi_handle
+) -> uacpi_status {
+    unsafe {
+        *_out_handle = _base as u32 as *mut core::ffi::c_void;
+    }
+
+    uacpi_status_UACPI_STATUS_OK
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_io_unmap(_handle: uacpi_handle) {}
+
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn uacpi_kernel_io_read8(
+    _arg1: uacpi_handle,
+    _offset: uacpi_size,
+    _out_value: *mut uacpi_u8
+) -> uacpi_status {
+    unsafe {
+        *_out_value = inb(_arg1 as u16);
+    }
+    uacpi_status_UACPI_STATUS_OK
+}
+
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn uacpi_kernel_io_read16(
+    _arg1: uacpi_handle,
+    _offset: uacpi_size,
+    _out_value: *mut uacpi_u16
+) -> uacpi_status {
+    unsafe {
+        *_out_value = inw(_arg1 as u16);
+    }
+    uacpi_status_UACPI_STATUS_OK
+}
+
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn uacpi_kernel_io_read32(
+    _arg1: uacpi_handle,
+    _offset: uacpi_size,
+    _out_value: *mut uacpi_u32
+) -> uacpi_status {
+    unsafe {
+        *_out_value = inl(_arg1 as u16);
+    }
+    uacpi_status_UACPI_STATUS_OK
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_io_write8(
+    _arg1: uacpi_handle,
+    _offset: uacpi_size,
+    _in_value: uacpi_u8
+) -> uacpi_status {
+    outb(_arg1 as u16, _in_value);
+    uacpi_status_UACPI_STATUS_OK
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_io_write16(
+    _arg1: uacpi_handle,
+    _offset: uacpi_size,
+    _in_value: uacpi_u16
+) -> uacpi_status {
+    outw(_arg1 as u16, _in_value);
+    uacpi_status_UACPI_STATUS_OK
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_io_write32(
+    _arg1: uacpi_handle,
+    _offset: uacpi_size,
+    _in_value: uacpi_u32
+) -> uacpi_status {
+    outl(_arg1 as u16, _in_value);
+    uacpi_status_UACPI_STATUS_OK
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_alloc(_size: uacpi_size) -> *mut ::core::ffi::c_void {
+    unsafe {
+        alloc::alloc::alloc(Layout::from_size_align(_size, 16).unwrap()) as *mut ::core::ffi::c_void
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_free(_mem: *mut ::core::ffi::c_void) {
+    // TODO: save layouts used somewhere and call alloc::alloc::dealloc. Not required for now as we
+    // have a bump alloator but yeah
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_get_nanoseconds_since_boot() -> uacpi_u64 {
+    timer::monotonic_ns()
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_stall(_usec: uacpi_u8) {
+    timer::delay(Duration::from_micros(_usec as u64));
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_sleep(_msec: uacpi_u64) {
+    timer::wheel::sleep(Duration::from_millis(_msec));
+}
+
+// uACPI takes care of recursion and ownership itself, so both mutexes and events are semaphores
+fn new_semaphore(count: usize) -> uacpi_handle {
+    Box::into_raw(Box::new(Semaphore::new(count))) as uacpi_handle
+}
+
+fn free_semaphore(handle: uacpi_handle) {
+    if !handle.is_null() {
+        drop(unsafe { Box::from_raw(handle as *mut Semaphore) });
+    }
+}
+
+// A timeout of 0xffff means waiting forever
+fn acquire_semaphore(handle: uacpi_handle, timeout_ms: uacpi_u16) -> bool {
+    let semaphore = unsafe { &*(handle as *const Semaphore) };
+    match timeout_ms {
+        0 => semaphore.try_acquire(),
+        0xffff => {
+            semaphore.acquire();
+            true
+        }
+        ms => semaphore.acquire_timeout(Duration::from_millis(ms as u64)),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_create_mutex() -> uacpi_handle {
+    new_semaphore(1)
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_free_mutex(_arg1: uacpi_handle) {
+    free_semaphore(_arg1);
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_create_event() -> uacpi_handle {
+    new_semaphore(0)
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_free_event(_arg1: uacpi_handle) {
+    free_semaphore(_arg1);
+}
+
+// Offset by one since a null thread ID means "no thread" to uACPI
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_get_thread_id() -> uacpi_thread_id {
+    (sched::current().to_usize() + 1) as uacpi_thread_id
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_acquire_mutex(
+    _arg1: uacpi_handle,
+    _arg2: uacpi_u16
+) -> uacpi_status {
+    if acquire_semaphore(_arg1, _arg2) {
+        uacpi_status_UACPI_STATUS_OK
+    } else {
+        uacpi_status_UACPI_STATUS_TIMEOUT
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_release_mutex(_arg1: uacpi_handle) {
+    unsafe { &*(_arg1 as *const Semaphore) }.release();
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_wait_for_event(_arg1: uacpi_handle, _arg2: uacpi_u16) -> uacpi_bool {
+    acquire_semaphore(_arg1, _arg2)
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_signal_event(_arg1: uacpi_handle) {
+    unsafe { &*(_arg1 as *const Semaphore) }.release();
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_reset_event(_arg1: uacpi_handle) {
+    unsafe { &*(_arg1 as *const Semaphore) }.reset();
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_handle_firmware_request(
+    _arg1: *mut uacpi_firmware_request
+) -> uacpi_status {
+    uacpi_status_UACPI_STATUS_UNIMPLEMENTED
+}
+
+struct UacpiIrq {
+    handler: uacpi_interrupt_handler,
+    ctx: uacpi_handle,
+    gsi: u32,
+    vector: u8,
+}
+
+fn uacpi_irq_trampoline(ctx: usize) {
+    let irq = unsafe { &*(ctx as *const UacpiIrq) };
+    if let Some(handler) = irq.handler {
+        unsafe {
+            handler(irq.ctx);
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn uacpi_kernel_install_interrupt_handler(
+    _irq: uacpi_u32,
+    _arg1: uacpi_interrupt_handler,
+    _ctx: uacpi_handle,
+    _out_irq_handle: *mut uacpi_handle
+) -> uacpi_status {
+    // uACPI only ever installs the SCI
+    let route = irq::sci_route(_irq as u8);
+
+    let uacpi_irq = Box::into_raw(
+        Box::new(UacpiIrq {
+            handler: _arg1,
+            ctx: _ctx,
+            gsi: route.gsi,
+            vector: 0,
+        })
+    );
+
+    match irq::install_gsi_handler(route, uacpi_irq_trampoline, uacpi_irq as usize) {
+        Ok(vector) => {
+            debug!(
+                "Installed uACPI interrupt handler for IRQ {_irq} (GSI {}) on vector {vector:#x}",
+                route.gsi
+            );
+            unsafe {
+                (*uacpi_irq).vector = vector;
+                *_out_irq_handle = uacpi_irq as *mut core::ffi::c_void;
+            }
+            uacpi_status_UACPI_STATUS_OK
+        }
+        Err(err) => {
+            error!("Could not install uACPI interrupt handler for IRQ {_irq}: {err:?}");
+            drop(unsafe { Box::from_raw(uacpi_irq) });
+            uacpi_status_UACPI_STATUS_INTERNAL_ERROR
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_uninstall_interrupt_handler(
+    _arg1: uacpi_interrupt_handler,
+    _irq_handle: uacpi_handle
+) -> uacpi_status {
+    let uacpi_irq = unsafe { Box::from_raw(_irq_handle as *mut UacpiIrq) };
+    irq::uninstall_gsi_handler(uacpi_irq.gsi, uacpi_irq.vector);
+    uacpi_status_UACPI_STATUS_OK
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_create_spinlock() -> uacpi_handle {
+    unsafe {
+        alloc::alloc::alloc_zeroed(
+            Layout::from_size_align(
+                core::mem::size_of::<AtomicBool>(),
+                core::mem::align_of::<AtomicBool>()
+            ).unwrap()
+        ) as *mut ::core::ffi::c_void
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_free_spinlock(_arg1: uacpi_handle) {}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_lock_spinlock(_arg1: uacpi_handle) -> uacpi_cpu_flags {
+    let flag = unsafe { &*(_arg1 as *mut AtomicBool) };
+
+    // uACPI takes these from the SCI handler too, so the holder must not be interrupted. The flags
+    // we hand back only say whether interrupts were enabled before
+    let flags = interrupts_enabled() as uacpi_cpu_flags;
+    disable_interrupts();
+
+    while
+        flag
+            .compare_exchange_weak(
+                false,
+                true,
+                core::sync::atomic::Ordering::AcqRel,
+                core::sync::atomic::Ordering::Acquire
+            )
+            .is_err()
+    {
+        spin_loop();
+    }
+
+    flags
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_unlock_spinlock(_arg1: uacpi_handle, _arg2: uacpi_cpu_flags) {
+    let flag = unsafe { &*(_arg1 as *mut AtomicBool) };
+    flag.store(false, core::sync::atomic::Ordering::Release);
+
+    if _arg2 != 0 {
+        enable_interrupts();
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_schedule_work(
+    _arg1: uacpi_work_type,
+    _arg2: uacpi_work_handler,
+    _ctx: uacpi_handle
+) -> uacpi_status {
+    let Some(handler) = _arg2 else {
+        return uacpi_status_UACPI_STATUS_INVALID_ARGUMENT;
+    };
+
+    // GPE work has to run on the CPU that received the SCI, which is all of them as long as
+    // there is only one
+    let ctx = _ctx as usize;
+    workqueue::queue(move || unsafe { handler(ctx as uacpi_handle) });
+
+    uacpi_status_UACPI_STATUS_OK
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn uacpi_kernel_wait_for_work_completion() -> uacpi_status {
+    workqueue::wait_for_completion();
+    uacpi_status_UACPI_STATUS_OK
+}
@@ -0,0 +1,300 @@
+use alloc::{ string::String, vec::Vec };
+use core::{ ffi::{ CStr, c_void }, time::Duration };
+
+use uacpi::{
+    UACPI_MAX_DEPTH_ANY,
+    uacpi_handle,
+    uacpi_install_notify_handler,
+    uacpi_iteration_decision,
+    uacpi_iteration_decision_UACPI_ITERATION_DECISION_CONTINUE,
+    uacpi_namespace_for_each_child,
+    uacpi_namespace_node,
+    uacpi_namespace_root,
+    uacpi_object_type_bits_UACPI_OBJECT_THERMAL_ZONE_BIT,
+    uacpi_status,
+    uacpi_status_UACPI_STATUS_OK,
+};
+
+use crate::{
+    debug,
+    error,
+    info,
+    misc::{ acpi::{ devices::node_path, eval::eval_integer }, power },
+    sync::{ mutex::Mutex, timer::wheel, workqueue },
+    warning,
+};
+
+// Thermal zone notifications, see section 11.4 of the ACPI spec
+const NOTIFY_TEMPERATURE_CHANGED: u64 = 0x80;
+const NOTIFY_TRIP_POINTS_CHANGED: u64 = 0x81;
+
+// ACPI reports temperatures in tenths of a kelvin
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Temperature(u32);
+
+impl Temperature {
+    pub const fn from_deci_kelvin(deci_kelvin: u32) -> Self {
+        Self(deci_kelvin)
+    }
+
+    pub const fn deci_kelvin(self) -> u32 {
+        self.0
+    }
+
+    pub const fn deci_celsius(self) -> i32 {
+        (self.0 as i32) - 2732
+    }
+}
+
+impl core::fmt::Display for Temperature {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let deci_celsius = self.deci_celsius();
+        write!(
+            f,
+            "{}{}.{}C",
+            if deci_celsius < 0 { "-" } else { "" },
+            deci_celsius.unsigned_abs() / 10,
+            deci_celsius.unsigned_abs() % 10
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ThermalZone {
+    pub path: String,
+    // Past this the system has to be shut down right away
+    pub critical: Option<Temperature>,
+    // Past this the system should go to S4, which we can't do, so this only gets logged
+    pub hot: Option<Temperature>,
+    // Past this the OS is supposed to start throttling the processors
+    pub passive: Option<Temperature>,
+    // How often the firmware wants the temperature to be polled, None if it sends notifications
+    pub polling_interval: Option<Duration>,
+    node: usize,
+}
+
+impl ThermalZone {
+    fn new(node: *mut uacpi_namespace_node) -> Self {
+        let mut zone = Self {
+            path: node_path(node),
+            critical: None,
+            hot: None,
+            passive: None,
+            polling_interval: None,
+            node: node as usize,
+        };
+
+        zone.update_trip_points();
+        zone
+    }
+
+    pub fn node(&self) -> *mut uacpi_namespace_node {
+        self.node as *mut uacpi_namespace_node
+    }
+
+    fn eval_temperature(&self, path: &CStr) -> Option<Temperature> {
+        // Some firmware returns 0 for trip points it doesn't use
+        eval_integer(self.node(), path)
+            .filter(|x| *x != 0)
+            .map(|x| Temperature::from_deci_kelvin(x as u32))
+    }
+
+    fn update_trip_points(&mut self) {
+        self.critical = self.eval_temperature(c"_CRT");
+        self.hot = self.eval_temperature(c"_HOT");
+        self.passive = self.eval_temperature(c"_PSV");
+
+        // _TZP is in tenths of a second, with 0 meaning that the zone sends notifications
+        self.polling_interval = eval_integer(self.node(), c"_TZP")
+            .filter(|x| *x != 0)
+            .map(|x| Duration::from_millis(x * 100));
+    }
+
+    pub fn temperature(&self) -> Option<Temperature> {
+        self.eval_temperature(c"_TMP")
+    }
+}
+
+static ZONES: Mutex<Vec<ThermalZone>> = Mutex::new(Vec::new());
+
+pub fn zones() -> Vec<ThermalZone> {
+    ZONES.lock().clone()
+}
+
+// Reads the temperature of every zone and shuts down if any of them is past its critical trip
+// point. This evaluates AML, so it can't be called from an interrupt handler
+pub fn check() {
+    for zone in zones() {
+        check_zone(&zone);
+    }
+}
+
+fn check_zone(zone: &ThermalZone) {
+    let Some(temperature) = zone.temperature() else {
+        return;
+    };
+
+    if let Some(critical) = zone.critical && temperature >= critical {
+        error!(
+            "Thermal zone {} is at {temperature}, past its critical trip point of {critical}",
+            zone.path
+        );
+        power::shutdown();
+    }
+
+    if let Some(hot) = zone.hot && temperature >= hot {
+        warning!(
+            "Thermal zone {} is at {temperature}, past its hot trip point of {hot}",
+            zone.path
+        );
+    } else if let Some(passive) = zone.passive && temperature >= passive {
+        warning!(
+            "Thermal zone {} is at {temperature}, past its passive trip point of {passive}",
+            zone.path
+        );
+    } else {
+        debug!("Thermal zone {} is at {temperature}", zone.path);
+    }
+}
+
+fn update_trip_points(node: usize) {
+    let lock = ZONES.lock();
+    let Some(idx) = lock.iter().position(|x| x.node == node) else {
+        return;
+    };
+
+    // Evaluating the trip points runs AML, so don't do it with the lock held
+    let mut zone = lock[idx].clone();
+    drop(lock);
+    let was_polled = zone.polling_interval.is_some();
+    zone.update_trip_points();
+
+    // A zone that just started asking to be polled has no timer yet, the others re-arm their own
+    if !was_polled {
+        arm_polling(&zone);
+    }
+
+    let mut lock = ZONES.lock();
+    if let Some(entry) = lock.iter_mut().find(|x| x.node == node) {
+        *entry = zone;
+    }
+}
+
+// Zones with a _TZP never send notifications, so they get checked every polling interval instead
+fn arm_polling(zone: &ThermalZone) {
+    let Some(interval) = zone.polling_interval else {
+        return;
+    };
+
+    if wheel::add_timer(interval, poll_timer, zone.node).is_err() {
+        warning!("Could not arm the polling timer of thermal zone {}", zone.path);
+    }
+}
+
+fn poll_timer(node: usize) {
+    workqueue::queue(move || poll_zone(node));
+}
+
+fn poll_zone(node: usize) {
+    let Some(zone) = ZONES.lock()
+        .iter()
+        .find(|x| x.node == node)
+        .cloned() else {
+        return;
+    };
+
+    check_zone(&zone);
+    arm_polling(&zone);
+}
+
+unsafe extern "C" fn notify_handler(
+    _ctx: uacpi_handle,
+    node: *mut uacpi_namespace_node,
+    value: u64
+) -> uacpi_status {
+    let node = node as usize;
+    match value {
+        NOTIFY_TEMPERATURE_CHANGED => workqueue::queue(check),
+        NOTIFY_TRIP_POINTS_CHANGED =>
+            workqueue::queue(move || {
+                update_trip_points(node);
+                check();
+            }),
+        _ => debug!("Unhandled thermal zone notification {value:#x}"),
+    }
+
+    uacpi_status_UACPI_STATUS_OK
+}
+
+unsafe extern "C" fn collect_zone(
+    user: *mut c_void,
+    node: *mut uacpi_namespace_node,
+    _depth: u32
+) -> uacpi_iteration_decision {
+    let zones = unsafe { &mut *(user as *mut Vec<ThermalZone>) };
+    zones.push(ThermalZone::new(node));
+
+    uacpi_iteration_decision_UACPI_ITERATION_DECISION_CONTINUE
+}
+
+fn log_zone(zone: &ThermalZone) {
+    info!("Thermal zone {}:", zone.path);
+    match zone.temperature() {
+        Some(temperature) => info!("    Temperature: {temperature}"),
+        None => info!("    Temperature: unknown"),
+    }
+    if let Some(critical) = zone.critical {
+        info!("    Critical trip point: {critical}");
+    }
+    if let Some(hot) = zone.hot {
+        info!("    Hot trip point: {hot}");
+    }
+    if let Some(passive) = zone.passive {
+        info!("    Passive trip point: {passive}");
+    }
+    if let Some(interval) = zone.polling_interval {
+        debug!("    Polling interval: {}ms", interval.as_millis());
+    }
+}
+
+pub fn init() {
+    let mut zones: Vec<ThermalZone> = Vec::new();
+    unsafe {
+        uacpi_namespace_for_each_child(
+            uacpi_namespace_root(),
+            Some(collect_zone),
+            None,
+            uacpi_object_type_bits_UACPI_OBJECT_THERMAL_ZONE_BIT,
+            UACPI_MAX_DEPTH_ANY,
+            &raw mut zones as *mut c_void
+        );
+    }
+
+    for zone in &zones {
+        log_zone(zone);
+
+        if
+            (unsafe {
+                uacpi_install_notify_handler(
+                    zone.node(),
+                    Some(notify_handler),
+                    core::ptr::null_mut()
+                )
+            }) != uacpi_status_UACPI_STATUS_OK
+        {
+            warning!("Could not install notify handler for thermal zone {}", zone.path);
+        }
+    }
+
+    info!("Found {} thermal zone(s)", zones.len());
+
+    // The zones have to be in place before the first poll comes in, or it wouldn't find its zone
+    // and polling would stop for good
+    *ZONES.lock() = zones;
+    for zone in ZONES.lock().iter() {
+        arm_polling(zone);
+    }
+
+    // The firmware might have booted us while already being too hot
+    check();
+}
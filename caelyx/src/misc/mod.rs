@@ -1,6 +1,7 @@
 pub mod isituninit;
 pub mod output;
 pub mod panic;
+pub mod power;
 pub mod ptr_align;
 pub mod acpi;
 pub mod str_writer;
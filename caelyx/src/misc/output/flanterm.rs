@@ -11,7 +11,10 @@ use crate::{
 };
 use flanterm::{flanterm_context, flanterm_fb_context, flanterm_fb_init, flanterm_write};
 
-static FLANTERM_CONTEXT: Mutex<IsItUninit<&flanterm_context>> = Mutex::new(IsItUninit::uninit());
+static FLANTERM_CONTEXT: Mutex<IsItUninit<&flanterm_context>> = Mutex::named(
+    "flanterm",
+    IsItUninit::uninit()
+);
 static DISABLE_FLANTERM_LOGGING: Mutex<bool> = Mutex::new(false);
 
 // https://github.com/fcambus/spleen
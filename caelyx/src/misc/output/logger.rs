@@ -1,11 +1,15 @@
 use core::fmt::Arguments;
 
 use crate::{
-    misc::{ isituninit::IsItUninit, output::raw_print::{ print_fmt, print_line_ending } },
-    sync::mutex::Mutex,
+    drvs::rtc,
+    misc::output::raw_print::{ print_fmt, print_line_ending },
+    sync::{ mutex::Mutex, once::Once, rwlock::RwSpinLock, timer::monotonic_ns },
 };
 
-static LOGGER: Mutex<IsItUninit<Logger>> = Mutex::new(IsItUninit::uninit());
+// Messages below the level are dropped under the read lock, so they never wait on each other
+static LOGGER: Once<RwSpinLock<Logger>> = Once::new();
+// Keeps the lines of messages that do get printed from interleaving
+static PRINT_LOCK: Mutex<()> = Mutex::named("logger", ());
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum LogLevel {
@@ -56,8 +60,30 @@ impl Logger {
         Self { level }
     }
 
-    pub fn log(&mut self, log: Log) {
+    pub fn log(&self, log: Log) {
         if log.level.should_display(&self.level) {
+            let _lock = PRINT_LOCK.lock();
+
+            // Wall clock time once the RTC has been read, time since boot before that
+            if rtc::wall_clock_valid() {
+                let ns = rtc::wall_clock_ns();
+                let seconds = ns / 1_000_000_000;
+                print_fmt(
+                    format_args!(
+                        "[{:02}:{:02}:{:02}.{:06}] ",
+                        (seconds / 3600) % 24,
+                        (seconds / 60) % 60,
+                        seconds % 60,
+                        (ns / 1000) % 1_000_000
+                    )
+                );
+            } else {
+                let ns = monotonic_ns();
+                print_fmt(
+                    format_args!("[{:5}.{:06}] ", ns / 1_000_000_000, (ns / 1000) % 1_000_000)
+                );
+            }
+
             print_fmt(format_args!("{} {}:{} ", log.level, log.file, log.line));
             print_fmt(log.args);
             print_line_ending();
@@ -66,14 +92,18 @@ impl Logger {
 }
 
 pub fn init() {
-    let mut lock = LOGGER.lock();
-    lock.write(Logger::new(LogLevel::Trace));
+    LOGGER.call_once(|| RwSpinLock::new(Logger::new(LogLevel::Trace)));
+}
+
+pub fn set_level(level: LogLevel) {
+    if let Some(logger) = LOGGER.get() {
+        logger.write().level = level;
+    }
 }
 
 pub fn log(level: LogLevel, file: &'static str, line: u32, args: Arguments<'_>) {
-    let mut lock = LOGGER.lock();
-    if let Some(logger) = lock.try_get_mut() {
-        logger.log(Log {
+    if let Some(logger) = LOGGER.get() {
+        logger.read().log(Log {
             file,
             line,
             level,
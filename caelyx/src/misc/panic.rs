@@ -1,10 +1,23 @@
+use core::sync::atomic::{ AtomicBool, Ordering };
+
 use crate::fatal;
 use crate::misc::output::raw_print::print_line_ending;
+use crate::misc::power;
 use crate::x86::halt;
 use crate::x86::idt::interrupt_control::disable_interrupts;
 
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
 #[panic_handler]
 pub fn panic_handler(info: &core::panic::PanicInfo) -> ! {
+    // If rebooting or powering off panics too there is nothing left to try
+    if PANICKING.swap(true, Ordering::AcqRel) {
+        disable_interrupts();
+        loop {
+            halt();
+        }
+    }
+
     print_line_ending();
     fatal!(r"  -------------            -------------    ");
     fatal!(r"/             \          /             \  ");
@@ -26,6 +39,7 @@ pub fn panic_handler(info: &core::panic::PanicInfo) -> ! {
     }
 
     fatal!("\tmessage: \"{}\"", info.message());
+    power::on_panic();
     disable_interrupts();
     fatal!("kernel halted");
     loop {
@@ -0,0 +1,181 @@
+use core::{ sync::atomic::{ AtomicU8, AtomicU32, Ordering }, time::Duration };
+
+use uacpi::{
+    uacpi_enter_sleep_state,
+    uacpi_prepare_for_sleep_state,
+    uacpi_reboot,
+    uacpi_sleep_state_UACPI_SLEEP_STATE_S5,
+    uacpi_status_UACPI_STATUS_OK,
+};
+
+use crate::{
+    boot::multiboot2::{ MultibootTag, TagIterator },
+    error,
+    fatal,
+    info,
+    sync::timer,
+    warning,
+    x86::{
+        gdt::SharedGdtrAndIdtr,
+        halt,
+        idt::interrupt_control::disable_interrupts,
+        ioport::{ inb, outb },
+    },
+};
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicAction {
+    Halt = 0,
+    Reboot = 1,
+    PowerOff = 2,
+}
+
+static PANIC_ACTION: AtomicU8 = AtomicU8::new(PanicAction::Halt as u8);
+static PANIC_DELAY_MS: AtomicU32 = AtomicU32::new(0);
+
+// Decides what the panic handler does after printing the panic message
+pub fn set_panic_action(action: PanicAction, delay: Duration) {
+    PANIC_DELAY_MS.store(delay.as_millis() as u32, Ordering::Relaxed);
+    PANIC_ACTION.store(action as u8, Ordering::Relaxed);
+}
+
+// Picks the panic action from the kernel command line, e.g. "panic=reboot,5" reboots 5 seconds
+// after a panic. Without a delay it happens right away
+pub fn init(tag_iter: &mut TagIterator) {
+    let cmdline = tag_iter.find_map(|x| {
+        match x {
+            MultibootTag::CmdLine(cmdline) => Some(cmdline),
+            _ => None,
+        }
+    });
+    let Some(option) = cmdline
+        .unwrap_or("")
+        .split_whitespace()
+        .find_map(|x| x.strip_prefix("panic=")) else {
+        return;
+    };
+
+    let (action, delay) = option.split_once(',').unwrap_or((option, "0"));
+    let action = match action {
+        "halt" => PanicAction::Halt,
+        "reboot" => PanicAction::Reboot,
+        "poweroff" => PanicAction::PowerOff,
+        _ => {
+            warning!("Unknown panic action {action:?}, halting on panic");
+            return;
+        }
+    };
+    let Ok(delay) = delay.parse::<u64>() else {
+        warning!("Invalid panic delay {delay:?}, halting on panic");
+        return;
+    };
+
+    set_panic_action(action, Duration::from_secs(delay));
+    info!("Panic action: {action:?} after {delay}s");
+}
+
+pub fn panic_action() -> PanicAction {
+    match PANIC_ACTION.load(Ordering::Relaxed) {
+        1 => PanicAction::Reboot,
+        2 => PanicAction::PowerOff,
+        _ => PanicAction::Halt,
+    }
+}
+
+// Called by the panic handler once the panic has been reported. Only returns if the action is
+// PanicAction::Halt
+pub fn on_panic() {
+    let action = panic_action();
+    if action == PanicAction::Halt {
+        return;
+    }
+
+    let delay = Duration::from_millis(PANIC_DELAY_MS.load(Ordering::Relaxed) as u64);
+    fatal!(
+        "{} in {}.{:03}s",
+        if action == PanicAction::Reboot { "rebooting" } else { "powering off" },
+        delay.as_secs(),
+        delay.subsec_millis()
+    );
+
+    timer::delay(delay);
+
+    match action {
+        PanicAction::Reboot => reboot(),
+        PanicAction::PowerOff => shutdown(),
+        PanicAction::Halt => unreachable!(),
+    }
+}
+
+fn halt_forever() -> ! {
+    disable_interrupts();
+    loop {
+        halt();
+    }
+}
+
+// Enters S5. uACPI has to evaluate \_PTS and \_S5 for this, so it can't be called from an
+// interrupt handler
+pub fn shutdown() -> ! {
+    info!("Shutting down");
+
+    unsafe {
+        if
+            uacpi_prepare_for_sleep_state(uacpi_sleep_state_UACPI_SLEEP_STATE_S5) !=
+            uacpi_status_UACPI_STATUS_OK
+        {
+            error!("Could not prepare for S5");
+            halt_forever();
+        }
+
+        disable_interrupts();
+        uacpi_enter_sleep_state(uacpi_sleep_state_UACPI_SLEEP_STATE_S5);
+    }
+
+    error!("Could not enter S5");
+    halt_forever();
+}
+
+fn reboot_8042() {
+    // Wait for the input buffer of the keyboard controller to be empty before pulsing the reset
+    // line with command 0xFE
+    for _ in 0..0x10000 {
+        if (inb(0x64) & 0b10) == 0 {
+            break;
+        }
+    }
+
+    outb(0x64, 0xfe);
+}
+
+fn reboot_triple_fault() -> ! {
+    // With an empty IDT the next exception can't be delivered, which turns into a double fault that
+    // can't be delivered either, at which point the CPU gives up and resets
+    let idtr = SharedGdtrAndIdtr { limit: 0, base: 0 };
+    unsafe {
+        core::arch::asm!("lidt [{idtr:e}]", "int3", idtr = in(reg) &raw const idtr);
+    }
+
+    unreachable!();
+}
+
+pub fn reboot() -> ! {
+    info!("Rebooting");
+    disable_interrupts();
+
+    // This uses the reset register from the FADT, if there is one
+    if (unsafe { uacpi_reboot() }) != uacpi_status_UACPI_STATUS_OK {
+        warning!("ACPI reset failed, trying the 8042");
+    }
+
+    reboot_8042();
+
+    // Give the keyboard controller a moment to pull the reset line
+    for _ in 0..0x100000 {
+        core::hint::spin_loop();
+    }
+
+    warning!("8042 reset failed, triple faulting");
+    reboot_triple_fault();
+}
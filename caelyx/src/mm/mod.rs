@@ -1,4 +1,5 @@
 pub mod heap;
 pub mod pmm;
+pub mod tlb;
 pub mod virt_page_alloc;
 pub mod vmm;
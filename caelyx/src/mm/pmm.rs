@@ -9,7 +9,7 @@ use crate::{
     },
     debug,
     misc::{ isituninit::IsItUninit, ptr_align::{ align_ptr_down, align_ptr_up } },
-    sync::mutex::Mutex,
+    sync::ticket::{ LockStats, TicketMutex },
     trace,
 };
 
@@ -242,7 +242,11 @@ impl PhysicalMemoryAllocator {
     }
 }
 
-static PMM: Mutex<IsItUninit<PhysicalMemoryAllocator>> = Mutex::new(IsItUninit::uninit());
+// Every page allocation goes through the bitmap, so it's the first lock to fight over with SMP
+static PMM: TicketMutex<IsItUninit<PhysicalMemoryAllocator>> = TicketMutex::named(
+    "pmm",
+    IsItUninit::uninit()
+);
 
 pub fn init(tag_iter: &mut MultibootTagIterator) {
     PMM.lock().write(PhysicalMemoryAllocator::new(tag_iter).expect("Could not create PMM"));
@@ -257,3 +261,7 @@ pub fn free(addr: *const u8, count: usize) {
     let lock = PMM.lock();
     lock.get_ref().free(addr as usize, count);
 }
+
+pub fn lock_stats() -> LockStats {
+    PMM.stats()
+}
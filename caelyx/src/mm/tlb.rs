@@ -0,0 +1,203 @@
+use core::{
+    hint::spin_loop,
+    ptr::null_mut,
+    sync::atomic::{ AtomicBool, AtomicPtr, AtomicU8, AtomicU32, Ordering },
+};
+
+use crate::{
+    debug,
+    mm::pmm,
+    percpu,
+    x86::{ idt::interrupt_control::IrqGuard, irq, lapic, percpu::{ self, MAX_CPUS } },
+};
+
+// Past this many pages reloading cr3 is cheaper than invalidating them one by one
+const MAX_BATCH_PAGES: usize = 16;
+const MAX_BATCH_FRAMES: usize = 8;
+
+// Invalidations that have to happen on every CPU before the frames that were mapped there can be
+// reused. Everything is done when the batch gets flushed, which happens when it's dropped at the
+// latest
+pub struct TlbBatch {
+    pages: [u32; MAX_BATCH_PAGES],
+    page_count: usize,
+    flush_all: bool,
+    // (address, page count) to give back to the PMM once no CPU can reach them anymore
+    frames: [(u32, usize); MAX_BATCH_FRAMES],
+    frame_count: usize,
+}
+
+// The batch of the CPU doing a shootdown. Only one can be going on at a time
+static REQUEST: AtomicPtr<TlbBatch> = AtomicPtr::new(null_mut());
+static REQUEST_LOCKED: AtomicBool = AtomicBool::new(false);
+static PENDING_ACKS: AtomicU32 = AtomicU32::new(0);
+// 0 until init(), before that every flush stays local
+static VECTOR: AtomicU8 = AtomicU8::new(0);
+
+percpu! {
+    // Set for every CPU that still has to flush for the current request
+    static FLUSH_PENDING: AtomicBool = AtomicBool::new(false);
+}
+
+fn invlpg(virt_addr: u32) {
+    unsafe {
+        core::arch::asm!("invlpg [{virt_addr}]", virt_addr = in(reg) virt_addr);
+    }
+}
+
+fn reload_cr3() {
+    unsafe {
+        core::arch::asm!("mov {tmp:e}, cr3", "mov cr3, {tmp:e}", tmp = out(reg) _);
+    }
+}
+
+impl TlbBatch {
+    pub const fn new() -> Self {
+        Self {
+            pages: [0; MAX_BATCH_PAGES],
+            page_count: 0,
+            flush_all: false,
+            frames: [(0, 0); MAX_BATCH_FRAMES],
+            frame_count: 0,
+        }
+    }
+
+    pub fn invalidate(&mut self, virt_addr: u32) {
+        if self.page_count == MAX_BATCH_PAGES {
+            self.flush_all = true;
+            return;
+        }
+
+        self.pages[self.page_count] = virt_addr;
+        self.page_count += 1;
+    }
+
+    // Frees the frames only after every CPU flushed its TLB
+    pub fn free_after(&mut self, phys: *const u8, count: usize) {
+        if self.frame_count == MAX_BATCH_FRAMES {
+            self.flush();
+        }
+
+        self.frames[self.frame_count] = (phys as u32, count);
+        self.frame_count += 1;
+    }
+
+    fn invalidate_local(&self) {
+        if self.flush_all {
+            reload_cr3();
+        } else {
+            for &page in &self.pages[..self.page_count] {
+                invlpg(page);
+            }
+        }
+    }
+
+    // Invalidates everything on this CPU and on every other online one, then frees the frames
+    pub fn flush(&mut self) {
+        if self.page_count == 0 && !self.flush_all && self.frame_count == 0 {
+            return;
+        }
+
+        self.invalidate_local();
+        shootdown(self);
+
+        for &(phys, count) in &self.frames[..self.frame_count] {
+            pmm::free(phys as *const u8, count);
+        }
+
+        *self = Self::new();
+    }
+}
+
+impl Default for TlbBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TlbBatch {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+// Flushes for the current request if this CPU still has to. Whoever waits on other CPUs keeps
+// calling this, since two CPUs shooting each other down with interrupts disabled would otherwise
+// wait on each other forever. The same goes for spinning on a Mutex, whose holder may be waiting
+// for us to flush, so Mutex::lock() calls it too
+pub fn handle_pending() {
+    // Nothing can be pending before init(), and the per-CPU data might not be there yet either
+    if VECTOR.load(Ordering::Acquire) == 0 {
+        return;
+    }
+    if !FLUSH_PENDING.get().swap(false, Ordering::AcqRel) {
+        return;
+    }
+
+    let request = REQUEST.load(Ordering::Acquire);
+    unsafe {
+        (*request).invalidate_local();
+    }
+    PENDING_ACKS.fetch_sub(1, Ordering::Release);
+}
+
+fn shootdown(batch: &TlbBatch) {
+    let vector = VECTOR.load(Ordering::Acquire);
+    if vector == 0 {
+        return;
+    }
+
+    let _guard = IrqGuard::new();
+    let this_cpu = percpu::cpu_id();
+    // Every CPU shares the kernel's address space, so every other online CPU may have it cached
+    let mut targets = 0u32;
+    for cpu in (0..MAX_CPUS).filter(|&cpu| cpu != this_cpu) {
+        if percpu::for_cpu(cpu).is_some_and(|x| x.online.load(Ordering::Acquire)) {
+            targets |= 1 << cpu;
+        }
+    }
+    if targets == 0 {
+        return;
+    }
+
+    while
+        REQUEST_LOCKED.compare_exchange_weak(
+            false,
+            true,
+            Ordering::Acquire,
+            Ordering::Relaxed
+        ).is_err()
+    {
+        handle_pending();
+        spin_loop();
+    }
+
+    REQUEST.store(batch as *const TlbBatch as *mut TlbBatch, Ordering::Release);
+    PENDING_ACKS.store(targets.count_ones(), Ordering::Release);
+    for cpu in (0..MAX_CPUS).filter(|&cpu| (targets & (1 << cpu)) != 0) {
+        // CPUs never go offline, so everything found above is still there
+        let data = percpu::for_cpu(cpu).unwrap();
+        FLUSH_PENDING.for_cpu(cpu).unwrap().store(true, Ordering::Release);
+        lapic::send_ipi(data.lapic_id.load(Ordering::Relaxed) as u8, vector as u32);
+    }
+
+    while PENDING_ACKS.load(Ordering::Acquire) != 0 {
+        handle_pending();
+        spin_loop();
+    }
+
+    REQUEST.store(null_mut(), Ordering::Release);
+    REQUEST_LOCKED.store(false, Ordering::Release);
+}
+
+fn ipi_handler(_ctx: usize) {
+    handle_pending();
+}
+
+pub fn init() {
+    let vector = irq::allocate_vector().expect("Could not allocate TLB shootdown vector");
+    irq::install_handler(vector, ipi_handler, 0).expect("Could not install TLB shootdown handler");
+    VECTOR.store(vector, Ordering::Release);
+
+    debug!("TLB shootdowns use vector {vector:#x}");
+}
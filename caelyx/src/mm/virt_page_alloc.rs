@@ -89,7 +89,10 @@ impl Default for VirtPageAllocator {
     }
 }
 
-static VIRT_PAGE_ALLOC: Mutex<IsItUninit<VirtPageAllocator>> = Mutex::new(IsItUninit::uninit());
+static VIRT_PAGE_ALLOC: Mutex<IsItUninit<VirtPageAllocator>> = Mutex::named(
+    "virt_page_alloc",
+    IsItUninit::uninit()
+);
 
 pub fn init() {
     let mut lock = VIRT_PAGE_ALLOC.lock();
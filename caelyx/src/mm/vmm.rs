@@ -2,7 +2,8 @@ use crate::{
     debug,
     info,
     misc::output::flanterm::paging_fix,
-    mm::pmm,
+    mm::{ pmm, tlb::TlbBatch },
+    sync::mutex::Mutex,
     trace,
     x86::cpuid::feature_present,
 };
@@ -255,6 +256,9 @@ impl PageTable {
 }
 
 static PAGE_DIRECTORY: PageDirectory = PageDirectory::create();
+// Held across looking at a PDE and acting on it, so that two threads can't both allocate a page
+// table for the same PDE, or one free a page table the other is about to map into
+static PAGING_LOCK: Mutex<()> = Mutex::named("vmm", ());
 
 pub fn map(
     phys_addr: u32,
@@ -267,6 +271,7 @@ pub fn map(
     let pde: usize = ((virt_addr >> 22) & 0x3ff) as usize;
     let pte: usize = ((virt_addr >> 12) & 0x3ff) as usize;
 
+    let _lock = PAGING_LOCK.lock();
     if !PAGE_DIRECTORY.get(pde).present {
         let pt = pmm::allocate(1).expect("Could not allocate PT");
 
@@ -323,6 +328,7 @@ pub fn map4mb(
 ) {
     let pde: usize = ((virt_addr >> 22) & 0x3ff) as usize;
 
+    let _lock = PAGING_LOCK.lock();
     if PAGE_DIRECTORY.get(pde).present {
         panic!("Double map (PDE level) 0x{virt_addr:08X}");
     }
@@ -345,9 +351,17 @@ pub fn map4mb(
 }
 
 pub fn unmap(virt_addr: u32) {
+    let mut batch = TlbBatch::new();
+    unmap_batched(virt_addr, &mut batch);
+}
+
+// Unmaps without flushing yet. Nothing that was mapped there may be reused before the batch is
+// flushed, since other CPUs can still reach it through their TLBs
+pub fn unmap_batched(virt_addr: u32, batch: &mut TlbBatch) {
     let pde: usize = ((virt_addr >> 22) & 0x3ff) as usize;
     let pte: usize = ((virt_addr >> 12) & 0x3ff) as usize;
 
+    let _lock = PAGING_LOCK.lock();
     let pde_entry = PAGE_DIRECTORY.get(pde);
     if !pde_entry.present {
         panic!("Double free (PDE level) 0x{virt_addr:08X}");
@@ -355,6 +369,7 @@ pub fn unmap(virt_addr: u32) {
 
     if pde_entry.page_size {
         PAGE_DIRECTORY.set(pde, PageDirectoryEntry::default());
+        batch.invalidate(virt_addr);
         debug!("Unmapped 4MB page at 0x{virt_addr:08X}");
         return;
     }
@@ -373,17 +388,20 @@ pub fn unmap(virt_addr: u32) {
     }
 
     if !present {
-        pmm::free(pde_entry.addr as *const u8, 1);
         PAGE_DIRECTORY.set(pde, PageDirectoryEntry::default());
+        batch.free_after(pde_entry.addr as *const u8, 1);
     }
 
-    flush_tlb(virt_addr);
+    batch.invalidate(virt_addr);
 }
 
 // My virtual address space layout is beyond horrendously fucked:
 // 0x00000000 - 0x003FFFFF : Kernel
-// 0x00400000 - 0xBFFFFFFF : User (future)
+// 0x00400000 - 0xBFFFFFFF : User
 // 0xC0000000 - 0xFFFFFFFF : Kernel
+pub const USER_START: u32 = 0x0040_0000;
+pub const USER_END: u32 = 0xc000_0000;
+
 pub fn init() {
     assert!(feature_present(&crate::x86::cpuid::Features::Pse));
 
@@ -0,0 +1,28 @@
+section .text
+
+; void context_switch(uint32_t *old_esp, uint32_t new_esp)
+;
+; Saves the callee-saved registers and eflags on the current stack, stores the stack pointer in
+; *old_esp and picks up the thread whose stack new_esp points into. Everything else is
+; caller-saved in cdecl, so the compiler already took care of it
+global context_switch
+context_switch:
+    mov eax, [esp + 4]
+    mov edx, [esp + 8]
+
+    push ebp
+    push ebx
+    push esi
+    push edi
+    pushfd
+
+    mov [eax], esp
+    mov esp, edx
+
+    popfd
+    pop edi
+    pop esi
+    pop ebx
+    pop ebp
+
+    ret
@@ -0,0 +1,586 @@
+use alloc::boxed::Box;
+use core::{ sync::atomic::{ AtomicBool, AtomicU32, Ordering }, time::Duration };
+
+use crate::{
+    info,
+    sched::{ stack::KernelStack, user::UserMemory },
+    sync::{ mutex::Mutex, timer::{ self, monotonic_ns, tick, wheel } },
+    syscall,
+    x86::{
+        idt::interrupt_control::{
+            self,
+            IrqGuard,
+            disable_interrupts,
+            enable_interrupts,
+            interrupts_enabled,
+        },
+        irq::in_interrupt,
+        percpu,
+        tss,
+        wait_for_interrupt,
+    },
+};
+
+pub mod stack;
+pub mod user;
+#[cfg(feature = "user-demo")]
+pub mod user_demo;
+
+const MAX_THREADS: usize = 64;
+const NONE: u16 = u16::MAX;
+
+// How many ticks a thread gets before it has to make room for the others of the same priority
+const TIME_SLICE_TICKS: u32 = 5;
+
+// A new thread starts with interrupts disabled, thread_entry() enables them once the switch is
+// done. Bit 1 is reserved and always set
+const INITIAL_EFLAGS: u32 = 1 << 1;
+
+unsafe extern "C" {
+    fn context_switch(old_esp: *mut u32, new_esp: u32);
+}
+
+// Higher priorities always run first, threads of the same priority take turns
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Idle,
+    Low,
+    Normal,
+    High,
+}
+
+const PRIORITY_LEVELS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    Blocked,
+    Exited,
+}
+
+#[derive(Debug)]
+pub enum SchedError {
+    NoFreeThreads,
+    OutOfMemory,
+}
+
+// Stays unique after the thread exited and its slot got reused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadId {
+    index: u16,
+    generation: u16,
+}
+
+impl ThreadId {
+    // Packed into a usize so that it can be passed as a timer or IRQ context
+    pub fn to_usize(self) -> usize {
+        ((self.generation as usize) << 16) | (self.index as usize)
+    }
+
+    pub fn from_usize(val: usize) -> Self {
+        Self {
+            index: val as u16,
+            generation: (val >> 16) as u16,
+        }
+    }
+}
+
+type ThreadEntry = Box<dyn FnOnce() + Send>;
+
+struct Thread {
+    name: &'static str,
+    priority: Priority,
+    state: ThreadState,
+    // Set by unpark() on a thread that isn't blocked, so that its next park() returns right away
+    unpark_pending: bool,
+    // Nobody is going to join the thread, so its slot can go as soon as it exited
+    detached: bool,
+    joiner: Option<ThreadId>,
+    entry: Option<ThreadEntry>,
+    // None for the boot thread, which keeps running on the stack from the entry code
+    stack: Option<KernelStack>,
+    // Only set for threads that run in ring 3
+    user: Option<UserMemory>,
+}
+
+struct Scheduler {
+    threads: [Option<Thread>; MAX_THREADS],
+    generations: [u16; MAX_THREADS],
+    // One FIFO of ready threads per priority, linked through `next`
+    next: [u16; MAX_THREADS],
+    queues: [(u16, u16); PRIORITY_LEVELS],
+    current: u16,
+    slice_left: u32,
+    // A thread that exited, its stack can only be freed once we're off it
+    reap: u16,
+}
+
+impl Scheduler {
+    const fn new() -> Self {
+        Self {
+            threads: [const { None }; MAX_THREADS],
+            generations: [0; MAX_THREADS],
+            next: [NONE; MAX_THREADS],
+            queues: [(NONE, NONE); PRIORITY_LEVELS],
+            current: 0,
+            slice_left: TIME_SLICE_TICKS,
+            reap: NONE,
+        }
+    }
+
+    fn id(&self, index: u16) -> ThreadId {
+        ThreadId {
+            index,
+            generation: self.generations[index as usize],
+        }
+    }
+
+    fn thread_mut(&mut self, id: ThreadId) -> Option<&mut Thread> {
+        if self.generations.get(id.index as usize) != Some(&id.generation) {
+            return None;
+        }
+
+        self.threads[id.index as usize].as_mut()
+    }
+
+    fn current_mut(&mut self) -> &mut Thread {
+        self.threads[self.current as usize].as_mut().expect("Current thread has no slot")
+    }
+
+    fn enqueue(&mut self, index: u16) {
+        let thread = self.threads[index as usize].as_mut().expect("Queued thread has no slot");
+        thread.state = ThreadState::Ready;
+
+        let queue = &mut self.queues[thread.priority as usize];
+        if queue.1 == NONE {
+            queue.0 = index;
+        } else {
+            self.next[queue.1 as usize] = index;
+        }
+        queue.1 = index;
+        self.next[index as usize] = NONE;
+    }
+
+    fn dequeue(&mut self) -> Option<u16> {
+        let queue = self.queues
+            .iter_mut()
+            .rev()
+            .find(|x| x.0 != NONE)?;
+
+        let index = queue.0;
+        queue.0 = self.next[index as usize];
+        if queue.0 == NONE {
+            queue.1 = NONE;
+        }
+
+        Some(index)
+    }
+
+    // Frees the slot of an exited thread, unless we might still be running on its stack
+    fn release(&mut self, index: u16) {
+        if self.reap == index {
+            if let Some(thread) = self.threads[index as usize].as_mut() {
+                thread.detached = true;
+            }
+            return;
+        }
+
+        self.threads[index as usize] = None;
+        self.generations[index as usize] = self.generations[index as usize].wrapping_add(1);
+    }
+}
+
+static SCHEDULER: Mutex<Scheduler> = Mutex::named("sched", Scheduler::new());
+// Written by context_switch() after the scheduler lock has been dropped, so they live outside of it
+static SAVED_ESP: [AtomicU32; MAX_THREADS] = [const { AtomicU32::new(0) }; MAX_THREADS];
+static STARTED: AtomicBool = AtomicBool::new(false);
+// Set when the current thread should make way at the next opportunity
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+
+// Turns the flow of control we booted on into the first thread and starts preempting
+pub fn init() {
+    let mut lock = SCHEDULER.lock();
+    lock.threads[0] = Some(Thread {
+        name: "kmain",
+        priority: Priority::Normal,
+        state: ThreadState::Running,
+        unpark_pending: false,
+        detached: true,
+        joiner: None,
+        entry: None,
+        stack: None,
+        user: None,
+    });
+    lock.current = 0;
+    drop(lock);
+
+    STARTED.store(true, Ordering::Release);
+
+    // There has to be something to run when every other thread is blocked
+    spawn("idle", Priority::Idle, idle).expect("Could not spawn the idle thread");
+    tick::register_cpu_handler(scheduler_tick);
+
+    info!("Initialized scheduler");
+}
+
+pub fn started() -> bool {
+    STARTED.load(Ordering::Acquire)
+}
+
+// Threads only ever run on the boot CPU for now, the others just idle and handle IPIs
+fn on_sched_cpu() -> bool {
+    percpu::cpu_id() == 0
+}
+
+// Whether the caller can block, which rules out interrupt handlers and code holding a lock
+pub fn can_block() -> bool {
+    started() && on_sched_cpu() && !in_interrupt() && interrupts_enabled()
+}
+
+fn idle() {
+    loop {
+        // With interrupts off so that no timer gets added between looking at the wheel and halting
+        disable_interrupts();
+        timer::idle_enter();
+        wait_for_interrupt();
+        timer::idle_exit();
+        yield_now();
+    }
+}
+
+pub struct JoinHandle {
+    id: ThreadId,
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    // Waits for the thread to exit
+    pub fn join(self) {
+        loop {
+            let mut lock = SCHEDULER.lock();
+            let current = lock.id(lock.current);
+            match lock.thread_mut(self.id) {
+                Some(thread) if thread.state != ThreadState::Exited => {
+                    thread.joiner = Some(current);
+                }
+                _ => {
+                    break;
+                }
+            }
+            drop(lock);
+
+            park();
+        }
+    }
+}
+
+// Dropping the handle detaches the thread
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        let mut lock = SCHEDULER.lock();
+        let Some(thread) = lock.thread_mut(self.id) else {
+            return;
+        };
+
+        if thread.state == ThreadState::Exited {
+            lock.release(self.id.index);
+        } else {
+            thread.detached = true;
+        }
+    }
+}
+
+// Starts `entry` on a thread of its own
+pub fn spawn(
+    name: &'static str,
+    priority: Priority,
+    entry: impl FnOnce() + Send + 'static
+) -> Result<JoinHandle, SchedError> {
+    spawn_thread(name, priority, Box::new(entry), None)
+}
+
+fn spawn_thread(
+    name: &'static str,
+    priority: Priority,
+    entry: ThreadEntry,
+    user: Option<UserMemory>
+) -> Result<JoinHandle, SchedError> {
+    assert!(started(), "sched::spawn() called before sched::init()");
+
+    let stack = KernelStack::new().ok_or(SchedError::OutOfMemory)?;
+
+    // What context_switch() pops: eflags, edi, esi, ebx and ebp, then it returns into
+    // thread_entry(). The last slot stands in for thread_entry()'s return address
+    let frame: [u32; 7] = [INITIAL_EFLAGS, 0, 0, 0, 0, thread_entry as *const () as u32, 0];
+    let esp = stack.top() - (core::mem::size_of_val(&frame) as u32);
+    unsafe {
+        core::ptr::write(esp as *mut [u32; 7], frame);
+    }
+
+    let mut lock = SCHEDULER.lock();
+    let index = lock.threads
+        .iter()
+        .position(|x| x.is_none())
+        .ok_or(SchedError::NoFreeThreads)? as u16;
+
+    SAVED_ESP[index as usize].store(esp, Ordering::Relaxed);
+    lock.threads[index as usize] = Some(Thread {
+        name,
+        priority,
+        state: ThreadState::Ready,
+        unpark_pending: false,
+        detached: false,
+        joiner: None,
+        entry: Some(entry),
+        stack: Some(stack),
+        user,
+    });
+    lock.enqueue(index);
+
+    let id = lock.id(index);
+    let preempt = priority > lock.current_mut().priority;
+    drop(lock);
+
+    if preempt {
+        NEED_RESCHED.store(true, Ordering::Release);
+        preempt_check();
+    }
+
+    Ok(JoinHandle { id })
+}
+
+// Where every new thread starts, off the frame spawn() built
+extern "C" fn thread_entry() -> ! {
+    finish_switch();
+
+    let entry = {
+        let mut lock = SCHEDULER.lock();
+        lock.current_mut().entry.take()
+    };
+
+    enable_interrupts();
+    if let Some(entry) = entry {
+        entry();
+    }
+
+    exit();
+}
+
+// Switches to the next ready thread. The caller sets the state of the current thread first: a
+// thread that's still running goes back into its queue, anything else waits until it's woken up
+fn schedule() {
+    let _guard = IrqGuard::new();
+
+    let switch = {
+        let mut lock = SCHEDULER.lock();
+        let current = lock.current;
+        let state = lock.current_mut().state;
+        if state == ThreadState::Running {
+            lock.enqueue(current);
+        }
+
+        // The idle thread is always ready, so there's always something to run
+        let next = lock.dequeue().expect("No thread to run");
+        let thread = lock.threads[next as usize].as_mut().expect("Queued thread has no slot");
+        thread.state = ThreadState::Running;
+        // Where the CPU switches to when the thread gets interrupted in ring 3 or makes a syscall
+        if let Some(stack) = &thread.stack {
+            tss::set_kernel_stack(stack.top());
+            syscall::set_kernel_stack(stack.top());
+        }
+        lock.current = next;
+        // Mirrored out of the scheduler so that current() doesn't need the lock, lock debugging
+        // relies on that
+        percpu::cpu().current_thread.store(lock.id(next).to_usize() as u32, Ordering::Relaxed);
+        lock.slice_left = TIME_SLICE_TICKS;
+        NEED_RESCHED.store(false, Ordering::Release);
+
+        if state == ThreadState::Exited {
+            lock.reap = current;
+        }
+
+        (next != current).then_some((current, next))
+    };
+
+    if let Some((current, next)) = switch {
+        // Every thread has its own interrupt-disable nesting, which includes the guard above
+        let nesting = interrupt_control::take_nesting();
+        unsafe {
+            context_switch(
+                SAVED_ESP[current as usize].as_ptr(),
+                SAVED_ESP[next as usize].load(Ordering::Relaxed)
+            );
+        }
+        interrupt_control::restore_nesting(nesting);
+        finish_switch();
+    }
+}
+
+// Runs on the new thread right after a switch, with interrupts still disabled
+fn finish_switch() {
+    let mut lock = SCHEDULER.lock();
+    let reap = core::mem::replace(&mut lock.reap, NONE);
+    if reap == NONE {
+        return;
+    }
+
+    let Some(thread) = lock.threads[reap as usize].as_mut() else {
+        return;
+    };
+    let stack = thread.stack.take();
+    let user = thread.user.take();
+    if thread.detached {
+        lock.release(reap);
+    }
+    drop(lock);
+
+    drop(user);
+    drop(stack);
+}
+
+// Gives the rest of the time slice to the next thread of the same or a higher priority
+pub fn yield_now() {
+    if started() {
+        schedule();
+    }
+}
+
+// Switches right away if something asked for it and we're somewhere we can switch
+fn preempt_check() {
+    if NEED_RESCHED.load(Ordering::Acquire) && can_block() {
+        schedule();
+    }
+}
+
+// Called on the way out of an interrupt once it has been acknowledged, so that a thread woken up
+// by it or one whose time slice ran out gets switched away from
+pub fn preempt_from_irq() {
+    if started() && on_sched_cpu() && !in_interrupt() && NEED_RESCHED.load(Ordering::Acquire) {
+        schedule();
+    }
+}
+
+fn scheduler_tick() {
+    // The other CPUs have no threads to preempt yet
+    if !on_sched_cpu() {
+        return;
+    }
+
+    let mut lock = SCHEDULER.lock();
+    lock.slice_left = lock.slice_left.saturating_sub(1);
+    if lock.slice_left == 0 {
+        NEED_RESCHED.store(true, Ordering::Release);
+    }
+}
+
+pub fn current() -> ThreadId {
+    ThreadId::from_usize(percpu::cpu().current_thread.load(Ordering::Relaxed) as usize)
+}
+
+pub fn current_name() -> &'static str {
+    let lock = SCHEDULER.lock();
+    lock.threads[lock.current as usize].as_ref().map_or("kmain", |x| x.name)
+}
+
+// Blocks until unpark() gets called for the current thread. Returns right away if that already
+// happened since the last park(), and may return early, so always check the condition in a loop
+pub fn park() {
+    assert!(!in_interrupt(), "sched::park() called from interrupt context");
+    if !started() {
+        return;
+    }
+
+    let _guard = IrqGuard::new();
+
+    let mut lock = SCHEDULER.lock();
+    let thread = lock.current_mut();
+    let pending = core::mem::replace(&mut thread.unpark_pending, false);
+    if !pending {
+        thread.state = ThreadState::Blocked;
+    }
+    drop(lock);
+
+    if !pending {
+        schedule();
+    }
+}
+
+// Wakes up a parked thread. Safe to call from interrupt context
+pub fn unpark(id: ThreadId) {
+    let mut lock = SCHEDULER.lock();
+    let current_priority = lock.current_mut().priority;
+    let Some(thread) = lock.thread_mut(id) else {
+        return;
+    };
+
+    match thread.state {
+        ThreadState::Blocked => {
+            let priority = thread.priority;
+            lock.enqueue(id.index);
+            if priority > current_priority {
+                NEED_RESCHED.store(true, Ordering::Release);
+            }
+        }
+        ThreadState::Ready | ThreadState::Running => {
+            thread.unpark_pending = true;
+        }
+        ThreadState::Exited => {}
+    }
+    drop(lock);
+
+    preempt_check();
+}
+
+// Ends the current thread
+pub fn exit() -> ! {
+    assert!(!in_interrupt(), "sched::exit() called from interrupt context");
+    disable_interrupts();
+
+    let joiner = {
+        let mut lock = SCHEDULER.lock();
+        let thread = lock.current_mut();
+        thread.state = ThreadState::Exited;
+        thread.joiner.take()
+    };
+
+    if let Some(joiner) = joiner {
+        unpark(joiner);
+    }
+
+    schedule();
+    unreachable!("Exited thread got scheduled again");
+}
+
+fn wake_sleeper(ctx: usize) {
+    unpark(ThreadId::from_usize(ctx));
+}
+
+// Blocks the current thread until timer::monotonic_ns() reaches `deadline_ns`. Where we can't
+// block this waits on the timer wheel instead
+pub fn sleep_until(deadline_ns: u64) {
+    if !can_block() || !tick::started() {
+        wheel::sleep_until(deadline_ns);
+        return;
+    }
+
+    let id = current();
+    while monotonic_ns() < deadline_ns {
+        // The tick can fire a bit before the clock reaches the deadline, so this may take
+        // another round
+        let Ok(timer) = wheel::add_timer_at(deadline_ns, wake_sleeper, id.to_usize()) else {
+            yield_now();
+            continue;
+        };
+
+        park();
+        wheel::cancel(timer);
+    }
+}
+
+pub fn sleep(dur: Duration) {
+    sleep_until(monotonic_ns().saturating_add(dur.as_nanos() as u64));
+}
@@ -0,0 +1,50 @@
+use crate::mm::{ pmm, tlb::TlbBatch, virt_page_alloc, vmm };
+
+// 16KiB per kernel thread, plus the guard page
+pub const STACK_PAGES: usize = 4;
+
+// A kernel stack with an unmapped page below it, so that an overflow page faults instead of
+// silently running into whatever comes next
+pub struct KernelStack {
+    // The guard page, the stack itself starts one page above
+    virt: u32,
+    phys: *mut u8,
+}
+
+unsafe impl Send for KernelStack {}
+
+impl KernelStack {
+    pub fn new() -> Option<Self> {
+        let virt = virt_page_alloc::allocate(STACK_PAGES + 1)?;
+        let Some(phys) = pmm::allocate(STACK_PAGES) else {
+            virt_page_alloc::free(virt as *const u8, STACK_PAGES + 1);
+            return None;
+        };
+
+        for i in 0..STACK_PAGES {
+            let offset = (i as u32) * 4096;
+            vmm::map((phys as u32) + offset, virt + 4096 + offset, false, true, false, false);
+        }
+
+        Some(Self { virt, phys })
+    }
+
+    // The initial stack pointer, the stack grows down from here
+    pub fn top(&self) -> u32 {
+        self.virt + ((STACK_PAGES as u32) + 1) * 4096
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let mut batch = TlbBatch::new();
+        for i in 0..STACK_PAGES {
+            vmm::unmap_batched(self.virt + 4096 + (i as u32) * 4096, &mut batch);
+        }
+        batch.free_after(self.phys, STACK_PAGES);
+        // The virtual range can only be handed out again once no CPU has it cached anymore
+        batch.flush();
+
+        virt_page_alloc::free(self.virt as *const u8, STACK_PAGES + 1);
+    }
+}
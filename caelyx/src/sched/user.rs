@@ -0,0 +1,226 @@
+use alloc::boxed::Box;
+use core::ptr::null_mut;
+
+use crate::{
+    mm::{ pmm, tlb::TlbBatch, vmm::{ self, USER_START } },
+    sched::{ JoinHandle, MAX_THREADS, Priority, SCHEDULER, SchedError, spawn_thread },
+    sync::mutex::Mutex,
+    x86::gdt::{ GDT_USER_CODE, GDT_USER_DATA, RPL_USER },
+};
+
+// There's only the one page directory for now, so every user thread gets a slot of its own in the
+// user part of the address space. The code goes at the bottom, the stack at the top and whatever
+// gets mapped later in between
+const SLOT_SIZE: u32 = 32 * 1024 * 1024;
+const USER_STACK_PAGES: usize = 4;
+const MAX_MAPPINGS: usize = 16;
+
+// Interrupts on, bit 1 is reserved and always set
+const USER_EFLAGS: u32 = (1 << 9) | (1 << 1);
+
+static SLOTS: Mutex<[bool; MAX_THREADS]> = Mutex::named("user_slots", [false; MAX_THREADS]);
+
+// The ring 3 memory of a user thread, unmapped and freed along with its kernel stack
+pub(super) struct UserMemory {
+    slot: usize,
+    code_phys: *mut u8,
+    code_pages: usize,
+    stack_phys: *mut u8,
+    // (address, physical address, page count) of everything map_anonymous() handed out
+    mappings: [(u32, *mut u8, usize); MAX_MAPPINGS],
+    mapping_count: usize,
+    next_mapping: u32,
+}
+
+unsafe impl Send for UserMemory {}
+
+fn free_slot(slot: usize) {
+    SLOTS.lock()[slot] = false;
+}
+
+impl UserMemory {
+    fn new(code: &[u8]) -> Option<Self> {
+        let slot = {
+            let mut slots = SLOTS.lock();
+            let slot = slots.iter().position(|x| !x)?;
+            slots[slot] = true;
+            slot
+        };
+
+        let code_pages = code.len().div_ceil(4096).max(1);
+        let Some(code_phys) = pmm::allocate(code_pages) else {
+            free_slot(slot);
+            return None;
+        };
+        let Some(stack_phys) = pmm::allocate(USER_STACK_PAGES) else {
+            pmm::free(code_phys, code_pages);
+            free_slot(slot);
+            return None;
+        };
+
+        let mut memory = Self {
+            slot,
+            code_phys,
+            code_pages,
+            stack_phys,
+            mappings: [(0, null_mut(), 0); MAX_MAPPINGS],
+            mapping_count: 0,
+            next_mapping: 0,
+        };
+        // A page of space after the code, so that running off its end faults
+        memory.next_mapping = memory.entry() + ((code_pages as u32) + 1) * 4096;
+
+        for i in 0..code_pages {
+            let offset = (i as u32) * 4096;
+            let virt = memory.entry() + offset;
+            vmm::map((code_phys as u32) + offset, virt, true, true, false, false);
+        }
+        for i in 0..USER_STACK_PAGES {
+            let offset = (i as u32) * 4096;
+            let virt = memory.stack_bottom() + offset;
+            vmm::map((stack_phys as u32) + offset, virt, true, true, false, false);
+        }
+
+        // Whatever was in there before must not leak into ring 3
+        unsafe {
+            core::ptr::write_bytes(memory.entry() as *mut u8, 0, code_pages * 4096);
+            core::ptr::copy_nonoverlapping(code.as_ptr(), memory.entry() as *mut u8, code.len());
+            core::ptr::write_bytes(memory.stack_bottom() as *mut u8, 0, USER_STACK_PAGES * 4096);
+        }
+
+        Some(memory)
+    }
+
+    fn base(&self) -> u32 {
+        USER_START + (self.slot as u32) * SLOT_SIZE
+    }
+
+    // The code gets entered at its first byte
+    pub(super) fn entry(&self) -> u32 {
+        self.base()
+    }
+
+    pub(super) fn stack_top(&self) -> u32 {
+        self.base() + SLOT_SIZE
+    }
+
+    fn stack_bottom(&self) -> u32 {
+        self.stack_top() - (USER_STACK_PAGES as u32) * 4096
+    }
+
+    // Finds room for `pages` pages mapped to `phys`, leaving a page of space below the stack
+    fn reserve(&mut self, phys: *mut u8, pages: usize) -> Option<u32> {
+        if self.mapping_count == MAX_MAPPINGS {
+            return None;
+        }
+
+        let virt = self.next_mapping;
+        let end = virt.checked_add((pages as u32).checked_mul(4096)?)?;
+        if end > self.stack_bottom() - 4096 {
+            return None;
+        }
+
+        self.mappings[self.mapping_count] = (virt, phys, pages);
+        self.mapping_count += 1;
+        self.next_mapping = end;
+        Some(virt)
+    }
+}
+
+impl Drop for UserMemory {
+    fn drop(&mut self) {
+        let mut batch = TlbBatch::new();
+        for i in 0..self.code_pages {
+            vmm::unmap_batched(self.entry() + (i as u32) * 4096, &mut batch);
+        }
+        for i in 0..USER_STACK_PAGES {
+            vmm::unmap_batched(self.stack_bottom() + (i as u32) * 4096, &mut batch);
+        }
+        for &(virt, _, pages) in &self.mappings[..self.mapping_count] {
+            for i in 0..pages {
+                vmm::unmap_batched(virt + (i as u32) * 4096, &mut batch);
+            }
+        }
+        batch.free_after(self.code_phys, self.code_pages);
+        batch.free_after(self.stack_phys, USER_STACK_PAGES);
+        for &(_, phys, pages) in &self.mappings[..self.mapping_count] {
+            batch.free_after(phys, pages);
+        }
+        batch.flush();
+
+        free_slot(self.slot);
+    }
+}
+
+// Drops to ring 3 at `entry` with the stack at `stack_top`. Interrupts and faults from there come
+// back in on the kernel stack the TSS points at
+fn enter_user(entry: u32, stack_top: u32) -> ! {
+    unsafe {
+        core::arch::asm!(
+            // gs stops pointing at the per-CPU data here, so nothing may interrupt us until iret
+            "cli",
+            "mov ds, {data:x}",
+            "mov es, {data:x}",
+            "mov fs, {data:x}",
+            "mov gs, {data:x}",
+            "push {data:e}",
+            "push {stack:e}",
+            "push {eflags}",
+            "push {code}",
+            "push {entry:e}",
+            "iretd",
+            data = in(reg) (GDT_USER_DATA | RPL_USER) as u32,
+            stack = in(reg) stack_top,
+            entry = in(reg) entry,
+            eflags = const USER_EFLAGS,
+            code = const GDT_USER_CODE | RPL_USER,
+            options(noreturn)
+        );
+    }
+}
+
+// Where every user thread starts, still in the kernel
+fn user_entry() {
+    let (entry, stack_top) = {
+        let mut lock = SCHEDULER.lock();
+        let memory = lock.current_mut().user.as_ref().expect("User thread has no user memory");
+        (memory.entry(), memory.stack_top())
+    };
+
+    enter_user(entry, stack_top);
+}
+
+// Maps `pages` zeroed pages into the current thread's slot and returns where. None if it has no
+// room left or isn't a user thread
+pub fn map_anonymous(pages: usize) -> Option<u32> {
+    let phys = pmm::allocate(pages)?;
+    // Only the thread itself maps anything into its slot, so it stays reserved once we let go
+    let virt = {
+        let mut lock = SCHEDULER.lock();
+        lock.current_mut().user.as_mut().and_then(|x| x.reserve(phys, pages))
+    };
+    let Some(virt) = virt else {
+        pmm::free(phys, pages);
+        return None;
+    };
+
+    for i in 0..pages {
+        let offset = (i as u32) * 4096;
+        vmm::map((phys as u32) + offset, virt + offset, true, true, false, false);
+    }
+    unsafe {
+        core::ptr::write_bytes(virt as *mut u8, 0, pages * 4096);
+    }
+
+    Some(virt)
+}
+
+// Starts `code` in ring 3 on a thread of its own. A fault in there only ends that thread
+pub fn spawn_user(
+    name: &'static str,
+    priority: Priority,
+    code: &[u8]
+) -> Result<JoinHandle, SchedError> {
+    let memory = UserMemory::new(code).ok_or(SchedError::OutOfMemory)?;
+    spawn_thread(name, priority, Box::new(user_entry), Some(memory))
+}
@@ -0,0 +1,72 @@
+; A tiny ring 3 program that's built into the kernel and started at boot with the user-demo
+; feature. It goes through every syscall once, both through int 0x80 and sysenter, and then faults
+; on purpose, which should only take its own thread down. It gets copied to wherever spawn_user()
+; puts it, so everything in here has to be position independent
+
+; Have to match syscall/mod.rs
+SYSCALL_VECTOR equ 0x80
+SYS_EXIT equ 0
+SYS_WRITE equ 1
+SYS_GETPID equ 2
+SYS_SLEEP equ 3
+SYS_MMAP equ 4
+
+; Only ever copied out of here, never run in place
+section .rodata
+
+global user_demo_start
+global user_demo_end
+
+user_demo_start:
+    ; Syscalls leave everything but eax alone, so edx can hold on to where we ended up
+    call .base
+.base:
+    pop edx
+
+    ; write(1, hello, hello_len)
+    mov eax, SYS_WRITE
+    mov ebx, 1
+    lea esi, [edx + hello - .base]
+    mov edi, hello_len
+    int SYSCALL_VECTOR
+
+    ; getpid(), nothing to do with the result yet
+    mov eax, SYS_GETPID
+    int SYSCALL_VECTOR
+
+    ; The same through sysenter, which needs a CPU that has it. It returns to edx with the stack
+    ; in ecx, and edx comes back unchanged, so it still tells us where we are afterwards
+    mov eax, SYS_GETPID
+    mov ecx, esp
+    lea edx, [edx + .after_sysenter - .base]
+    sysenter
+.after_sysenter:
+    lea edx, [edx + .base - .after_sysenter]
+
+    ; sleep(100)
+    mov eax, SYS_SLEEP
+    mov ebx, 100
+    int SYSCALL_VECTOR
+
+    ; mmap(4096), then write to the page. Errors come back as -4095 to -1
+    mov eax, SYS_MMAP
+    mov ebx, 4096
+    int SYSCALL_VECTOR
+    cmp eax, -4096
+    ja .exit
+    mov dword [eax], 1
+
+    ; Nothing is mapped for ring 3 down there
+    mov eax, [0]
+
+.exit:
+    ; exit(1), only reached if something went wrong
+    mov eax, SYS_EXIT
+    mov ebx, 1
+    int SYSCALL_VECTOR
+
+hello:
+    db "Hello from ring 3", 10
+hello_len equ $ - hello
+
+user_demo_end:
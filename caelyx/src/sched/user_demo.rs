@@ -0,0 +1,17 @@
+use crate::{ sched::{ Priority, user::spawn_user }, warning };
+
+unsafe extern "C" {
+    static user_demo_start: u8;
+    static user_demo_end: u8;
+}
+
+// Starts the program from user_demo.asm, which makes every syscall once and then faults
+pub fn start() {
+    let start = &raw const user_demo_start;
+    let size = (&raw const user_demo_end as usize) - (start as usize);
+    let code = unsafe { core::slice::from_raw_parts(start, size) };
+
+    if let Err(err) = spawn_user("user_demo", Priority::Normal, code) {
+        warning!("Could not start the user demo: {err:?}");
+    }
+}
@@ -0,0 +1,77 @@
+use core::{ sync::atomic::{ AtomicU32, Ordering }, time::Duration };
+
+use crate::sync::{ sleep_mutex::SleepMutexGuard, waitqueue::WaitQueue };
+
+// Lets threads wait for a change to state protected by a SleepMutex. As usual wakeups may be
+// spurious, so wait in a loop or use wait_while()
+pub struct Condvar {
+    // Bumped by every notification, a waiter is done once it changed since it started waiting
+    sequence: AtomicU32,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            sequence: AtomicU32::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    // Unlocks the mutex, waits for a notification and locks the mutex again
+    pub fn wait<'a, T>(&self, guard: SleepMutexGuard<'a, T>) -> SleepMutexGuard<'a, T> {
+        let mutex = guard.mutex();
+
+        // Read before unlocking, so that a notification right after the unlock isn't missed
+        let sequence = self.sequence.load(Ordering::Acquire);
+        drop(guard);
+
+        self.waiters.wait_until(|| self.sequence.load(Ordering::Acquire) != sequence);
+        mutex.lock()
+    }
+
+    // Waits for as long as `condition` returns true
+    pub fn wait_while<'a, T>(
+        &self,
+        mut guard: SleepMutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool
+    ) -> SleepMutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+
+        guard
+    }
+
+    // Like wait(), but gives up after `timeout`. The bool is true if it timed out
+    pub fn wait_timeout<'a, T>(
+        &self,
+        guard: SleepMutexGuard<'a, T>,
+        timeout: Duration
+    ) -> (SleepMutexGuard<'a, T>, bool) {
+        let mutex = guard.mutex();
+        let sequence = self.sequence.load(Ordering::Acquire);
+        drop(guard);
+
+        let notified = self.waiters.wait_until_timeout(timeout, || {
+            self.sequence.load(Ordering::Acquire) != sequence
+        });
+        (mutex.lock(), !notified)
+    }
+
+    pub fn notify_one(&self) {
+        self.sequence.fetch_add(1, Ordering::AcqRel);
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.sequence.fetch_add(1, Ordering::AcqRel);
+        self.waiters.wake_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,316 @@
+use core::{
+    cell::UnsafeCell,
+    fmt::Arguments,
+    hint::spin_loop,
+    panic::Location,
+    ptr::null_mut,
+    sync::atomic::{ AtomicBool, AtomicPtr, AtomicU8, AtomicU32, Ordering },
+};
+
+use crate::{
+    drvs::{ e9, serial },
+    percpu,
+    sched,
+    sync::timer::tsc::{ cycles_to_ns, rdtsc },
+    x86::percpu,
+};
+
+// Lock debugging, built with the lock-debug feature. Every Mutex remembers who holds it so that
+// locking it twice from the same CPU panics instead of spinning forever, and named locks are
+// sorted into classes whose nesting order gets checked against every order seen before. Nothing
+// in here may take a Mutex, since it runs inside of Mutex::lock(). That's also why hold times are
+// measured with the TSC, the clock source may need a lock to be read
+
+const MAX_HELD_LOCKS: usize = 32;
+// Class indices have to fit into a u64 bitmask
+const MAX_CLASSES: usize = 64;
+// Serial output alone can take a few ms per line, so this is generous on purpose
+const HOLD_WARN_NS: u64 = 50_000_000;
+
+const NO_OWNER: u32 = u32::MAX;
+
+// Lives in every Mutex when lock debugging is on
+pub struct LockDebug {
+    name: Option<&'static str>,
+    // Index + 1 into CLASSES, 0 until the first time the lock is taken
+    class: AtomicU8,
+    owner_cpu: AtomicU32,
+    owner_thread: AtomicU32,
+    owner_location: AtomicPtr<Location<'static>>,
+}
+
+#[derive(Clone, Copy)]
+struct HeldLock {
+    lock: *const LockDebug,
+    class: Option<usize>,
+    location: &'static Location<'static>,
+    acquired_tsc: u64,
+}
+
+struct CpuLocks {
+    held: [Option<HeldLock>; MAX_HELD_LOCKS],
+    depth: usize,
+}
+
+struct Classes {
+    names: [Option<&'static str>; MAX_CLASSES],
+    count: usize,
+}
+
+struct ClassTable(UnsafeCell<Classes>);
+
+unsafe impl Sync for ClassTable {}
+
+percpu! {
+    // Only ever touched with interrupts disabled, which every Mutex holder has
+    static CPU_LOCKS: CpuLocks = CpuLocks {
+        held: [None; MAX_HELD_LOCKS],
+        depth: 0,
+    };
+}
+
+static CLASSES: ClassTable = ClassTable(
+    UnsafeCell::new(Classes {
+        names: [None; MAX_CLASSES],
+        count: 0,
+    })
+);
+// A raw spinlock for CLASSES, a Mutex would end up back in here
+static CLASSES_LOCKED: AtomicBool = AtomicBool::new(false);
+// Bit (a * MAX_CLASSES + b) is set once class b has been taken while holding class a
+static ORDER: [AtomicU32; (MAX_CLASSES * MAX_CLASSES) / 32] =
+    [const { AtomicU32::new(0) }; (MAX_CLASSES * MAX_CLASSES) / 32];
+// Same layout, marks inversions that were already reported
+static REPORTED: [AtomicU32; (MAX_CLASSES * MAX_CLASSES) / 32] =
+    [const { AtomicU32::new(0) }; (MAX_CLASSES * MAX_CLASSES) / 32];
+
+// The logger and most outputs take locks of their own, so reports only go to the ports that don't
+fn report(args: Arguments<'_>) {
+    e9::print_fmt(args);
+    serial::print_fmt(args);
+    e9::print_fmt(format_args!("\r\n"));
+    serial::print_fmt(format_args!("\r\n"));
+}
+
+fn cpu_id() -> u32 {
+    percpu::cpu_id() as u32
+}
+
+// Locks taken before the per-CPU areas are set up go untracked
+fn cpu_locks() -> Option<&'static mut CpuLocks> {
+    percpu::ready().then(|| unsafe { &mut *CPU_LOCKS.as_ptr() })
+}
+
+fn test_bit(bits: &[AtomicU32], idx: usize) -> bool {
+    (bits[idx / 32].load(Ordering::Relaxed) & (1 << (idx % 32))) != 0
+}
+
+// Returns whether the bit was set before
+fn set_bit(bits: &[AtomicU32], idx: usize) -> bool {
+    (bits[idx / 32].fetch_or(1 << (idx % 32), Ordering::Relaxed) & (1 << (idx % 32))) != 0
+}
+
+// Locks with the same name share a class, so that e.g. the locks of every instance of a driver
+// are checked as one
+fn class_of(name: &'static str) -> Option<usize> {
+    while
+        CLASSES_LOCKED.compare_exchange_weak(
+            false,
+            true,
+            Ordering::Acquire,
+            Ordering::Relaxed
+        ).is_err()
+    {
+        spin_loop();
+    }
+
+    let classes = unsafe { &mut *CLASSES.0.get() };
+    let found = classes.names[..classes.count].iter().position(|x| *x == Some(name));
+    let class = found.or_else(|| {
+        if classes.count == MAX_CLASSES {
+            return None;
+        }
+        classes.names[classes.count] = Some(name);
+        classes.count += 1;
+        Some(classes.count - 1)
+    });
+
+    CLASSES_LOCKED.store(false, Ordering::Release);
+    class
+}
+
+fn class_name(class: usize) -> &'static str {
+    unsafe { (*CLASSES.0.get()).names[class].unwrap_or("?") }
+}
+
+// Whether `to` has ever been taken while `from` was held, directly or through other classes
+fn order_reaches(from: usize, to: usize) -> bool {
+    let mut visited = 1u64 << from;
+    let mut pending = 1u64 << from;
+    while pending != 0 {
+        let class = pending.trailing_zeros() as usize;
+        pending &= !(1u64 << class);
+
+        for next in 0..MAX_CLASSES {
+            if !test_bit(&ORDER, class * MAX_CLASSES + next) {
+                continue;
+            }
+            if next == to {
+                return true;
+            }
+            if (visited & (1u64 << next)) == 0 {
+                visited |= 1u64 << next;
+                pending |= 1u64 << next;
+            }
+        }
+    }
+
+    false
+}
+
+impl LockDebug {
+    pub const fn new(name: Option<&'static str>) -> Self {
+        Self {
+            name,
+            class: AtomicU8::new(0),
+            owner_cpu: AtomicU32::new(NO_OWNER),
+            owner_thread: AtomicU32::new(0),
+            owner_location: AtomicPtr::new(null_mut()),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        self.name.unwrap_or("<unnamed>")
+    }
+
+    fn class(&self) -> Option<usize> {
+        let name = self.name?;
+        let class = self.class.load(Ordering::Relaxed);
+        if class != 0 {
+            return Some((class as usize) - 1);
+        }
+
+        let class = class_of(name)?;
+        self.class.store((class as u8) + 1, Ordering::Relaxed);
+        Some(class)
+    }
+
+    // Called before trying to take the lock, so that an inversion gets reported even if it ends
+    // up deadlocking right after
+    pub fn check_order(&self, location: &'static Location<'static>) {
+        let (Some(class), Some(locks)) = (self.class(), cpu_locks()) else {
+            return;
+        };
+
+        for held in locks.held[..locks.depth].iter().flatten() {
+            let Some(held_class) = held.class else {
+                continue;
+            };
+            if held_class == class {
+                continue;
+            }
+
+            if
+                order_reaches(class, held_class) &&
+                !set_bit(&REPORTED, held_class * MAX_CLASSES + class)
+            {
+                report(
+                    format_args!(
+                        "lockdep: lock order inversion: {} taken at {location} while holding {} \
+                         (taken at {}), but {} has been taken while holding {} before",
+                        class_name(class),
+                        class_name(held_class),
+                        held.location,
+                        class_name(held_class),
+                        class_name(class)
+                    )
+                );
+            }
+
+            set_bit(&ORDER, held_class * MAX_CLASSES + class);
+        }
+    }
+
+    // Called every time taking the lock fails. Mutexes keep interrupts disabled while they're
+    // held, so if the owner is this CPU it can never let go
+    pub fn check_deadlock(&self, location: &'static Location<'static>) {
+        let cpu = cpu_id();
+        if self.owner_cpu.load(Ordering::Relaxed) != cpu {
+            return;
+        }
+
+        let owner_location = self.owner_location.load(Ordering::Relaxed);
+        let owner_thread = self.owner_thread.load(Ordering::Relaxed);
+        report(
+            format_args!(
+                "lockdep: deadlock: {} taken at {location} is already held by CPU {cpu}, thread \
+                 {owner_thread:#x}",
+                self.name()
+            )
+        );
+
+        if owner_location.is_null() {
+            panic!("Deadlock on lock {} at {location}", self.name());
+        }
+        panic!(
+            "Deadlock on lock {} at {location}, already taken at {}",
+            self.name(),
+            unsafe { &*owner_location }
+        );
+    }
+
+    pub fn acquired(&self, location: &'static Location<'static>) {
+        self.owner_location.store(location as *const _ as *mut _, Ordering::Relaxed);
+        self.owner_thread.store(sched::current().to_usize() as u32, Ordering::Relaxed);
+        self.owner_cpu.store(cpu_id(), Ordering::Relaxed);
+
+        let Some(locks) = cpu_locks() else {
+            return;
+        };
+        if locks.depth == MAX_HELD_LOCKS {
+            report(format_args!("lockdep: more than {MAX_HELD_LOCKS} locks held, not tracking"));
+            return;
+        }
+
+        locks.held[locks.depth] = Some(HeldLock {
+            lock: self,
+            class: self.class(),
+            location,
+            acquired_tsc: rdtsc(),
+        });
+        locks.depth += 1;
+    }
+
+    // Called right before the lock is let go of
+    pub fn released(&self) {
+        self.owner_cpu.store(NO_OWNER, Ordering::Relaxed);
+        self.owner_location.store(null_mut(), Ordering::Relaxed);
+
+        let Some(locks) = cpu_locks() else {
+            return;
+        };
+        // Guards don't have to be dropped in the order they were created in
+        let Some(idx) = locks.held[..locks.depth]
+            .iter()
+            .rposition(|x| x.is_some_and(|x| core::ptr::eq(x.lock, self))) else {
+            return;
+        };
+        let held = locks.held[idx].take().unwrap();
+        locks.held.copy_within(idx + 1..locks.depth, idx);
+        locks.depth -= 1;
+        locks.held[locks.depth] = None;
+
+        // Comes out as 0 until the TSC is calibrated
+        let held_ns = cycles_to_ns(rdtsc().wrapping_sub(held.acquired_tsc));
+        if held_ns > HOLD_WARN_NS {
+            report(
+                format_args!(
+                    "lockdep: {} taken at {} was held for {}ms",
+                    self.name(),
+                    held.location,
+                    held_ns / 1_000_000
+                )
+            );
+        }
+    }
+}
@@ -1,2 +1,12 @@
+pub mod condvar;
+#[cfg(feature = "lock-debug")]
+pub mod lockdep;
 pub mod mutex;
+pub mod once;
+pub mod rwlock;
+pub mod semaphore;
+pub mod sleep_mutex;
+pub mod ticket;
 pub mod timer;
+pub mod waitqueue;
+pub mod workqueue;
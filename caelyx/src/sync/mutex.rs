@@ -6,23 +6,68 @@ use core::{
     sync::atomic::{AtomicUsize, Ordering},
 };
 
-use crate::x86::idt::interrupt_control::{
-    disable_interrupts, enable_interrupts, interrupts_enabled,
-};
+#[cfg(feature = "lock-debug")]
+use crate::sync::lockdep::LockDebug;
+use crate::{ mm::tlb, x86::idt::interrupt_control::IrqGuard };
 
 pub struct NegativeSendAndSync;
 
 impl !Send for NegativeSendAndSync {}
 impl !Sync for NegativeSendAndSync {}
 
-pub struct MutexGuard<'a, T> {
+// The bare lock underneath a Mutex, without interrupt handling or the value it protects
+pub trait RawLock {
+    const INIT: Self;
+
+    fn try_lock(&self) -> bool;
+
+    // Spins until the lock is taken, calling `contended` on every failed attempt
+    fn lock(&self, contended: impl FnMut());
+
+    fn unlock(&self);
+}
+
+// Whoever gets to the lock first takes it, which is cheap but can starve a CPU under contention
+pub struct SpinLock {
+    locked: AtomicUsize,
+}
+
+impl RawLock for SpinLock {
+    const INIT: Self = Self {
+        locked: AtomicUsize::new(0),
+    };
+
+    fn try_lock(&self) -> bool {
+        self.locked.compare_exchange(0, 1, Ordering::AcqRel, Ordering::Acquire).is_ok()
+    }
+
+    fn lock(&self, mut contended: impl FnMut()) {
+        while self
+            .locked
+            .compare_exchange_weak(0, 1, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            contended();
+            spin_loop();
+        }
+    }
+
+    fn unlock(&self) {
+        self.locked.store(0, Ordering::Release);
+    }
+}
+
+pub struct MutexGuard<'a, T, R: RawLock = SpinLock> {
     val: &'a mut T,
-    locked: &'a AtomicUsize,
+    raw: &'a R,
     traits: PhantomData<NegativeSendAndSync>,
-    initial_interrupts: bool,
+    // Dropped after the lock is let go of
+    _irq: IrqGuard,
+    #[cfg(feature = "lock-debug")]
+    debug: &'a LockDebug,
 }
 
-impl<'a, T> Deref for MutexGuard<'a, T> {
+impl<'a, T, R: RawLock> Deref for MutexGuard<'a, T, R> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -30,63 +75,107 @@ impl<'a, T> Deref for MutexGuard<'a, T> {
     }
 }
 
-impl<'a, T> DerefMut for MutexGuard<'a, T> {
+impl<'a, T, R: RawLock> DerefMut for MutexGuard<'a, T, R> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         self.val
     }
 }
 
-impl<'a, T> Drop for MutexGuard<'a, T> {
+impl<'a, T, R: RawLock> Drop for MutexGuard<'a, T, R> {
     fn drop(&mut self) {
-        self.locked.store(0, Ordering::Release);
-        if self.initial_interrupts {
-            enable_interrupts();
-        }
+        #[cfg(feature = "lock-debug")]
+        self.debug.released();
+        self.raw.unlock();
     }
 }
 
-pub struct Mutex<T> {
+// Keeps interrupts disabled while it's held, so it can be shared with interrupt handlers. The
+// raw lock decides how waiters line up, see TicketMutex for the fair one
+pub struct Mutex<T, R: RawLock = SpinLock> {
     val: UnsafeCell<T>,
-    locked: AtomicUsize,
+    raw: R,
     traits: PhantomData<NegativeSendAndSync>,
+    #[cfg(feature = "lock-debug")]
+    debug: LockDebug,
 }
 
-impl<T> Mutex<T> {
-    pub const fn new(val: T) -> Mutex<T> {
+impl<T, R: RawLock> Mutex<T, R> {
+    pub const fn new(val: T) -> Mutex<T, R> {
+        Mutex {
+            val: UnsafeCell::new(val),
+            raw: R::INIT,
+            traits: PhantomData,
+            #[cfg(feature = "lock-debug")]
+            debug: LockDebug::new(None),
+        }
+    }
+
+    // The name only matters with lock debugging on, where locks with the same name form a class
+    // whose nesting order gets checked
+    #[allow(unused_variables)]
+    pub const fn named(name: &'static str, val: T) -> Mutex<T, R> {
         Mutex {
             val: UnsafeCell::new(val),
-            locked: AtomicUsize::new(0),
+            raw: R::INIT,
             traits: PhantomData,
+            #[cfg(feature = "lock-debug")]
+            debug: LockDebug::new(Some(name)),
         }
     }
 
-    pub fn lock(&self) -> MutexGuard<'_, T> {
-        let initial = interrupts_enabled();
-        if initial {
-            disable_interrupts();
+    pub(super) fn raw(&self) -> &R {
+        &self.raw
+    }
+
+    fn guard(&self, irq: IrqGuard) -> MutexGuard<'_, T, R> {
+        MutexGuard {
+            val: unsafe { &mut *self.val.get() },
+            raw: &self.raw,
+            traits: PhantomData,
+            _irq: irq,
+            #[cfg(feature = "lock-debug")]
+            debug: &self.debug,
         }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T, R> {
+        let irq = IrqGuard::new();
+
+        #[cfg(feature = "lock-debug")]
+        let location = core::panic::Location::caller();
+        #[cfg(feature = "lock-debug")]
+        self.debug.check_order(location);
+
+        // Interrupts are off while we spin, so a shootdown from whoever holds the lock can only be
+        // answered from here
+        self.raw.lock(|| {
+            #[cfg(feature = "lock-debug")]
+            self.debug.check_deadlock(location);
+            tlb::handle_pending();
+        });
+
+        #[cfg(feature = "lock-debug")]
+        self.debug.acquired(location);
 
-        loop {
-            unsafe {
-                if self
-                    .locked
-                    .compare_exchange_weak(0, 1, Ordering::AcqRel, Ordering::Acquire)
-                    .is_err()
-                {
-                    spin_loop();
-                    continue;
-                }
-
-                return MutexGuard {
-                    val: &mut *self.val.get(),
-                    locked: &self.locked,
-                    traits: PhantomData,
-                    initial_interrupts: initial,
-                };
-            }
+        self.guard(irq)
+    }
+
+    // Never spins, so it can't deadlock and skips the lock order checks
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T, R>> {
+        let irq = IrqGuard::new();
+
+        if !self.raw.try_lock() {
+            return None;
         }
+
+        #[cfg(feature = "lock-debug")]
+        self.debug.acquired(core::panic::Location::caller());
+
+        Some(self.guard(irq))
     }
 }
 
-unsafe impl<T: Send> Send for Mutex<T> {}
-unsafe impl<T: Sync> Sync for Mutex<T> {}
+unsafe impl<T: Send, R: RawLock + Send> Send for Mutex<T, R> {}
+unsafe impl<T: Send, R: RawLock + Sync> Sync for Mutex<T, R> {}
@@ -0,0 +1,126 @@
+use core::{
+    cell::{ Cell, UnsafeCell },
+    hint::spin_loop,
+    mem::MaybeUninit,
+    ops::Deref,
+    sync::atomic::{ AtomicU8, Ordering },
+};
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+// A value that gets written once and can be read without locking from then on. Replaces the
+// Mutex<IsItUninit<T>> pattern for globals that are set up during init and never change. Don't
+// initialize it from an interrupt handler that can interrupt another initializer, that spins
+// forever
+pub struct Once<T> {
+    state: AtomicU8,
+    val: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(INCOMPLETE),
+            val: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    // Runs `init` if nobody did yet, otherwise waits for whoever is running it
+    pub fn call_once(&self, init: impl FnOnce() -> T) -> &T {
+        if
+            self.state
+                .compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+                .is_ok()
+        {
+            unsafe {
+                (*self.val.get()).write(init());
+            }
+            self.state.store(COMPLETE, Ordering::Release);
+        }
+
+        self.wait()
+    }
+
+    // Returns the value back if the Once was already initialized
+    pub fn set(&self, val: T) -> Result<(), T> {
+        let mut val = Some(val);
+        self.call_once(|| val.take().unwrap());
+        val.map_or(Ok(()), Err)
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        if self.is_completed() {
+            Some(unsafe { (*self.val.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    // Spins until the value has been initialized
+    pub fn wait(&self) -> &T {
+        loop {
+            if let Some(val) = self.get() {
+                return val;
+            }
+            spin_loop();
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
+
+impl<T> Default for Once<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            unsafe {
+                self.val.get_mut().assume_init_drop();
+            }
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for Once<T> {}
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+
+// A value that is initialized on first use
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    init: Cell<Option<F>>,
+}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Self {
+            once: Once::new(),
+            init: Cell::new(Some(init)),
+        }
+    }
+
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(|| {
+            let init = this.init.take().expect("Lazy initializer panicked before");
+            init()
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+
+// `init` is only ever taken by the thread that wins the Once
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
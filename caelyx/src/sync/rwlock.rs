@@ -0,0 +1,152 @@
+use core::{
+    cell::UnsafeCell,
+    hint::spin_loop,
+    marker::PhantomData,
+    ops::{ Deref, DerefMut },
+    sync::atomic::{ AtomicUsize, Ordering },
+};
+
+use crate::{ sync::mutex::NegativeSendAndSync, x86::idt::interrupt_control::IrqGuard };
+
+// Bit 0 is set while a writer holds the lock, bit 1 while one is waiting for it, which keeps new
+// readers out so that writers can't be starved. The rest counts the readers
+const WRITER: usize = 1 << 0;
+const WRITER_WAITING: usize = 1 << 1;
+const READER: usize = 1 << 2;
+
+// Like Mutex, but any number of readers can hold the lock at once. Interrupts stay disabled while
+// a guard is held, so it can be used from interrupt handlers
+pub struct RwSpinLock<T> {
+    val: UnsafeCell<T>,
+    state: AtomicUsize,
+    traits: PhantomData<NegativeSendAndSync>,
+}
+
+pub struct RwSpinLockReadGuard<'a, T> {
+    lock: &'a RwSpinLock<T>,
+    traits: PhantomData<NegativeSendAndSync>,
+    _irq: IrqGuard,
+}
+
+pub struct RwSpinLockWriteGuard<'a, T> {
+    lock: &'a RwSpinLock<T>,
+    traits: PhantomData<NegativeSendAndSync>,
+    _irq: IrqGuard,
+}
+
+impl<T> RwSpinLock<T> {
+    pub const fn new(val: T) -> Self {
+        Self {
+            val: UnsafeCell::new(val),
+            state: AtomicUsize::new(0),
+            traits: PhantomData,
+        }
+    }
+
+    fn try_acquire_read(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        (state & (WRITER | WRITER_WAITING)) == 0 &&
+            self.state
+                .compare_exchange_weak(state, state + READER, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    fn try_acquire_write(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        (state & !WRITER_WAITING) == 0 &&
+            self.state
+                .compare_exchange_weak(state, WRITER, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    pub fn read(&self) -> RwSpinLockReadGuard<'_, T> {
+        let irq = IrqGuard::new();
+        while !self.try_acquire_read() {
+            spin_loop();
+        }
+
+        RwSpinLockReadGuard {
+            lock: self,
+            traits: PhantomData,
+            _irq: irq,
+        }
+    }
+
+    pub fn write(&self) -> RwSpinLockWriteGuard<'_, T> {
+        let irq = IrqGuard::new();
+        while !self.try_acquire_write() {
+            self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            spin_loop();
+        }
+
+        RwSpinLockWriteGuard {
+            lock: self,
+            traits: PhantomData,
+            _irq: irq,
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwSpinLockReadGuard<'_, T>> {
+        let irq = IrqGuard::new();
+        if !self.try_acquire_read() {
+            return None;
+        }
+
+        Some(RwSpinLockReadGuard {
+            lock: self,
+            traits: PhantomData,
+            _irq: irq,
+        })
+    }
+
+    pub fn try_write(&self) -> Option<RwSpinLockWriteGuard<'_, T>> {
+        let irq = IrqGuard::new();
+        if !self.try_acquire_write() {
+            return None;
+        }
+
+        Some(RwSpinLockWriteGuard {
+            lock: self,
+            traits: PhantomData,
+            _irq: irq,
+        })
+    }
+}
+
+impl<T> Deref for RwSpinLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.val.get() }
+    }
+}
+
+impl<T> Drop for RwSpinLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(READER, Ordering::Release);
+    }
+}
+
+impl<T> Deref for RwSpinLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.val.get() }
+    }
+}
+
+impl<T> DerefMut for RwSpinLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.val.get() }
+    }
+}
+
+impl<T> Drop for RwSpinLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        // Also clears WRITER_WAITING, any other waiting writer sets it again on its next attempt
+        self.lock.state.store(0, Ordering::Release);
+    }
+}
+
+unsafe impl<T: Send> Send for RwSpinLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwSpinLock<T> {}
@@ -0,0 +1,47 @@
+use core::{ sync::atomic::{ AtomicUsize, Ordering }, time::Duration };
+
+use crate::sync::waitqueue::WaitQueue;
+
+// A counting semaphore. Releasing is safe from interrupt context, acquiring blocks the thread
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Self {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |x| x.checked_sub(1))
+            .is_ok()
+    }
+
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    // Returns whether the semaphore was acquired before `timeout` passed
+    pub fn acquire_timeout(&self, timeout: Duration) -> bool {
+        self.waiters.wait_until_timeout(timeout, || self.try_acquire())
+    }
+
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    // Drops whatever count has built up
+    pub fn reset(&self) {
+        self.count.store(0, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,82 @@
+use core::{
+    cell::UnsafeCell,
+    ops::{ Deref, DerefMut },
+    sync::atomic::{ AtomicBool, Ordering },
+};
+
+use crate::sync::waitqueue::WaitQueue;
+
+// A lock that puts the thread to sleep while it waits instead of spinning with interrupts
+// disabled. Meant for longer critical sections in thread context, interrupt handlers have to stick
+// to sync::mutex::Mutex
+pub struct SleepMutex<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    val: UnsafeCell<T>,
+}
+
+pub struct SleepMutexGuard<'a, T> {
+    mutex: &'a SleepMutex<T>,
+}
+
+impl<T> SleepMutex<T> {
+    pub const fn new(val: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            val: UnsafeCell::new(val),
+        }
+    }
+
+    fn try_acquire(&self) -> bool {
+        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    pub fn lock(&self) -> SleepMutexGuard<'_, T> {
+        self.waiters.wait_until(|| self.try_acquire());
+        SleepMutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<SleepMutexGuard<'_, T>> {
+        self.try_acquire().then_some(SleepMutexGuard { mutex: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+        self.waiters.wake_one();
+    }
+}
+
+impl<'a, T> SleepMutexGuard<'a, T> {
+    // The mutex the guard belongs to, for Condvar to unlock and relock it
+    pub(super) fn mutex(&self) -> &'a SleepMutex<T> {
+        self.mutex
+    }
+}
+
+impl<T> Deref for SleepMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.mutex.val.get() }
+    }
+}
+
+impl<T> DerefMut for SleepMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.mutex.val.get() }
+    }
+}
+
+impl<T> Drop for SleepMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+unsafe impl<T: Send> Send for SleepMutex<T> {}
+unsafe impl<T: Send> Sync for SleepMutex<T> {}
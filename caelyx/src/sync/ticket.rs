@@ -0,0 +1,84 @@
+use core::{ hint::spin_loop, sync::atomic::{ AtomicU32, Ordering } };
+
+use crate::sync::mutex::{ Mutex, RawLock };
+
+// A Mutex that hands the lock out in the order it was asked for, so no CPU can be starved by the
+// others. Worth it for locks that see a lot of contention
+pub type TicketMutex<T> = Mutex<T, TicketLock>;
+
+#[derive(Clone, Copy, Debug)]
+pub struct LockStats {
+    // How often the lock was taken
+    pub acquired: u32,
+    // How often that meant waiting for someone else first
+    pub contended: u32,
+}
+
+// Every locker draws a ticket and waits until it's being served
+pub struct TicketLock {
+    next_ticket: AtomicU32,
+    now_serving: AtomicU32,
+    acquired: AtomicU32,
+    contended: AtomicU32,
+}
+
+impl TicketLock {
+    pub fn stats(&self) -> LockStats {
+        LockStats {
+            acquired: self.acquired.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl RawLock for TicketLock {
+    const INIT: Self = Self {
+        next_ticket: AtomicU32::new(0),
+        now_serving: AtomicU32::new(0),
+        acquired: AtomicU32::new(0),
+        contended: AtomicU32::new(0),
+    };
+
+    // Only draws a ticket if it would be served right away
+    fn try_lock(&self) -> bool {
+        let serving = self.now_serving.load(Ordering::Relaxed);
+        let taken = self.next_ticket
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed
+            )
+            .is_ok();
+        if taken {
+            self.acquired.fetch_add(1, Ordering::Relaxed);
+        }
+
+        taken
+    }
+
+    fn lock(&self, mut contended: impl FnMut()) {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        if self.now_serving.load(Ordering::Acquire) != ticket {
+            self.contended.fetch_add(1, Ordering::Relaxed);
+            while self.now_serving.load(Ordering::Acquire) != ticket {
+                contended();
+                spin_loop();
+            }
+        }
+
+        self.acquired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn unlock(&self) {
+        // Only the holder ever writes this, so there's no need for an atomic add
+        let serving = self.now_serving.load(Ordering::Relaxed);
+        self.now_serving.store(serving.wrapping_add(1), Ordering::Release);
+    }
+}
+
+impl<T> Mutex<T, TicketLock> {
+    pub fn stats(&self) -> LockStats {
+        self.raw().stats()
+    }
+}
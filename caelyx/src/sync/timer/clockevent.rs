@@ -0,0 +1,118 @@
+use core::{ sync::atomic::{ AtomicUsize, Ordering }, time::Duration };
+
+use crate::{
+    sync::{ mutex::Mutex, timer::{ hpet::HpetTimerError, monotonic_ns, tick } },
+    warning,
+    x86::irq::IrqError,
+};
+
+const MAX_CLOCK_EVENT_DEVICES: usize = 8;
+
+pub type EventHandler = fn();
+
+#[derive(Debug)]
+pub enum ClockEventError {
+    NoDevice,
+    Unsupported,
+    CalibrationFailed,
+    Hpet(HpetTimerError),
+    Irq(IrqError),
+}
+
+// A timer that raises an interrupt after a given time, every time it fires it calls event().
+// Ratings work like for clock sources
+pub trait ClockEventDevice: Sync {
+    fn name(&self) -> &'static str;
+
+    fn rating(&self) -> u32;
+
+    fn periodic_capable(&self) -> bool;
+
+    fn one_shot_capable(&self) -> bool;
+
+    // Whether every CPU has its own instance of the device, so that each can get its own tick
+    fn per_cpu(&self) -> bool {
+        false
+    }
+
+    // Longest delay a single one-shot can cover, longer ones fire early
+    fn max_delta(&self) -> Duration;
+
+    // Claims the interrupt and whatever else is needed. Runs once, when the device gets selected
+    fn setup(&self) -> Result<(), ClockEventError>;
+
+    fn start_periodic(&self, period: Duration) -> Result<(), ClockEventError>;
+
+    fn start_one_shot(&self, dur: Duration) -> Result<(), ClockEventError>;
+
+    // Fires once timer::monotonic_ns() reaches `deadline_ns`
+    fn program_deadline(&self, deadline_ns: u64) -> Result<(), ClockEventError> {
+        self.start_one_shot(Duration::from_nanos(deadline_ns.saturating_sub(monotonic_ns())))
+    }
+
+    fn stop(&self);
+}
+
+static DEVICES: Mutex<[Option<&'static dyn ClockEventDevice>; MAX_CLOCK_EVENT_DEVICES]> =
+    Mutex::new([None; MAX_CLOCK_EVENT_DEVICES]);
+static CURRENT: Mutex<Option<&'static dyn ClockEventDevice>> = Mutex::new(None);
+// 0 runs the kernel tick
+static EVENT_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+pub fn register(device: &'static dyn ClockEventDevice) {
+    let mut lock = DEVICES.lock();
+    let Some(slot) = lock.iter_mut().find(|x| x.is_none()) else {
+        drop(lock);
+        warning!("No room to register clock event device {}", device.name());
+        return;
+    };
+    *slot = Some(device);
+}
+
+// Sets up the highest rated device that can do what we need, moving on to the next one if that
+// fails. The device stays selected for good
+pub fn select(periodic: bool) -> Result<&'static dyn ClockEventDevice, ClockEventError> {
+    if let Some(device) = *CURRENT.lock() {
+        return Ok(device);
+    }
+
+    let mut devices = *DEVICES.lock();
+    devices.sort_unstable_by_key(|x| core::cmp::Reverse(x.map_or(0, |x| x.rating())));
+
+    for device in devices.into_iter().flatten() {
+        let capable = if periodic { device.periodic_capable() } else { device.one_shot_capable() };
+        if !capable {
+            continue;
+        }
+
+        match device.setup() {
+            Ok(()) => {
+                *CURRENT.lock() = Some(device);
+                return Ok(device);
+            }
+            Err(err) => warning!("Could not set up clock event device {}: {err:?}", device.name()),
+        }
+    }
+
+    Err(ClockEventError::NoDevice)
+}
+
+pub fn current() -> Option<&'static dyn ClockEventDevice> {
+    *CURRENT.lock()
+}
+
+// Replaces what runs when the selected device fires, which is the kernel tick by default
+pub fn set_event_handler(handler: EventHandler) {
+    EVENT_HANDLER.store(handler as usize, Ordering::Release);
+}
+
+// Called from the interrupt handler of the device
+pub(super) fn event() {
+    let handler = EVENT_HANDLER.load(Ordering::Acquire);
+    if handler == 0 {
+        tick::tick();
+    } else {
+        let handler: EventHandler = unsafe { core::mem::transmute(handler) };
+        handler();
+    }
+}
@@ -0,0 +1,114 @@
+use crate::{ info, sync::{ mutex::Mutex, rwlock::RwSpinLock }, warning };
+
+const MAX_CLOCK_SOURCES: usize = 8;
+
+// Rough guide for ratings, higher is better: 1 only counts ticks, 100 is usable but drifts or
+// is slow to read, 200 is a good hardware counter and 300 is the best we can get
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+
+    fn rating(&self) -> u32;
+
+    // Counts per second
+    fn frequency(&self) -> u64;
+
+    // A free-running counter that only ever goes up. Has to be safe to call from interrupt context
+    fn read(&self) -> u64;
+
+    // Sources that are driven by interrupts themselves can't be used to busy-wait with
+    // interrupts disabled
+    fn needs_interrupts(&self) -> bool {
+        false
+    }
+
+    fn cycles_to_ns(&self, cycles: u64) -> u64 {
+        let hz = self.frequency();
+        if hz == 0 {
+            return 0;
+        }
+
+        // Split into whole seconds and the rest so that the multiplication can't overflow
+        (cycles / hz) * 1_000_000_000 + ((cycles % hz) * 1_000_000_000) / hz
+    }
+}
+
+struct CurrentSource {
+    source: &'static dyn ClockSource,
+    // The counter value and the time when the source was selected, so that the clock carries on
+    // from where the previous source left it
+    base_cycles: u64,
+    base_ns: u64,
+}
+
+impl CurrentSource {
+    fn now_ns(&self) -> u64 {
+        let cycles = self.source.read().wrapping_sub(self.base_cycles);
+        self.base_ns + self.source.cycles_to_ns(cycles)
+    }
+}
+
+static SOURCES: Mutex<[Option<&'static dyn ClockSource>; MAX_CLOCK_SOURCES]> = Mutex::new(
+    [None; MAX_CLOCK_SOURCES]
+);
+// Read on every clock read, written only when switching sources. The lock also disables
+// interrupts, so interrupt handlers can't deadlock on it. Nothing may log while holding it for
+// writing though, since the logger reads the clock
+static CURRENT: RwSpinLock<Option<CurrentSource>> = RwSpinLock::new(None);
+
+// Adds a clock source and switches to it if it's rated higher than the current one
+pub fn register(source: &'static dyn ClockSource) {
+    let mut lock = SOURCES.lock();
+    let Some(slot) = lock.iter_mut().find(|x| x.is_none()) else {
+        drop(lock);
+        warning!("No room to register clock source {}", source.name());
+        return;
+    };
+    *slot = Some(source);
+    drop(lock);
+
+    select();
+}
+
+// Switches to the highest rated clock source
+pub fn select() {
+    let best = SOURCES.lock()
+        .iter()
+        .flatten()
+        .copied()
+        .max_by_key(|x| x.rating());
+    let Some(best) = best else {
+        return;
+    };
+
+    let mut lock = CURRENT.write();
+    if lock.as_ref().is_some_and(|x| core::ptr::addr_eq(x.source, best)) {
+        return;
+    }
+
+    let base_ns = lock.as_ref().map_or(0, |x| x.now_ns());
+    *lock = Some(CurrentSource {
+        source: best,
+        base_cycles: best.read(),
+        base_ns,
+    });
+    drop(lock);
+
+    info!(
+        "Clock source is now {} ({}.{:03}MHz, rating {})",
+        best.name(),
+        best.frequency() / 1_000_000,
+        (best.frequency() / 1000) % 1000,
+        best.rating()
+    );
+}
+
+pub fn current() -> Option<&'static dyn ClockSource> {
+    CURRENT.read().as_ref().map(|x| x.source)
+}
+
+// Nanoseconds since the first clock source was registered. 0 until then
+pub fn now_ns() -> u64 {
+    CURRENT.read()
+        .as_ref()
+        .map_or(0, |x| x.now_ns())
+}
@@ -1,17 +1,95 @@
-use core::{ time::Duration, arch::asm };
+use alloc::vec::Vec;
+use core::{
+    arch::asm,
+    sync::atomic::{ AtomicBool, AtomicU32, AtomicUsize, Ordering },
+    time::Duration,
+};
 
 use crate::{
     debug,
     misc::{ acpi::{ AcpiGAS, get_hpet_table }, isituninit::IsItUninit },
     mm::{ virt_page_alloc, vmm },
-    sync::mutex::Mutex,
+    sync::{
+        mutex::Mutex,
+        timer::{
+            clockevent::{ self, ClockEventDevice, ClockEventError },
+            clocksource::{ self, ClockSource },
+            tick,
+        },
+    },
     trace,
+    x86::{ irq::{ self, IrqHandler, IrqRoute }, lapic },
 };
 use uacpi::uacpi_table;
 
+const REG_CAPABILITIES: usize = 0x00;
+const REG_CONFIG: usize = 0x10;
+const REG_MAIN_COUNTER: usize = 0xf0;
+
+// COUNT_SIZE_CAP: the main counter is 64 bits wide instead of 32
+const CAPABILITIES_64BIT_COUNTER: u64 = 1 << 13;
+// The spec caps the counter period at 100ns
+const MAX_COUNTER_PERIOD_FS: u64 = 100_000_000;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+
+// Every comparator has a block of registers at 0x100 + 0x20 * N
+const fn reg_timer_config(idx: usize) -> usize {
+    0x100 + 0x20 * idx
+}
+
+const fn reg_timer_comparator(idx: usize) -> usize {
+    0x108 + 0x20 * idx
+}
+
+const fn reg_timer_fsb_route(idx: usize) -> usize {
+    0x110 + 0x20 * idx
+}
+
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_64BIT_CAPABLE: u64 = 1 << 5;
+// Lets the next comparator write set the accumulator of a periodic timer
+const TIMER_VAL_SET: u64 = 1 << 6;
+const TIMER_32BIT_MODE: u64 = 1 << 8;
+const TIMER_INT_ROUTE_SHIFT: u64 = 9;
+const TIMER_INT_ROUTE_MASK: u64 = 0b11111 << TIMER_INT_ROUTE_SHIFT;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+const TIMER_FSB_CAPABLE: u64 = 1 << 15;
+
 #[derive(Debug)]
 pub enum HpetTimerError {
     HpetAcpiSdtNotFound,
+    InvalidCounterPeriod(u64),
+    NoFreeComparator,
+    NotPeriodicCapable,
+    NoUsableRoute,
+    InvalidComparator,
+    Irq(irq::IrqError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetRoute {
+    // Interrupts are delivered as MSIs straight to the LAPIC
+    Fsb,
+    IoApic {
+        gsi: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HpetComparator {
+    pub index: usize,
+    pub periodic_capable: bool,
+    pub wide: bool,
+    pub fsb_capable: bool,
+    // Bit N is set if the comparator can be routed to IOAPIC input N
+    pub route_capabilities: u32,
+    allocated: bool,
+    vector: Option<u8>,
+    route: Option<HpetRoute>,
 }
 
 #[repr(C, packed)]
@@ -26,6 +104,8 @@ pub struct HpetSdt {
 pub struct HpetTimer {
     address: usize,
     counter_period: u64,
+    wide_counter: bool,
+    comparators: Vec<HpetComparator>,
 }
 
 impl HpetTimer {
@@ -55,8 +135,6 @@ impl HpetTimer {
 
         assert!(hpet_address <= (u32::MAX as u64), "HPET address > 4GB");
 
-        let counter_period: u64;
-
         let virt_pages = virt_page_alloc
             ::allocate(1)
             .expect("Could not allocate virtual page to map HPET MMIO region");
@@ -66,11 +144,23 @@ impl HpetTimer {
 
         let hpet_address = virt_pages;
 
-        unsafe {
-            counter_period = core::ptr::read_volatile(hpet_address as *const u64) >> 32;
+        let mut hpet = HpetTimer {
+            counter_period: 0,
+            address: hpet_address as usize,
+            wide_counter: false,
+            comparators: Vec::new(),
+        };
+
+        // The upper half of the capabilities register holds the counter period in femtoseconds
+        let capabilities = hpet.read(REG_CAPABILITIES);
+        hpet.counter_period = capabilities >> 32;
+        hpet.wide_counter = (capabilities & CAPABILITIES_64BIT_COUNTER) != 0;
+
+        if hpet.counter_period == 0 || hpet.counter_period > MAX_COUNTER_PERIOD_FS {
+            return Err(HpetTimerError::InvalidCounterPeriod(hpet.counter_period));
         }
 
-        trace!("HPET speed: {counter_period} femtoseconds/tick");
+        trace!("HPET speed: {} femtoseconds/tick", hpet.counter_period);
 
         /*
         General Configuration Register
@@ -80,49 +170,469 @@ impl HpetTimer {
         1 - main counter is running, timer interrupts are allowed if enabled
         */
 
+        hpet.write(REG_CONFIG, hpet.read(REG_CONFIG) & !CONFIG_ENABLE); // disable counter
+        hpet.write(REG_MAIN_COUNTER, 0); // clear counter
+        hpet.write(REG_CONFIG, hpet.read(REG_CONFIG) | CONFIG_ENABLE); // enable counter
+
+        trace!("Cleared HPET counter");
+
+        // Bits 8-12 hold the index of the last comparator
+        let comparator_count = (((capabilities >> 8) & 0b11111) + 1) as usize;
+        for index in 0..comparator_count {
+            let config = hpet.read(reg_timer_config(index));
+
+            // Leave every comparator disabled until someone asks for it
+            hpet.write(
+                reg_timer_config(index),
+                config & !(TIMER_INT_ENABLE | TIMER_PERIODIC | TIMER_FSB_ENABLE)
+            );
+
+            let comparator = HpetComparator {
+                index,
+                periodic_capable: (config & TIMER_PERIODIC_CAPABLE) != 0,
+                wide: (config & TIMER_64BIT_CAPABLE) != 0,
+                fsb_capable: (config & TIMER_FSB_CAPABLE) != 0,
+                route_capabilities: (config >> 32) as u32,
+                allocated: false,
+                vector: None,
+                route: None,
+            };
+
+            trace!(
+                "HPET comparator {index}: {}-bit{}{}, IOAPIC routes {:#010x}",
+                if comparator.wide { 64 } else { 32 },
+                if comparator.periodic_capable { ", periodic" } else { "" },
+                if comparator.fsb_capable { ", FSB" } else { "" },
+                comparator.route_capabilities
+            );
+
+            hpet.comparators.push(comparator);
+        }
+
+        debug!(
+            "Initialized HPET at {hpet_address:#08X} ({}-bit counter, {} comparators)",
+            if hpet.wide_counter { 64 } else { 32 },
+            comparator_count
+        );
+        Ok(hpet)
+    }
+
+    fn read(&self, reg: usize) -> u64 {
+        unsafe { core::ptr::read_volatile((self.address + reg) as *const u64) }
+    }
+
+    fn write(&self, reg: usize, val: u64) {
         unsafe {
-            core::ptr::write_volatile((hpet_address as *mut u8).add(0x10) as *mut u64, 0); // disable counter
-            core::ptr::write_volatile((hpet_address as *mut u8).add(0xf0) as *mut u64, 0); // clear counter
-            core::ptr::write_volatile((hpet_address as *mut u8).add(0x10) as *mut u64, 1); // enable counter
+            core::ptr::write_volatile((self.address + reg) as *mut u64, val);
         }
+    }
 
-        trace!("Cleared HPET counter");
+    pub fn comparators(&self) -> &[HpetComparator] {
+        &self.comparators
+    }
+
+    fn counter(&self) -> u64 {
+        read_main_counter(self.address, self.wide_counter)
+    }
 
-        debug!("Initialized HPET at {hpet_address:#08X}");
-        Ok(HpetTimer { counter_period, address: hpet_address as usize })
+    fn duration_to_ticks(&self, dur: Duration) -> u64 {
+        // The period is in femtoseconds per tick
+        let ticks = (dur.as_nanos() * 1_000_000) / (self.counter_period as u128);
+        (ticks as u64).max(1)
     }
 
-    pub fn sleep(&self, dur: Duration) {
-        let mic = dur.as_micros();
-        let pass = (mic * 1_000_000_000) / (self.counter_period as u128); // period is in femtoseconds/tick
-        let addr = unsafe { (self.address as *const u8).add(0xf0) as *const u64 };
-        let start = unsafe { core::ptr::read_volatile(addr) };
+    fn comparator(&self, idx: usize) -> Result<&HpetComparator, HpetTimerError> {
+        self.comparators
+            .get(idx)
+            .filter(|x| x.allocated)
+            .ok_or(HpetTimerError::InvalidComparator)
+    }
 
-        while ((unsafe { core::ptr::read_volatile(addr) }) as u128) < pass + (start as u128) {
-            unsafe {
-                asm!("pause");
+    // Picks a GSI the comparator can be wired to, preferring ones above the ISA IRQs so that we
+    // don't steal the input of a legacy device
+    fn pick_ioapic_route(comparator: &HpetComparator) -> Option<u32> {
+        (16..32).chain(0..16).find(|gsi| (comparator.route_capabilities & (1 << gsi)) != 0)
+    }
+
+    // Reserves a comparator and routes its interrupt to `handler`, through the FSB if the
+    // comparator supports it and through the IOAPIC otherwise. The comparator stays disabled until
+    // one of the start functions is called. Returns the index of the comparator
+    pub fn allocate_comparator(
+        &mut self,
+        periodic: bool,
+        handler: IrqHandler,
+        ctx: usize
+    ) -> Result<usize, HpetTimerError> {
+        let idx = self.comparators
+            .iter()
+            .position(|x| !x.allocated && (!periodic || x.periodic_capable))
+            .ok_or(if periodic {
+                HpetTimerError::NotPeriodicCapable
+            } else {
+                HpetTimerError::NoFreeComparator
+            })?;
+
+        let comparator = self.comparators[idx];
+        let mut config = self.read(reg_timer_config(idx));
+        config &= !(TIMER_INT_ROUTE_MASK | TIMER_FSB_ENABLE | TIMER_LEVEL_TRIGGERED);
+
+        let (vector, route) = if comparator.fsb_capable {
+            let vector = irq::allocate_vector().map_err(HpetTimerError::Irq)?;
+            if let Err(err) = irq::install_handler(vector, handler, ctx) {
+                irq::free_vector(vector);
+                return Err(HpetTimerError::Irq(err));
             }
+
+            // Same format as an MSI: the address selects the LAPIC, the data holds the vector with
+            // fixed delivery and edge triggering
+            let address = 0xfee0_0000u64 | ((lapic::id() as u64) << 12);
+            self.write(reg_timer_fsb_route(idx), (address << 32) | (vector as u64));
+            config |= TIMER_FSB_ENABLE;
+
+            (vector, HpetRoute::Fsb)
+        } else {
+            let gsi = Self::pick_ioapic_route(&comparator).ok_or(HpetTimerError::NoUsableRoute)?;
+
+            // HPET interrupts through the IOAPIC are active high, and edge-triggered as long as
+            // the level-triggered bit is clear, which saves us from acknowledging them
+            let vector = irq
+                ::install_gsi_handler(
+                    IrqRoute {
+                        gsi,
+                        active_low: false,
+                        level_triggered: false,
+                    },
+                    handler,
+                    ctx
+                )
+                .map_err(HpetTimerError::Irq)?;
+            config |= (gsi as u64) << TIMER_INT_ROUTE_SHIFT;
+
+            (vector, HpetRoute::IoApic { gsi })
+        };
+
+        // Narrow comparators only match against the low half of the counter
+        if !comparator.wide {
+            config |= TIMER_32BIT_MODE;
+        }
+
+        self.write(reg_timer_config(idx), config);
+
+        let entry = &mut self.comparators[idx];
+        entry.allocated = true;
+        entry.vector = Some(vector);
+        entry.route = Some(route);
+
+        debug!("Allocated HPET comparator {idx} ({route:?}, vector {vector:#x})");
+        Ok(idx)
+    }
+
+    pub fn free_comparator(&mut self, idx: usize) {
+        let Ok(comparator) = self.comparator(idx).copied() else {
+            return;
+        };
+
+        self.stop(idx);
+
+        match (comparator.route, comparator.vector) {
+            (Some(HpetRoute::IoApic { gsi }), Some(vector)) => {
+                irq::uninstall_gsi_handler(gsi, vector);
+            }
+            (Some(HpetRoute::Fsb), Some(vector)) => {
+                irq::free_vector(vector);
+            }
+            _ => {}
+        }
+
+        let entry = &mut self.comparators[idx];
+        entry.allocated = false;
+        entry.vector = None;
+        entry.route = None;
+    }
+
+    // Fires the comparator's interrupt once after `dur`
+    pub fn start_one_shot(&self, idx: usize, dur: Duration) -> Result<(), HpetTimerError> {
+        let comparator = self.comparator(idx)?;
+
+        let mut config = self.read(reg_timer_config(idx));
+        config &= !TIMER_PERIODIC;
+        config |= TIMER_INT_ENABLE;
+        self.write(reg_timer_config(idx), config);
+
+        let target = self.counter().wrapping_add(self.duration_to_ticks(dur));
+        self.write(
+            reg_timer_comparator(idx),
+            if comparator.wide { target } else { target & (u32::MAX as u64) }
+        );
+
+        Ok(())
+    }
+
+    // Fires the comparator's interrupt every `period`
+    pub fn start_periodic(&self, idx: usize, period: Duration) -> Result<(), HpetTimerError> {
+        let comparator = self.comparator(idx)?;
+        if !comparator.periodic_capable {
+            return Err(HpetTimerError::NotPeriodicCapable);
         }
+
+        let ticks = self.duration_to_ticks(period);
+        let mask = if comparator.wide { u64::MAX } else { u32::MAX as u64 };
+
+        let mut config = self.read(reg_timer_config(idx));
+        config |= TIMER_INT_ENABLE | TIMER_PERIODIC | TIMER_VAL_SET;
+        self.write(reg_timer_config(idx), config);
+
+        // With VAL_SET the first write sets the comparator, the second one the accumulator that
+        // gets added to it every time it fires
+        self.write(reg_timer_comparator(idx), self.counter().wrapping_add(ticks) & mask);
+        self.write(reg_timer_comparator(idx), ticks & mask);
+
+        Ok(())
     }
 
-    pub fn get_us_passed(&self) -> u64 {
-        let ticks = unsafe { core::ptr::read_volatile(self.address as *const u64) };
-        let usx1 = 1_000_000_000 * self.counter_period;
-        let us_passed = ticks / usx1;
-        us_passed
+    pub fn stop(&self, idx: usize) {
+        if self.comparator(idx).is_err() {
+            return;
+        }
+
+        let config = self.read(reg_timer_config(idx));
+        self.write(reg_timer_config(idx), config & !(TIMER_INT_ENABLE | TIMER_PERIODIC));
     }
 }
 
-static HPET: Mutex<IsItUninit<HpetTimer>> = Mutex::new(IsItUninit::uninit());
+static HPET: Mutex<IsItUninit<HpetTimer>> = Mutex::named("hpet", IsItUninit::uninit());
+
+// The clock has to work from interrupt handlers and while the HPET lock is held, so everything it
+// needs is kept outside of HPET. The address stays 0 until the HPET is initialized
+static CLOCK_ADDRESS: AtomicUsize = AtomicUsize::new(0);
+static CLOCK_PERIOD_FS: AtomicU32 = AtomicU32::new(0);
+static CLOCK_WIDE: AtomicBool = AtomicBool::new(false);
+// Extends a 32-bit main counter to 64 bits: the upper half and the last lower half that was read.
+// Every wrap has to be observed, which the tick takes care of as it fires far more often than the
+// counter wraps (about every 5 minutes at 14.3MHz)
+static COUNTER_EXTENSION: Mutex<(u32, u32)> = Mutex::new((0, 0));
+
+fn read_main_counter(address: usize, wide: bool) -> u64 {
+    let low = (address + REG_MAIN_COUNTER) as *const u32;
+    let high = (address + REG_MAIN_COUNTER + 4) as *const u32;
+
+    if wide {
+        // We can only do 32-bit reads, so retry if the low half wrapped between the two reads
+        loop {
+            let before = unsafe { core::ptr::read_volatile(high) };
+            let value = unsafe { core::ptr::read_volatile(low) };
+            let after = unsafe { core::ptr::read_volatile(high) };
+            if before == after {
+                return ((after as u64) << 32) | (value as u64);
+            }
+        }
+    }
+
+    // Read the counter with the lock held, else a newer value could be stored before ours
+    let mut lock = COUNTER_EXTENSION.lock();
+    let value = unsafe { core::ptr::read_volatile(low) };
+    if value < lock.1 {
+        lock.0 = lock.0.wrapping_add(1);
+    }
+    lock.1 = value;
 
-pub fn init() {
-    HPET.lock().write(HpetTimer::new().expect("Could not initialize HPET!"));
+    ((lock.0 as u64) << 32) | (value as u64)
 }
 
+// The main counter extended to 64 bits. 0 if the HPET isn't initialized
+pub fn hpet_counter() -> u64 {
+    let address = CLOCK_ADDRESS.load(Ordering::Acquire);
+    if address == 0 {
+        return 0;
+    }
+
+    read_main_counter(address, CLOCK_WIDE.load(Ordering::Relaxed))
+}
+
+// Length of a counter tick in femtoseconds
+pub fn hpet_period_fs() -> u32 {
+    CLOCK_PERIOD_FS.load(Ordering::Relaxed)
+}
+
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    (((ticks as u128) * (hpet_period_fs() as u128)) / 1_000_000) as u64
+}
+
+pub fn ns_to_ticks(ns: u64) -> u64 {
+    let period = hpet_period_fs();
+    if period == 0 {
+        return 0;
+    }
+
+    (((ns as u128) * 1_000_000).div_ceil(period as u128)) as u64
+}
+
+// Nanoseconds since the HPET was initialized. Monotonic and safe to call from interrupt context
+pub fn now_ns() -> u64 {
+    ticks_to_ns(hpet_counter())
+}
+
+pub fn now() -> Duration {
+    Duration::from_nanos(now_ns())
+}
+
+// Keeps the 32-bit counter extension from missing a wrap, whatever drives the tick
+fn sample_counter() {
+    hpet_counter();
+}
+
+struct HpetClockSource;
+
+impl ClockSource for HpetClockSource {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn rating(&self) -> u32 {
+        250
+    }
+
+    fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / (hpet_period_fs().max(1) as u64)
+    }
+
+    fn read(&self) -> u64 {
+        hpet_counter()
+    }
+
+    // The period is in femtoseconds, which is more precise than going through the frequency
+    fn cycles_to_ns(&self, cycles: u64) -> u64 {
+        ticks_to_ns(cycles)
+    }
+}
+
+static HPET_CLOCK_SOURCE: HpetClockSource = HpetClockSource;
+
+// Uses a single periodic-capable comparator, allocated when the device gets selected
+struct HpetClockEvent {
+    comparator: AtomicUsize,
+}
+
+impl HpetClockEvent {
+    fn comparator(&self) -> Result<usize, ClockEventError> {
+        match self.comparator.load(Ordering::Acquire) {
+            usize::MAX => Err(ClockEventError::NoDevice),
+            idx => Ok(idx),
+        }
+    }
+}
+
+impl ClockEventDevice for HpetClockEvent {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn rating(&self) -> u32 {
+        200
+    }
+
+    fn periodic_capable(&self) -> bool {
+        HPET.lock()
+            .try_get_ref()
+            .is_some_and(|x| x.comparators().iter().any(|x| x.periodic_capable))
+    }
+
+    fn one_shot_capable(&self) -> bool {
+        hpet_initialized()
+    }
+
+    // Narrow comparators wrap after 2^32 counter ticks, so go by that for all of them
+    fn max_delta(&self) -> Duration {
+        Duration::from_nanos(ticks_to_ns(u32::MAX as u64))
+    }
+
+    fn setup(&self) -> Result<(), ClockEventError> {
+        let idx = hpet_allocate_comparator(true, event_handler, 0).map_err(ClockEventError::Hpet)?;
+        self.comparator.store(idx, Ordering::Release);
+        Ok(())
+    }
+
+    fn start_periodic(&self, period: Duration) -> Result<(), ClockEventError> {
+        hpet_start_periodic(self.comparator()?, period).map_err(ClockEventError::Hpet)
+    }
+
+    fn start_one_shot(&self, dur: Duration) -> Result<(), ClockEventError> {
+        hpet_start_one_shot(self.comparator()?, dur).map_err(ClockEventError::Hpet)
+    }
+
+    fn stop(&self) {
+        if let Ok(idx) = self.comparator() {
+            hpet_stop(idx);
+        }
+    }
+}
+
+static HPET_CLOCK_EVENT: HpetClockEvent = HpetClockEvent {
+    comparator: AtomicUsize::new(usize::MAX),
+};
+
+fn event_handler(_ctx: usize) {
+    clockevent::event();
+}
+
+pub fn init() -> Result<(), HpetTimerError> {
+    let hpet = HpetTimer::new()?;
+
+    CLOCK_WIDE.store(hpet.wide_counter, Ordering::Relaxed);
+    CLOCK_PERIOD_FS.store(hpet.counter_period as u32, Ordering::Relaxed);
+    CLOCK_ADDRESS.store(hpet.address, Ordering::Release);
+
+    if !CLOCK_WIDE.load(Ordering::Relaxed) {
+        tick::register_handler(sample_counter);
+    }
+
+    HPET.lock().write(hpet);
+
+    clocksource::register(&HPET_CLOCK_SOURCE);
+    clockevent::register(&HPET_CLOCK_EVENT);
+    Ok(())
+}
+
+pub fn hpet_allocate_comparator(
+    periodic: bool,
+    handler: IrqHandler,
+    ctx: usize
+) -> Result<usize, HpetTimerError> {
+    HPET.lock().get_mut().allocate_comparator(periodic, handler, ctx)
+}
+
+pub fn hpet_free_comparator(idx: usize) {
+    HPET.lock().get_mut().free_comparator(idx);
+}
+
+pub fn hpet_start_one_shot(idx: usize, dur: Duration) -> Result<(), HpetTimerError> {
+    HPET.lock().get_ref().start_one_shot(idx, dur)
+}
+
+pub fn hpet_start_periodic(idx: usize, period: Duration) -> Result<(), HpetTimerError> {
+    HPET.lock().get_ref().start_periodic(idx, period)
+}
+
+pub fn hpet_stop(idx: usize) {
+    HPET.lock().get_ref().stop(idx);
+}
+
+pub fn hpet_initialized() -> bool {
+    CLOCK_ADDRESS.load(Ordering::Acquire) != 0
+}
+
+// Busy-waits without holding the HPET lock, so interrupts keep coming in while we wait
 pub fn hpet_sleep(dur: Duration) {
-    HPET.lock().get_ref().sleep(dur);
+    let ticks = ns_to_ticks(dur.as_nanos() as u64);
+    let start = hpet_counter();
+
+    while hpet_counter().wrapping_sub(start) < ticks {
+        unsafe {
+            asm!("pause");
+        }
+    }
 }
 
 pub fn hpet_get_us_passed() -> u64 {
-    HPET.lock().get_ref().get_us_passed()
+    now_ns() / 1000
 }
@@ -0,0 +1,220 @@
+use core::{
+    sync::atomic::{ AtomicBool, AtomicU8, AtomicU32, Ordering },
+    time::Duration,
+};
+
+use crate::{
+    info,
+    sync::timer::{
+        self,
+        clockevent::{ self, ClockEventDevice, ClockEventError },
+        monotonic_ns,
+        tsc,
+    },
+    x86::{
+        cpuid,
+        idt::interrupt_control::IrqGuard,
+        irq,
+        lapic::{
+            self,
+            LVT_MASKED,
+            LVT_TIMER_PERIODIC,
+            LVT_TIMER_TSC_DEADLINE,
+            REG_LVT_TIMER,
+            REG_TIMER_CURRENT_COUNT,
+            REG_TIMER_DIVIDE,
+            REG_TIMER_INITIAL_COUNT,
+        },
+        msr::{ IA32_TSC_DEADLINE, wrmsr },
+    },
+};
+
+// The divide configuration register encodes "divide by 16" as 0b0011
+const DIVIDE_BY_16: u32 = 0b0011;
+
+const CALIBRATION_INTERVAL: Duration = Duration::from_millis(10);
+
+// Frequency of the timer after the divider. The bus clock is the same for every CPU, so this
+// only has to be calibrated once
+static TIMER_HZ: AtomicU32 = AtomicU32::new(0);
+static VECTOR: AtomicU8 = AtomicU8::new(0);
+static TSC_DEADLINE: AtomicBool = AtomicBool::new(false);
+
+fn interrupt_handler(_ctx: usize) {
+    clockevent::event();
+}
+
+fn calibrate() -> u32 {
+    let _guard = IrqGuard::new();
+
+    lapic::write(REG_TIMER_DIVIDE, DIVIDE_BY_16);
+    lapic::write(REG_LVT_TIMER, LVT_MASKED);
+
+    let read = || (u32::MAX - lapic::read(REG_TIMER_CURRENT_COUNT)) as u64;
+
+    lapic::write(REG_TIMER_INITIAL_COUNT, u32::MAX);
+    let hz = timer::calibrate(CALIBRATION_INTERVAL, read);
+    lapic::write(REG_TIMER_INITIAL_COUNT, 0);
+
+    hz as u32
+}
+
+// Calibrates the timer and allocates its vector. Has to run on the BSP before init_cpu()
+fn setup() -> Result<(), ClockEventError> {
+    let hz = calibrate();
+    if hz < 1000 {
+        return Err(ClockEventError::CalibrationFailed);
+    }
+
+    let vector = irq::allocate_vector().map_err(ClockEventError::Irq)?;
+    if let Err(err) = irq::install_handler(vector, interrupt_handler, 0) {
+        irq::free_vector(vector);
+        return Err(ClockEventError::Irq(err));
+    }
+
+    // The deadline is in TSC cycles, so we need a TSC we can convert to time
+    let tsc_deadline = cpuid::tsc_deadline_supported() && tsc::tsc_reliable();
+
+    TIMER_HZ.store(hz, Ordering::Relaxed);
+    TSC_DEADLINE.store(tsc_deadline, Ordering::Relaxed);
+    VECTOR.store(vector, Ordering::Release);
+
+    info!(
+        "LAPIC timer runs at {}.{:03}MHz{}",
+        hz / 1_000_000,
+        (hz / 1000) % 1000,
+        if tsc_deadline { ", TSC-deadline mode supported" } else { "" }
+    );
+
+    init_cpu();
+    Ok(())
+}
+
+// Sets up the timer of the CPU executing this. It stays stopped until one of the start functions
+// is called
+pub fn init_cpu() {
+    lapic::write(REG_TIMER_DIVIDE, DIVIDE_BY_16);
+    lapic::write(REG_LVT_TIMER, LVT_MASKED | (VECTOR.load(Ordering::Acquire) as u32));
+    lapic::write(REG_TIMER_INITIAL_COUNT, 0);
+}
+
+pub fn initialized() -> bool {
+    VECTOR.load(Ordering::Acquire) != 0
+}
+
+pub fn tsc_deadline_supported() -> bool {
+    TSC_DEADLINE.load(Ordering::Relaxed)
+}
+
+fn duration_to_count(dur: Duration) -> u32 {
+    let count = (dur.as_nanos() * (TIMER_HZ.load(Ordering::Relaxed) as u128)) / 1_000_000_000;
+
+    // Durations past what the counter can hold just fire early, the handler has to cope with that
+    count.clamp(1, u32::MAX as u128) as u32
+}
+
+// Fires on this CPU every `period`
+pub fn start_periodic(period: Duration) {
+    let vector = VECTOR.load(Ordering::Acquire) as u32;
+    lapic::write(REG_LVT_TIMER, LVT_TIMER_PERIODIC | vector);
+    lapic::write(REG_TIMER_INITIAL_COUNT, duration_to_count(period));
+}
+
+// Fires on this CPU once after `dur`
+pub fn start_one_shot(dur: Duration) {
+    let vector = VECTOR.load(Ordering::Acquire) as u32;
+    lapic::write(REG_LVT_TIMER, vector);
+    lapic::write(REG_TIMER_INITIAL_COUNT, duration_to_count(dur));
+}
+
+// Fires on this CPU once timer::monotonic_ns() reaches `deadline_ns`. Uses TSC-deadline mode if
+// the CPU has it, which doesn't suffer from the rounding and range limits of the counter
+pub fn program_deadline(deadline_ns: u64) {
+    let delta = deadline_ns.saturating_sub(monotonic_ns());
+
+    if !tsc_deadline_supported() {
+        start_one_shot(Duration::from_nanos(delta));
+        return;
+    }
+
+    let vector = VECTOR.load(Ordering::Acquire) as u32;
+    lapic::write(REG_LVT_TIMER, LVT_TIMER_TSC_DEADLINE | vector);
+
+    // The LVT write has to land before the MSR write, which the two don't order among themselves
+    unsafe {
+        core::arch::asm!("mfence");
+    }
+
+    // Writing 0 disarms the timer, so a deadline that already passed has to be at least 1
+    let deadline = tsc::rdtsc().wrapping_add(tsc::ns_to_cycles(delta)).max(1);
+    wrmsr(IA32_TSC_DEADLINE, deadline);
+}
+
+pub fn stop() {
+    if tsc_deadline_supported() {
+        wrmsr(IA32_TSC_DEADLINE, 0);
+    }
+
+    lapic::write(REG_LVT_TIMER, LVT_MASKED | (VECTOR.load(Ordering::Acquire) as u32));
+    lapic::write(REG_TIMER_INITIAL_COUNT, 0);
+}
+
+struct LapicClockEvent;
+
+impl ClockEventDevice for LapicClockEvent {
+    fn name(&self) -> &'static str {
+        "lapic"
+    }
+
+    // Cheap to program and fires on every CPU on its own
+    fn rating(&self) -> u32 {
+        300
+    }
+
+    fn periodic_capable(&self) -> bool {
+        true
+    }
+
+    fn one_shot_capable(&self) -> bool {
+        true
+    }
+
+    fn per_cpu(&self) -> bool {
+        true
+    }
+
+    fn max_delta(&self) -> Duration {
+        let hz = TIMER_HZ.load(Ordering::Relaxed).max(1) as u64;
+        Duration::from_nanos(((u32::MAX as u64) * 1_000_000_000) / hz)
+    }
+
+    fn setup(&self) -> Result<(), ClockEventError> {
+        setup()
+    }
+
+    fn start_periodic(&self, period: Duration) -> Result<(), ClockEventError> {
+        start_periodic(period);
+        Ok(())
+    }
+
+    fn start_one_shot(&self, dur: Duration) -> Result<(), ClockEventError> {
+        start_one_shot(dur);
+        Ok(())
+    }
+
+    fn program_deadline(&self, deadline_ns: u64) -> Result<(), ClockEventError> {
+        program_deadline(deadline_ns);
+        Ok(())
+    }
+
+    fn stop(&self) {
+        stop();
+    }
+}
+
+static LAPIC_CLOCK_EVENT: LapicClockEvent = LapicClockEvent;
+
+// Calibration waits until the device actually gets selected
+pub fn init() {
+    clockevent::register(&LAPIC_CLOCK_EVENT);
+}
@@ -1 +1,184 @@
+use core::{ sync::atomic::{ AtomicBool, Ordering }, time::Duration };
+
+use crate::{ info, warning, x86::percpu };
+
+pub mod clockevent;
+pub mod clocksource;
 pub mod hpet;
+pub mod lapic_timer;
+pub mod pit;
+pub mod tick;
+pub mod tsc;
+pub mod wheel;
+
+const TICK_NS: u64 = 1_000_000_000 / (tick::TICK_HZ as u64);
+// The most the tick gets skipped for at once. The 32-bit HPET counter only gets extended on ticks,
+// so they can't stop for anywhere near as long as it takes to wrap
+const MAX_IDLE: Duration = Duration::from_secs(1);
+
+// Set when the tick is emulated with one-shot deadlines on a per-CPU device, which lets it stop
+// while the CPU is idle
+static TICKLESS: AtomicBool = AtomicBool::new(false);
+
+// Brings up the timers, which register themselves as clock sources and clock event devices, and
+// starts the kernel tick on the best event device
+pub fn init() {
+    if let Err(err) = hpet::init() {
+        warning!("Could not initialize HPET ({err:?}), falling back to the PIT");
+    }
+
+    // The TSC and the LAPIC timer get calibrated against the clock source, so they have to come
+    // after the HPET
+    tsc::init();
+    lapic_timer::init();
+    pit::init();
+
+    if start_tick() {
+        tick::mark_started();
+    }
+}
+
+fn start_tick() -> bool {
+    wheel::init();
+
+    let device = match clockevent::select(true) {
+        Ok(device) => device,
+        Err(err) => {
+            warning!("No clock event device can drive the kernel tick: {err:?}");
+            return false;
+        }
+    };
+
+    // The emulated tick goes by the clock source, so it can't be the tick itself
+    let clock_independent = clocksource::current().is_some_and(|x| !x.needs_interrupts());
+    if device.per_cpu() && device.one_shot_capable() && clock_independent {
+        clockevent::set_event_handler(tickless_event);
+        TICKLESS.store(true, Ordering::Release);
+        program_next_tick();
+        let name = device.name();
+        info!("{name} drives the kernel tick at {}Hz, tickless when idle", tick::TICK_HZ);
+        return true;
+    }
+
+    match device.start_periodic(Duration::from_secs(1) / tick::TICK_HZ) {
+        Ok(()) => {
+            info!("{} drives the kernel tick at {}Hz", device.name(), tick::TICK_HZ);
+            true
+        }
+        Err(err) => {
+            warning!("Could not start the kernel tick on {}: {err:?}", device.name());
+            false
+        }
+    }
+}
+
+fn tickless() -> bool {
+    TICKLESS.load(Ordering::Acquire)
+}
+
+fn program_deadline(deadline_ns: u64) {
+    // Only per-CPU devices are used like this, and those can't fail to be programmed
+    if let Some(device) = clockevent::current() {
+        let _ = device.program_deadline(deadline_ns);
+    }
+}
+
+fn program_next_tick() {
+    program_deadline((monotonic_ns() / TICK_NS + 1) * TICK_NS);
+}
+
+// Where in monotonic_ns() time `tick` starts, which can be in the past
+fn tick_to_ns(tick: u32) -> u64 {
+    let now = monotonic_ns() / TICK_NS;
+    let delta = tick.wrapping_sub(now as u32) as i32;
+    (now as i64).saturating_add(delta as i64).max(0) as u64 * TICK_NS
+}
+
+fn tickless_event() {
+    // The tick count is the boot CPU's, the others only run their per-CPU handlers
+    if percpu::cpu_id() == 0 {
+        tick::tick_to((monotonic_ns() / TICK_NS) as u32);
+    } else {
+        tick::tick();
+    }
+    program_next_tick();
+}
+
+// Starts the tick on the AP executing this, the same way start_tick() did on the boot CPU. A
+// device that isn't per-CPU only ever interrupts the boot CPU, so then the APs go without
+pub fn start_cpu_tick() {
+    let Some(device) = clockevent::current() else {
+        return;
+    };
+    if !tick::started() || !device.per_cpu() {
+        return;
+    }
+
+    if tickless() {
+        program_next_tick();
+    } else if let Err(err) = device.start_periodic(Duration::from_secs(1) / tick::TICK_HZ) {
+        warning!("Could not start the tick of CPU {}: {err:?}", percpu::cpu_id());
+    }
+}
+
+// Called by the idle thread with interrupts disabled right before it halts. Pushes the next tick
+// out to when the next timer expires, so that an idle CPU doesn't get woken up for nothing
+pub fn idle_enter() {
+    if !tickless() {
+        return;
+    }
+
+    let max_idle = clockevent::current().map_or(MAX_IDLE, |x| x.max_delta().min(MAX_IDLE));
+    let max_deadline = monotonic_ns().saturating_add(max_idle.as_nanos() as u64);
+    // Timers only run off the boot CPU's tick
+    let next_timer = if percpu::cpu_id() == 0 { wheel::next_expiry() } else { None };
+    let deadline = next_timer.map_or(max_deadline, |x| tick_to_ns(x).min(max_deadline));
+    program_deadline(deadline);
+}
+
+// Called by the idle thread once it got woken up, whatever it was that did that. Fires the tick
+// right away so that it catches up on the ones it skipped before anything else looks at it
+pub fn idle_exit() {
+    if tickless() {
+        program_deadline(monotonic_ns());
+    }
+}
+
+// Nanoseconds since boot (more or less, the clock starts when the timers get initialized). Safe to
+// call from interrupt context
+pub fn monotonic_ns() -> u64 {
+    clocksource::now_ns()
+}
+
+// Busy-waits on the clock source, or on the PIT if it needs interrupts to advance. Works with
+// interrupts disabled
+pub fn delay(dur: Duration) {
+    if !clocksource::current().is_some_and(|x| !x.needs_interrupts()) {
+        pit::delay(dur);
+        return;
+    }
+
+    let deadline = monotonic_ns().saturating_add(dur.as_nanos() as u64);
+    while monotonic_ns() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+// Measures how far `read` advances over `interval` and returns that as a rate per second. Goes by
+// the clock source if it works without interrupts and by the PIT otherwise
+pub fn calibrate(interval: Duration, mut read: impl FnMut() -> u64) -> u64 {
+    if !clocksource::current().is_some_and(|x| !x.needs_interrupts()) {
+        return pit::calibrate(interval, read);
+    }
+
+    let clock_start = monotonic_ns();
+    let start = read();
+    delay(interval);
+    let end = read();
+    let elapsed = monotonic_ns() - clock_start;
+    if elapsed == 0 {
+        return 0;
+    }
+
+    (((end.wrapping_sub(start) as u128) * 1_000_000_000) / (elapsed as u128)) as u64
+}
@@ -0,0 +1,205 @@
+use core::time::Duration;
+
+use crate::{
+    debug,
+    sync::{
+        mutex::Mutex,
+        timer::clockevent::{ self, ClockEventDevice, ClockEventError },
+    },
+    x86::{
+        ioapic,
+        ioport::{ inb, outb },
+        irq::{ self, IrqError, IrqHandler },
+    },
+};
+
+// The PIT input clock, a third of the NTSC color burst frequency
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+
+const PORT_CHANNEL0: u16 = 0x40;
+const PORT_CHANNEL2: u16 = 0x42;
+const PORT_COMMAND: u16 = 0x43;
+// Bit 0 gates channel 2, bit 1 connects it to the speaker and bit 5 reads back its output
+const PORT_CHANNEL2_GATE: u16 = 0x61;
+
+// Command byte fields: channel in bits 6-7, access mode in bits 4-5, operating mode in bits 1-3
+const COMMAND_CHANNEL0: u8 = 0b00 << 6;
+const COMMAND_CHANNEL2: u8 = 0b10 << 6;
+const COMMAND_ACCESS_LOW_HIGH: u8 = 0b11 << 4;
+const COMMAND_MODE_TERMINAL_COUNT: u8 = 0b000 << 1;
+const COMMAND_MODE_RATE_GENERATOR: u8 = 0b010 << 1;
+
+// The longest interval a single countdown can cover, about 54.9ms
+pub const MAX_INTERVAL: Duration = Duration::from_nanos(
+    (0x10000u64 * 1_000_000_000) / (PIT_FREQUENCY as u64)
+);
+
+struct PitIrq {
+    gsi: u32,
+    vector: u8,
+}
+
+static PIT_IRQ: Mutex<Option<PitIrq>> = Mutex::new(None);
+
+fn duration_to_count(dur: Duration) -> u16 {
+    let count = (dur.as_nanos() * (PIT_FREQUENCY as u128)) / 1_000_000_000;
+
+    // A reload value of 0 means 0x10000
+    count.clamp(1, 0x10000) as u16
+}
+
+fn program_channel0(mode: u8, count: u16) {
+    outb(PORT_COMMAND, COMMAND_CHANNEL0 | COMMAND_ACCESS_LOW_HIGH | mode);
+    outb(PORT_CHANNEL0, count as u8);
+    outb(PORT_CHANNEL0, (count >> 8) as u8);
+}
+
+// Routes IRQ0 to `handler`. The interrupt stays masked until one of the start functions is called
+pub fn install_handler(handler: IrqHandler, ctx: usize) -> Result<u8, IrqError> {
+    let mut lock = PIT_IRQ.lock();
+    if lock.is_some() {
+        return Err(IrqError::VectorInUse);
+    }
+
+    // IRQ0 is almost always overridden to GSI 2 in the MADT
+    let route = irq::isa_irq_route(0);
+    let vector = irq::install_gsi_handler(route, handler, ctx)?;
+    ioapic::mask(route.gsi);
+
+    debug!("PIT IRQ0 routed to GSI {} (vector {vector:#x})", route.gsi);
+    *lock = Some(PitIrq { gsi: route.gsi, vector });
+
+    Ok(vector)
+}
+
+pub fn uninstall_handler() {
+    if let Some(pit_irq) = PIT_IRQ.lock().take() {
+        irq::uninstall_gsi_handler(pit_irq.gsi, pit_irq.vector);
+    }
+}
+
+fn unmask() {
+    if let Some(pit_irq) = PIT_IRQ.lock().as_ref() {
+        ioapic::unmask(pit_irq.gsi);
+    }
+}
+
+// Fires IRQ0 `hz` times a second
+pub fn start_periodic(hz: u32) {
+    let count = (PIT_FREQUENCY / hz.max(1)).clamp(1, 0x10000);
+    program_channel0(COMMAND_MODE_RATE_GENERATOR, count as u16);
+    unmask();
+}
+
+// Fires IRQ0 once after `dur`, which gets clamped to MAX_INTERVAL
+pub fn start_one_shot(dur: Duration) {
+    program_channel0(COMMAND_MODE_TERMINAL_COUNT, duration_to_count(dur));
+    unmask();
+}
+
+pub fn stop() {
+    if let Some(pit_irq) = PIT_IRQ.lock().as_ref() {
+        ioapic::mask(pit_irq.gsi);
+    }
+}
+
+fn event_handler(_ctx: usize) {
+    clockevent::event();
+}
+
+struct PitClockEvent;
+
+impl ClockEventDevice for PitClockEvent {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    // Slow to program and only has the one channel, so it's the last resort
+    fn rating(&self) -> u32 {
+        100
+    }
+
+    fn periodic_capable(&self) -> bool {
+        true
+    }
+
+    fn one_shot_capable(&self) -> bool {
+        true
+    }
+
+    fn max_delta(&self) -> Duration {
+        MAX_INTERVAL
+    }
+
+    fn setup(&self) -> Result<(), ClockEventError> {
+        install_handler(event_handler, 0).map_err(ClockEventError::Irq)?;
+        Ok(())
+    }
+
+    fn start_periodic(&self, period: Duration) -> Result<(), ClockEventError> {
+        if period > MAX_INTERVAL {
+            return Err(ClockEventError::Unsupported);
+        }
+
+        program_channel0(COMMAND_MODE_RATE_GENERATOR, duration_to_count(period));
+        unmask();
+        Ok(())
+    }
+
+    fn start_one_shot(&self, dur: Duration) -> Result<(), ClockEventError> {
+        start_one_shot(dur);
+        Ok(())
+    }
+
+    fn stop(&self) {
+        stop();
+    }
+}
+
+static PIT_CLOCK_EVENT: PitClockEvent = PitClockEvent;
+
+// Every PC has one, so there's nothing to probe
+pub fn init() {
+    clockevent::register(&PIT_CLOCK_EVENT);
+}
+
+// Counts channel 2 down once and waits for its output to go high. Channel 2 isn't wired to an
+// IRQ, so this works with interrupts disabled and doesn't disturb the tick on channel 0
+fn channel2_countdown(count: u16) {
+    // Enable the gate but keep the speaker disconnected
+    let gate = inb(PORT_CHANNEL2_GATE);
+    outb(PORT_CHANNEL2_GATE, (gate & !0b10) | 0b1);
+
+    outb(PORT_COMMAND, COMMAND_CHANNEL2 | COMMAND_ACCESS_LOW_HIGH | COMMAND_MODE_TERMINAL_COUNT);
+    outb(PORT_CHANNEL2, count as u8);
+    outb(PORT_CHANNEL2, (count >> 8) as u8);
+
+    while (inb(PORT_CHANNEL2_GATE) & (1 << 5)) == 0 {
+        core::hint::spin_loop();
+    }
+
+    outb(PORT_CHANNEL2_GATE, gate);
+}
+
+// Busy-waits for `dur` using channel 2
+pub fn delay(dur: Duration) {
+    let mut remaining = dur;
+    while !remaining.is_zero() {
+        let interval = remaining.min(MAX_INTERVAL);
+        channel2_countdown(duration_to_count(interval));
+        remaining -= interval;
+    }
+}
+
+// Measures how far `read` advances over `interval` (clamped to MAX_INTERVAL) of channel 2 and
+// returns that as a rate per second. Used to calibrate the TSC and the LAPIC timer
+pub fn calibrate(interval: Duration, mut read: impl FnMut() -> u64) -> u64 {
+    let count = duration_to_count(interval.min(MAX_INTERVAL));
+    let ticks = if count == 0 { 0x10000 } else { count as u64 };
+
+    let start = read();
+    channel2_countdown(count);
+    let end = read();
+
+    end.wrapping_sub(start) * (PIT_FREQUENCY as u64) / ticks
+}
@@ -0,0 +1,125 @@
+use core::sync::atomic::{ AtomicBool, AtomicU32, Ordering };
+
+use crate::{
+    sync::{ mutex::Mutex, timer::clocksource::{ self, ClockSource } },
+    x86::percpu,
+};
+
+// How often the kernel tick fires
+pub const TICK_HZ: u32 = 100;
+
+const MAX_TICK_HANDLERS: usize = 8;
+
+pub type TickHandler = fn();
+type HandlerTable = Mutex<[Option<TickHandler>; MAX_TICK_HANDLERS]>;
+
+static TICKS: AtomicU32 = AtomicU32::new(0);
+static STARTED: AtomicBool = AtomicBool::new(false);
+// A fixed-size table so that tick() never has to touch the heap from interrupt context
+static TICK_HANDLERS: HandlerTable = Mutex::new([None; MAX_TICK_HANDLERS]);
+// Run by the tick of every CPU that has one, not just the boot CPU's
+static CPU_TICK_HANDLERS: HandlerTable = Mutex::new([None; MAX_TICK_HANDLERS]);
+
+fn register(table: &HandlerTable, handler: TickHandler) -> bool {
+    let mut lock = table.lock();
+    let Some(slot) = lock.iter_mut().find(|x| x.is_none()) else {
+        return false;
+    };
+
+    *slot = Some(handler);
+    true
+}
+
+// Registers a function that runs on every tick in interrupt context, e.g. for timers. Returns
+// false if the table is full
+pub fn register_handler(handler: TickHandler) -> bool {
+    register(&TICK_HANDLERS, handler)
+}
+
+// Like register_handler(), but for things that every CPU has to do on its own tick, e.g.
+// preemption. Only CPUs whose tick comes from a per-CPU device get one
+pub fn register_cpu_handler(handler: TickHandler) -> bool {
+    register(&CPU_TICK_HANDLERS, handler)
+}
+
+pub fn unregister_handler(handler: TickHandler) {
+    for table in [&TICK_HANDLERS, &CPU_TICK_HANDLERS] {
+        let mut lock = table.lock();
+        for slot in lock.iter_mut() {
+            if slot.is_some_and(|x| core::ptr::fn_addr_eq(x, handler)) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+fn run_handlers(table: &HandlerTable) {
+    // Copy the table out so that handlers may register or unregister handlers themselves
+    let handlers = *table.lock();
+    for handler in handlers.into_iter().flatten() {
+        handler();
+    }
+}
+
+// Called by whatever event source drives the tick. The tick count and everything that isn't per-CPU
+// belongs to the boot CPU, the others only run their own part
+pub fn tick() {
+    if percpu::cpu_id() == 0 {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+        run_handlers(&TICK_HANDLERS);
+    }
+    run_handlers(&CPU_TICK_HANDLERS);
+}
+
+// Called instead of tick() when the tick is emulated with one-shot deadlines, which skip ticks
+// while the CPU idles. Catches the count up to `now` and runs the handlers once, they have to cope
+// with more than one tick having passed
+pub fn tick_to(now: u32) {
+    TICKS.store(now, Ordering::Relaxed);
+    run_handlers(&TICK_HANDLERS);
+    run_handlers(&CPU_TICK_HANDLERS);
+}
+
+// Number of ticks since the tick source was started, or since the clock started when the tick is
+// emulated
+pub fn ticks() -> u32 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+// Called once a tick source is running, from then on the tick can serve as a clock
+pub fn mark_started() {
+    STARTED.store(true, Ordering::Release);
+    clocksource::register(&TICK_CLOCK_SOURCE);
+}
+
+// Whether anything drives the tick. Until it does, waiting for a tick would wait forever
+pub fn started() -> bool {
+    STARTED.load(Ordering::Acquire)
+}
+
+// Counts ticks, for machines that have nothing better. Wraps after 497 days at 100Hz
+struct TickClockSource;
+
+impl ClockSource for TickClockSource {
+    fn name(&self) -> &'static str {
+        "tick"
+    }
+
+    fn rating(&self) -> u32 {
+        1
+    }
+
+    fn frequency(&self) -> u64 {
+        TICK_HZ as u64
+    }
+
+    fn read(&self) -> u64 {
+        ticks() as u64
+    }
+
+    fn needs_interrupts(&self) -> bool {
+        true
+    }
+}
+
+static TICK_CLOCK_SOURCE: TickClockSource = TickClockSource;
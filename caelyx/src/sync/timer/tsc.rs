@@ -0,0 +1,128 @@
+use core::{ sync::atomic::{ AtomicBool, AtomicU32, Ordering }, time::Duration };
+
+use crate::{
+    debug,
+    info,
+    sync::timer::{ self, clocksource::{ self, ClockSource } },
+    warning,
+    x86::{ cpuid::{ self, Features }, idt::interrupt_control::without_interrupts },
+};
+
+const CALIBRATION_INTERVAL: Duration = Duration::from_millis(10);
+const CALIBRATION_RUNS: usize = 3;
+
+// The frequency in kHz fits in 32 bits up to 4.29THz, which should do for a while
+static TSC_KHZ: AtomicU32 = AtomicU32::new(0);
+static TSC_RELIABLE: AtomicBool = AtomicBool::new(false);
+
+pub fn rdtsc() -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        core::arch::asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack));
+    }
+
+    ((high as u64) << 32) | (low as u64)
+}
+
+// Measures the TSC a few times and takes the median, which throws out runs that got disturbed by
+// SMIs or the hypervisor
+fn calibrate() -> u64 {
+    let mut runs = [0u64; CALIBRATION_RUNS];
+    without_interrupts(|| {
+        for run in runs.iter_mut() {
+            *run = timer::calibrate(CALIBRATION_INTERVAL, rdtsc);
+        }
+    });
+
+    runs.sort_unstable();
+    runs[CALIBRATION_RUNS / 2]
+}
+
+pub fn init() {
+    if !cpuid::feature_present(&Features::Tsc) {
+        warning!("CPU has no TSC");
+        return;
+    }
+
+    let invariant = cpuid::invariant_tsc();
+    let hz = match cpuid::tsc_frequency() {
+        Some(hz) => {
+            debug!("TSC frequency from CPUID: {hz}Hz");
+            hz
+        }
+        None => calibrate(),
+    };
+
+    if hz < 1000 {
+        warning!("TSC calibration failed ({hz}Hz)");
+        return;
+    }
+
+    TSC_KHZ.store((hz / 1000) as u32, Ordering::Relaxed);
+
+    // A TSC that changes its rate with the P-state or stops in deep C-states can't be trusted to
+    // tell time, it only beats having no clock at all
+    TSC_RELIABLE.store(invariant, Ordering::Release);
+
+    info!(
+        "TSC runs at {}.{:03}MHz{}",
+        hz / 1_000_000,
+        (hz / 1000) % 1000,
+        if invariant { " (invariant)" } else { ", not invariant" }
+    );
+
+    clocksource::register(&TSC_CLOCK_SOURCE);
+}
+
+pub fn tsc_khz() -> u32 {
+    TSC_KHZ.load(Ordering::Relaxed)
+}
+
+// Whether the TSC runs at a constant rate and can be converted to time
+pub fn tsc_reliable() -> bool {
+    TSC_RELIABLE.load(Ordering::Acquire)
+}
+
+pub fn cycles_to_ns(cycles: u64) -> u64 {
+    let hz = (tsc_khz() as u64) * 1000;
+    if hz == 0 {
+        return 0;
+    }
+
+    // Split into whole seconds and the rest so that the multiplication can't overflow
+    (cycles / hz) * 1_000_000_000 + ((cycles % hz) * 1_000_000_000) / hz
+}
+
+pub fn ns_to_cycles(ns: u64) -> u64 {
+    let khz = tsc_khz() as u64;
+
+    // Same trick as cycles_to_ns(), in whole milliseconds and the rest
+    (ns / 1_000_000) * khz + ((ns % 1_000_000) * khz) / 1_000_000
+}
+
+struct TscClockSource;
+
+impl ClockSource for TscClockSource {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn rating(&self) -> u32 {
+        if tsc_reliable() { 300 } else { 100 }
+    }
+
+    fn frequency(&self) -> u64 {
+        (tsc_khz() as u64) * 1000
+    }
+
+    fn read(&self) -> u64 {
+        rdtsc()
+    }
+
+    fn cycles_to_ns(&self, cycles: u64) -> u64 {
+        cycles_to_ns(cycles)
+    }
+}
+
+static TSC_CLOCK_SOURCE: TscClockSource = TscClockSource;
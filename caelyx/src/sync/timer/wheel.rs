@@ -0,0 +1,397 @@
+use core::{ sync::atomic::{ AtomicBool, Ordering }, time::Duration };
+
+use crate::{
+    sched,
+    sync::{ mutex::Mutex, timer::{ self, monotonic_ns, tick } },
+    x86::{ idt::interrupt_control::interrupts_enabled, irq::in_interrupt, wait_for_interrupt },
+};
+
+// A hierarchical timer wheel in the style of the classic Unix callout wheels: level N has
+// WHEEL_SLOTS slots that each cover WHEEL_SLOTS^N ticks. Timers are filed into the level their
+// deadline falls into and cascade down one level whenever the level below wraps around. Adding,
+// cancelling and expiring a timer is O(1) and nothing is ever allocated, so timers can be armed
+// from interrupt handlers
+const WHEEL_BITS: u32 = 6;
+const WHEEL_SLOTS: usize = 1 << WHEEL_BITS;
+const WHEEL_MASK: u32 = (WHEEL_SLOTS as u32) - 1;
+const WHEEL_LEVELS: usize = 4;
+// With TICK_HZ at 100 this is about 46 hours. Timers further out get filed at the end and
+// re-filed when they get there
+const MAX_DELTA: u32 = (1 << (WHEEL_BITS * (WHEEL_LEVELS as u32))) - 1;
+
+const MAX_TIMERS: usize = 256;
+const NONE: u16 = u16::MAX;
+
+pub type TimerCallback = fn(ctx: usize);
+
+#[derive(Debug)]
+pub enum TimerError {
+    NoFreeTimers,
+}
+
+// Identifies an armed timer. Stays unique after the timer fired, so cancelling a timer that
+// already ran is harmless
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    index: u16,
+    generation: u32,
+}
+
+#[derive(Clone, Copy)]
+struct TimerEntry {
+    callback: Option<TimerCallback>,
+    ctx: usize,
+    expires: u32,
+    generation: u32,
+    prev: u16,
+    next: u16,
+    // The level and slot the entry is linked into, NONE if it isn't in the wheel
+    level: u16,
+    slot: u16,
+}
+
+impl TimerEntry {
+    const EMPTY: Self = Self {
+        callback: None,
+        ctx: 0,
+        expires: 0,
+        generation: 0,
+        prev: NONE,
+        next: NONE,
+        level: NONE,
+        slot: NONE,
+    };
+}
+
+struct TimerWheel {
+    entries: [TimerEntry; MAX_TIMERS],
+    slots: [[u16; WHEEL_SLOTS]; WHEEL_LEVELS],
+    free: u16,
+    // The last tick that was processed
+    current: u32,
+}
+
+impl TimerWheel {
+    const fn new() -> Self {
+        Self {
+            entries: [TimerEntry::EMPTY; MAX_TIMERS],
+            slots: [[NONE; WHEEL_SLOTS]; WHEEL_LEVELS],
+            free: NONE,
+            current: 0,
+        }
+    }
+
+    // The free list is threaded through `next`, built lazily since it can't be done in a const fn
+    fn allocate(&mut self) -> Option<u16> {
+        if self.free == NONE && self.entries[0].generation == 0 {
+            for i in 0..MAX_TIMERS {
+                self.entries[i].next = if i + 1 < MAX_TIMERS { (i + 1) as u16 } else { NONE };
+                self.entries[i].generation = 1;
+            }
+            self.free = 0;
+        }
+
+        let index = self.free;
+        if index == NONE {
+            return None;
+        }
+
+        self.free = self.entries[index as usize].next;
+        Some(index)
+    }
+
+    fn release(&mut self, index: u16) {
+        let entry = &mut self.entries[index as usize];
+        entry.callback = None;
+        entry.generation = entry.generation.wrapping_add(1).max(1);
+        entry.next = self.free;
+        self.free = index;
+    }
+
+    fn link(&mut self, index: u16) {
+        let expires = self.entries[index as usize].expires;
+        let delta = expires.wrapping_sub(self.current).min(MAX_DELTA);
+        let expires = self.current.wrapping_add(delta);
+
+        let mut level = 0;
+        while level + 1 < WHEEL_LEVELS && delta >= 1 << (WHEEL_BITS * ((level as u32) + 1)) {
+            level += 1;
+        }
+        let slot = ((expires >> (WHEEL_BITS * (level as u32))) & WHEEL_MASK) as usize;
+
+        let head = self.slots[level][slot];
+        let entry = &mut self.entries[index as usize];
+        entry.prev = NONE;
+        entry.next = head;
+        entry.level = level as u16;
+        entry.slot = slot as u16;
+
+        if head != NONE {
+            self.entries[head as usize].prev = index;
+        }
+        self.slots[level][slot] = index;
+    }
+
+    fn unlink(&mut self, index: u16) {
+        let entry = self.entries[index as usize];
+        if entry.level == NONE {
+            return;
+        }
+
+        if entry.prev == NONE {
+            self.slots[entry.level as usize][entry.slot as usize] = entry.next;
+        } else {
+            self.entries[entry.prev as usize].next = entry.next;
+        }
+        if entry.next != NONE {
+            self.entries[entry.next as usize].prev = entry.prev;
+        }
+
+        let entry = &mut self.entries[index as usize];
+        entry.prev = NONE;
+        entry.next = NONE;
+        entry.level = NONE;
+        entry.slot = NONE;
+    }
+
+    // Moves every timer of a slot one level closer to expiring
+    fn cascade(&mut self, level: usize, slot: usize) {
+        let mut index = self.slots[level][slot];
+        self.slots[level][slot] = NONE;
+
+        while index != NONE {
+            let next = self.entries[index as usize].next;
+            let entry = &mut self.entries[index as usize];
+            entry.level = NONE;
+            entry.slot = NONE;
+            self.link(index);
+            index = next;
+        }
+    }
+
+    // Moves to the next tick, returning false if we're caught up with `now`
+    fn advance(&mut self, now: u32) -> bool {
+        if self.current == now {
+            return false;
+        }
+
+        self.current = self.current.wrapping_add(1);
+
+        // Whenever a level wraps around, the next slot of the level above gets spread out below
+        for level in 1..WHEEL_LEVELS {
+            let shift = WHEEL_BITS * (level as u32);
+            if (self.current & ((1 << shift) - 1)) != 0 {
+                break;
+            }
+
+            self.cascade(level, ((self.current >> shift) & WHEEL_MASK) as usize);
+        }
+
+        true
+    }
+
+    // Takes one timer that expired on the current tick out of the wheel
+    fn pop_expired(&mut self) -> Option<(TimerCallback, usize)> {
+        let slot = (self.current & WHEEL_MASK) as usize;
+        loop {
+            let index = self.slots[0][slot];
+            if index == NONE {
+                return None;
+            }
+
+            self.unlink(index);
+
+            // Timers that were clamped to MAX_DELTA land here early and need to be filed again,
+            // which always puts them into a different slot
+            let entry = self.entries[index as usize];
+            if entry.expires != self.current {
+                self.link(index);
+                continue;
+            }
+
+            self.release(index);
+            return Some((entry.callback?, entry.ctx));
+        }
+    }
+}
+
+static WHEEL: Mutex<TimerWheel> = Mutex::named("timer_wheel", TimerWheel::new());
+
+// Capped at half the range of the tick counter so that deadlines never look like they're in the
+// past after it wraps
+fn ns_to_ticks(ns: u64) -> u32 {
+    let tick_ns = 1_000_000_000 / (tick::TICK_HZ as u64);
+    ns.div_ceil(tick_ns).min((u32::MAX / 2) as u64) as u32
+}
+
+// Runs `callback` from the tick interrupt once `delay` has passed. The callback runs in interrupt
+// context, anything heavier should be handed to the work queue from there
+pub fn add_timer(
+    delay: Duration,
+    callback: TimerCallback,
+    ctx: usize
+) -> Result<TimerId, TimerError> {
+    let mut lock = WHEEL.lock();
+    let index = lock.allocate().ok_or(TimerError::NoFreeTimers)?;
+
+    // We're somewhere in the middle of the current tick, so a whole number of ticks from here can
+    // end up to one tick short. Round up and add a tick so that the timer never fires early
+    let ticks = ns_to_ticks(delay.as_nanos() as u64) + 1;
+    let expires = lock.current.wrapping_add(ticks);
+
+    let entry = &mut lock.entries[index as usize];
+    entry.callback = Some(callback);
+    entry.ctx = ctx;
+    entry.expires = expires;
+    let generation = entry.generation;
+
+    lock.link(index);
+    Ok(TimerId { index, generation })
+}
+
+// Like add_timer, but with an absolute deadline in timer::monotonic_ns() time
+pub fn add_timer_at(
+    deadline_ns: u64,
+    callback: TimerCallback,
+    ctx: usize
+) -> Result<TimerId, TimerError> {
+    add_timer(Duration::from_nanos(deadline_ns.saturating_sub(monotonic_ns())), callback, ctx)
+}
+
+// Returns true if the timer was still pending, false if it already fired or was cancelled
+pub fn cancel(id: TimerId) -> bool {
+    let mut lock = WHEEL.lock();
+    let entry = lock.entries[id.index as usize];
+    if entry.generation != id.generation || entry.callback.is_none() {
+        return false;
+    }
+
+    lock.unlink(id.index);
+    lock.release(id.index);
+    true
+}
+
+// The tick the next timer expires on, so that an idle CPU knows how long it can skip the tick for
+pub fn next_expiry() -> Option<u32> {
+    let lock = WHEEL.lock();
+    lock.entries
+        .iter()
+        .filter(|x| x.callback.is_some() && x.level != NONE)
+        .map(|x| x.expires.wrapping_sub(lock.current))
+        .min()
+        .map(|delta| lock.current.wrapping_add(delta))
+}
+
+// Registered as a tick handler. Catches up on every tick since the last call and runs the timers
+// that expired, without holding the lock so that callbacks can arm timers themselves
+fn run_timers() {
+    let now = tick::ticks();
+    loop {
+        let expired = {
+            let mut lock = WHEEL.lock();
+            loop {
+                if let Some(expired) = lock.pop_expired() {
+                    break Some(expired);
+                }
+                if !lock.advance(now) {
+                    break None;
+                }
+            }
+        };
+
+        let Some((callback, ctx)) = expired else {
+            break;
+        };
+        callback(ctx);
+    }
+}
+
+pub fn init() {
+    WHEEL.lock().current = tick::ticks();
+    tick::register_handler(run_timers);
+}
+
+fn set_flag(ctx: usize) {
+    unsafe { &*(ctx as *const AtomicBool) }.store(true, Ordering::Release);
+}
+
+// Waits until monotonic_ns() reaches `deadline_ns`. Blocks the thread once the scheduler runs,
+// before that it halts the CPU in between ticks. Falls back to busy-waiting where we can't wait
+// for interrupts
+pub fn sleep_until(deadline_ns: u64) {
+    let now = monotonic_ns();
+    if deadline_ns <= now {
+        return;
+    }
+
+    if sched::can_block() && tick::started() {
+        sched::sleep_until(deadline_ns);
+        return;
+    }
+
+    if in_interrupt() || !interrupts_enabled() || !tick::started() {
+        timer::delay(Duration::from_nanos(deadline_ns - now));
+        return;
+    }
+
+    // The flag lives on our stack, which is fine since we don't return before the timer fired
+    let fired = AtomicBool::new(false);
+    if add_timer_at(deadline_ns, set_flag, &raw const fired as usize).is_err() {
+        timer::delay(Duration::from_nanos(deadline_ns - now));
+        return;
+    }
+
+    while !fired.load(Ordering::Acquire) {
+        wait_for_interrupt();
+    }
+}
+
+pub fn sleep(dur: Duration) {
+    sleep_until(monotonic_ns().saturating_add(dur.as_nanos() as u64));
+}
+
+// A deadline to check a polling loop against
+#[derive(Debug, Clone, Copy)]
+pub struct Timeout {
+    deadline_ns: u64,
+}
+
+impl Timeout {
+    pub fn after(dur: Duration) -> Self {
+        Self { deadline_ns: monotonic_ns().saturating_add(dur.as_nanos() as u64) }
+    }
+
+    pub fn at(deadline_ns: u64) -> Self {
+        Self { deadline_ns }
+    }
+
+    pub fn expired(&self) -> bool {
+        monotonic_ns() >= self.deadline_ns
+    }
+
+    pub fn remaining(&self) -> Duration {
+        Duration::from_nanos(self.deadline_ns.saturating_sub(monotonic_ns()))
+    }
+}
+
+// Polls `condition` until it returns true or `timeout` passes, sleeping until the next interrupt
+// in between. Returns whether the condition was met
+pub fn wait_until(timeout: Duration, mut condition: impl FnMut() -> bool) -> bool {
+    let timeout = Timeout::after(timeout);
+    let can_halt = !in_interrupt() && interrupts_enabled() && tick::started();
+
+    loop {
+        if condition() {
+            return true;
+        }
+        if timeout.expired() {
+            return false;
+        }
+
+        if can_halt {
+            wait_for_interrupt();
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use core::{ ptr::null_mut, sync::atomic::{ AtomicBool, Ordering }, time::Duration };
+
+use crate::{
+    sched::{ self, ThreadId },
+    sync::{ mutex::Mutex, timer::{ monotonic_ns, wheel } },
+    x86::irq::in_interrupt,
+};
+
+// Lives on the stack of the waiting thread for as long as it's linked into the queue
+struct Waiter {
+    thread: ThreadId,
+    // Set by the waker once it's done with the waiter, after that the waiter may go away
+    woken: AtomicBool,
+    prev: *mut Waiter,
+    next: *mut Waiter,
+}
+
+impl Waiter {
+    fn new() -> Self {
+        Self {
+            thread: sched::current(),
+            woken: AtomicBool::new(false),
+            prev: null_mut(),
+            next: null_mut(),
+        }
+    }
+
+    fn woken(&self) -> bool {
+        self.woken.load(Ordering::Acquire)
+    }
+}
+
+struct WaiterList {
+    head: *mut Waiter,
+    tail: *mut Waiter,
+}
+
+// The waiters are only touched with the list locked
+unsafe impl Send for WaiterList {}
+
+impl WaiterList {
+    fn push_back(&mut self, waiter: *mut Waiter) {
+        unsafe {
+            (*waiter).prev = self.tail;
+            (*waiter).next = null_mut();
+            if self.tail.is_null() {
+                self.head = waiter;
+            } else {
+                (*self.tail).next = waiter;
+            }
+        }
+        self.tail = waiter;
+    }
+
+    fn remove(&mut self, waiter: *mut Waiter) {
+        unsafe {
+            let (prev, next) = ((*waiter).prev, (*waiter).next);
+            if prev.is_null() {
+                self.head = next;
+            } else {
+                (*prev).next = next;
+            }
+            if next.is_null() {
+                self.tail = prev;
+            } else {
+                (*next).prev = prev;
+            }
+        }
+    }
+
+    // Takes the first waiter off the list and marks it woken. Returns the thread to unpark, which
+    // has to happen after the waiter has been let go of
+    fn wake_front(&mut self) -> Option<ThreadId> {
+        let waiter = self.head;
+        if waiter.is_null() {
+            return None;
+        }
+
+        self.remove(waiter);
+        unsafe {
+            let thread = (*waiter).thread;
+            (*waiter).woken.store(true, Ordering::Release);
+            Some(thread)
+        }
+    }
+}
+
+// Threads waiting for a condition to become true. Wakers change the state the condition depends
+// on and then call wake_one() or wake_all(). Waking is safe from interrupt context, waiting isn't
+pub struct WaitQueue {
+    waiters: Mutex<WaiterList>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: Mutex::new(WaiterList {
+                head: null_mut(),
+                tail: null_mut(),
+            }),
+        }
+    }
+
+    fn add(&self, waiter: &mut Waiter) {
+        self.waiters.lock().push_back(waiter);
+    }
+
+    // Takes a waiter that stopped waiting on its own off the list. If a waker got to it first the
+    // wakeup is passed on, so that it doesn't get lost on a thread that doesn't need it anymore
+    fn cancel(&self, waiter: &mut Waiter) {
+        let mut lock = self.waiters.lock();
+        if !waiter.woken() {
+            lock.remove(waiter);
+            return;
+        }
+        drop(lock);
+
+        self.wake_one();
+    }
+
+    // Blocks until `condition` returns true. The condition may have side effects, like taking a
+    // lock, since it's only ever called until it succeeds once
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        loop {
+            if condition() {
+                return;
+            }
+
+            assert!(!in_interrupt(), "WaitQueue::wait_until() would block in interrupt context");
+
+            // Checking again once we're on the list means a wakeup can't slip in between the
+            // check and going to sleep
+            let mut waiter = Waiter::new();
+            self.add(&mut waiter);
+            if condition() {
+                self.cancel(&mut waiter);
+                return;
+            }
+
+            while !waiter.woken() {
+                sched::park();
+            }
+        }
+    }
+
+    // Like wait_until(), but gives up after `timeout`. Returns whether the condition was met
+    pub fn wait_until_timeout(
+        &self,
+        timeout: Duration,
+        mut condition: impl FnMut() -> bool
+    ) -> bool {
+        let deadline_ns = monotonic_ns().saturating_add(timeout.as_nanos() as u64);
+        loop {
+            if condition() {
+                return true;
+            }
+            if monotonic_ns() >= deadline_ns {
+                return false;
+            }
+
+            assert!(
+                !in_interrupt(),
+                "WaitQueue::wait_until_timeout() would block in interrupt context"
+            );
+
+            let mut waiter = Waiter::new();
+            self.add(&mut waiter);
+            if condition() {
+                self.cancel(&mut waiter);
+                return true;
+            }
+
+            while !waiter.woken() && monotonic_ns() < deadline_ns {
+                // Armed again every round, the timer is gone once it fired and that can be a bit
+                // before the clock reaches the deadline
+                let thread = waiter.thread.to_usize();
+                let Ok(timer) = wheel::add_timer_at(deadline_ns, unpark_thread, thread) else {
+                    // Nothing would wake us up at the deadline, so keep polling for it
+                    sched::yield_now();
+                    continue;
+                };
+
+                sched::park();
+                wheel::cancel(timer);
+            }
+
+            if !waiter.woken() {
+                self.cancel(&mut waiter);
+            }
+        }
+    }
+
+    // Returns whether there was anyone to wake up
+    pub fn wake_one(&self) -> bool {
+        let thread = self.waiters.lock().wake_front();
+        if let Some(thread) = thread {
+            sched::unpark(thread);
+        }
+
+        thread.is_some()
+    }
+
+    // Returns the number of threads that were woken up. Holds the lock throughout, so that threads
+    // which start waiting again right away don't get woken twice
+    pub fn wake_all(&self) -> usize {
+        let mut lock = self.waiters.lock();
+        let mut count = 0;
+        while let Some(thread) = lock.wake_front() {
+            sched::unpark(thread);
+            count += 1;
+        }
+
+        count
+    }
+
+    pub fn has_waiters(&self) -> bool {
+        !self.waiters.lock().head.is_null()
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn unpark_thread(ctx: usize) {
+    sched::unpark(ThreadId::from_usize(ctx));
+}
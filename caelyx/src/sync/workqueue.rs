@@ -0,0 +1,135 @@
+use alloc::{ boxed::Box, collections::vec_deque::VecDeque };
+use core::sync::atomic::{ AtomicUsize, Ordering };
+
+use crate::{
+    sched::{ self, Priority, ThreadId },
+    sync::{ mutex::Mutex, waitqueue::WaitQueue },
+    x86::irq::in_interrupt,
+};
+
+pub type Work = Box<dyn FnOnce() + Send>;
+
+// Only the worker and whoever waits for completion ever run work, so there aren't many at once
+const MAX_RUNNERS: usize = 8;
+
+static QUEUE: Mutex<VecDeque<Work>> = Mutex::named("workqueue", VecDeque::new());
+// Number of work items that were taken off the queue but haven't finished yet
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+static WORKER: Mutex<Option<ThreadId>> = Mutex::new(None);
+// Threads that are inside run_pending() right now, and so may be in the middle of a work item
+static RUNNERS: Mutex<[Option<ThreadId>; MAX_RUNNERS]> = Mutex::new([None; MAX_RUNNERS]);
+// Woken whenever RUNNING drops to 0
+static COMPLETION: WaitQueue = WaitQueue::new();
+
+// Queues a closure to be run by the worker outside of interrupt context. Safe to call from
+// interrupt handlers
+pub fn queue(work: impl FnOnce() + Send + 'static) {
+    QUEUE.lock().push_back(Box::new(work));
+
+    if let Some(worker) = *WORKER.lock() {
+        sched::unpark(worker);
+    }
+}
+
+pub fn has_pending() -> bool {
+    !QUEUE.lock().is_empty()
+}
+
+fn is_runner(thread: ThreadId) -> bool {
+    RUNNERS.lock().contains(&Some(thread))
+}
+
+// Returns whether the current thread got added, which it doesn't if it's in there already because
+// run_pending() got called from inside a work item
+fn add_runner() -> bool {
+    let thread = sched::current();
+    let mut lock = RUNNERS.lock();
+    if lock.contains(&Some(thread)) {
+        return false;
+    }
+
+    let slot = lock.iter_mut().find(|x| x.is_none()).expect("Too many threads running work");
+    *slot = Some(thread);
+    true
+}
+
+fn remove_runner() {
+    let thread = sched::current();
+    for slot in RUNNERS.lock().iter_mut() {
+        if *slot == Some(thread) {
+            *slot = None;
+        }
+    }
+}
+
+// Runs everything that is currently queued, including work queued by the work itself. Returns the
+// number of items that were run
+pub fn run_pending() -> usize {
+    assert!(!in_interrupt(), "workqueue::run_pending() called from interrupt context");
+
+    let added = add_runner();
+    let count = run_queued();
+    if added {
+        remove_runner();
+    }
+
+    count
+}
+
+fn run_queued() -> usize {
+    let mut count = 0;
+    loop {
+        // Take the item off the queue and bump RUNNING under the same lock so wait_for_completion()
+        // never sees an empty queue while an item is in flight
+        let work = {
+            let mut lock = QUEUE.lock();
+            let work = lock.pop_front();
+            if work.is_some() {
+                RUNNING.fetch_add(1, Ordering::AcqRel);
+            }
+            work
+        };
+
+        let Some(work) = work else {
+            break count;
+        };
+
+        work();
+        if RUNNING.fetch_sub(1, Ordering::AcqRel) == 1 {
+            COMPLETION.wake_all();
+        }
+        count += 1;
+    }
+}
+
+// Returns once the queue is empty and the worker is done with what it took off it. Work that is
+// running while this is called from inside another work item can't finish before we return, so
+// only the queue is drained in that case. That goes for the worker as well as for anyone else that
+// ended up in here through run_pending()
+pub fn wait_for_completion() {
+    run_pending();
+
+    if is_runner(sched::current()) {
+        return;
+    }
+
+    COMPLETION.wait_until(|| RUNNING.load(Ordering::Acquire) == 0);
+}
+
+// The worker loop: runs queued work and parks when there is nothing left to do
+fn run_worker() {
+    loop {
+        run_pending();
+
+        if !has_pending() {
+            sched::park();
+        }
+    }
+}
+
+// Starts the worker thread. Until then work only runs when someone calls run_pending()
+pub fn init() {
+    let worker = sched::spawn("workqueue", Priority::Normal, run_worker)
+        .expect("Could not spawn the work queue worker");
+    *WORKER.lock() = Some(worker.id());
+}
@@ -0,0 +1,194 @@
+use core::{ sync::atomic::{ AtomicBool, Ordering }, time::Duration };
+
+use crate::{
+    debug,
+    info,
+    misc::output::raw_print::print_fmt,
+    sched::{ self, user },
+    x86::{
+        cpuid::{ Features, feature_present },
+        gdt::GDT_CODE,
+        idt::{ self, interrupt_control::{ disable_interrupts, enable_interrupts } },
+        irq,
+        msr::{ IA32_SYSENTER_CS, IA32_SYSENTER_EIP, IA32_SYSENTER_ESP, wrmsr },
+    },
+};
+
+pub mod usercopy;
+
+// int 0x80 works everywhere, sysenter only where cpuid reports SEP. Both take the number in eax
+// and up to four arguments in ebx, esi, edi and ebp, and return the result in eax. sysenter also
+// takes the address to return to in edx and the stack pointer in ecx, so those are clobbered
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+pub const SYS_EXIT: u32 = 0;
+pub const SYS_WRITE: u32 = 1;
+pub const SYS_GETPID: u32 = 2;
+pub const SYS_SLEEP: u32 = 3;
+pub const SYS_MMAP: u32 = 4;
+const SYSCALL_COUNT: usize = 5;
+
+// Returned negated in eax. The numbers are the same as Linux', so that they look familiar
+#[derive(Debug, Clone, Copy)]
+pub enum SyscallError {
+    BadFd = 9,
+    NoMemory = 12,
+    Fault = 14,
+    Invalid = 22,
+    NoSys = 38,
+}
+
+// The user's registers the way pushad leaves them, both ways in pass them like this
+#[repr(C)]
+pub struct SyscallRegs {
+    pub edi: u32,
+    pub esi: u32,
+    pub ebp: u32,
+    pub esp: u32,
+    pub ebx: u32,
+    pub edx: u32,
+    pub ecx: u32,
+    pub eax: u32,
+}
+
+type SyscallResult = Result<u32, SyscallError>;
+type SyscallHandler = fn([u32; 4]) -> SyscallResult;
+
+// Indexed by the syscall number
+static SYSCALLS: [SyscallHandler; SYSCALL_COUNT] = {
+    let mut table: [SyscallHandler; SYSCALL_COUNT] = [sys_nosys; SYSCALL_COUNT];
+    table[SYS_EXIT as usize] = sys_exit;
+    table[SYS_WRITE as usize] = sys_write;
+    table[SYS_GETPID as usize] = sys_getpid;
+    table[SYS_SLEEP as usize] = sys_sleep;
+    table[SYS_MMAP as usize] = sys_mmap;
+    table
+};
+
+static SYSENTER: AtomicBool = AtomicBool::new(false);
+
+unsafe extern "C" {
+    fn sysenter_entry();
+    static sysenter_flags_cleared: u8;
+}
+
+fn sys_nosys(_args: [u32; 4]) -> SyscallResult {
+    Err(SyscallError::NoSys)
+}
+
+// exit(code)
+fn sys_exit([code, ..]: [u32; 4]) -> SyscallResult {
+    debug!("User thread {} exited with {}", sched::current_name(), code as i32);
+    sched::exit();
+}
+
+// write(fd, buf, len). Only stdout and stderr for now, which both go wherever kernel output goes
+fn sys_write([fd, buf, len, _]: [u32; 4]) -> SyscallResult {
+    if fd != 1 && fd != 2 {
+        return Err(SyscallError::BadFd);
+    }
+    usercopy::check_range(buf, len as usize)?;
+
+    let mut chunk = [0u8; 256];
+    let mut done = 0;
+    while done < len {
+        let count = ((len - done) as usize).min(chunk.len());
+        usercopy::copy_from_user(&mut chunk[..count], buf + done)?;
+
+        // A character split between two chunks comes out as two replacement characters
+        for part in chunk[..count].utf8_chunks() {
+            print_fmt(format_args!("{}", part.valid()));
+            if !part.invalid().is_empty() {
+                print_fmt(format_args!("\u{fffd}"));
+            }
+        }
+
+        done += count as u32;
+    }
+
+    Ok(len)
+}
+
+// getpid(), the thread ID for now since there are no processes
+fn sys_getpid(_args: [u32; 4]) -> SyscallResult {
+    Ok(sched::current().to_usize() as u32)
+}
+
+// sleep(ms)
+fn sys_sleep([ms, ..]: [u32; 4]) -> SyscallResult {
+    sched::sleep(Duration::from_millis(ms as u64));
+    Ok(0)
+}
+
+// mmap(len), maps zeroed read-write memory and returns its address
+fn sys_mmap([len, ..]: [u32; 4]) -> SyscallResult {
+    if len == 0 {
+        return Err(SyscallError::Invalid);
+    }
+
+    user::map_anonymous(len.div_ceil(4096) as usize).ok_or(SyscallError::NoMemory)
+}
+
+pub fn dispatch(regs: &mut SyscallRegs) {
+    // Both ways in leave interrupts off, but a syscall may block
+    enable_interrupts();
+
+    let args = [regs.ebx, regs.esi, regs.edi, regs.ebp];
+    let result = match SYSCALLS.get(regs.eax as usize) {
+        Some(handler) => handler(args),
+        None => Err(SyscallError::NoSys),
+    };
+    regs.eax = match result {
+        Ok(val) => val,
+        Err(err) => (-(err as i32)) as u32,
+    };
+
+    // The way back out has to switch gs back to the user's first
+    disable_interrupts();
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn caelyx_sysenter(regs: *mut SyscallRegs) {
+    dispatch(unsafe { &mut *regs });
+}
+
+fn init_sysenter() {
+    wrmsr(IA32_SYSENTER_CS, GDT_CODE as u64);
+    wrmsr(IA32_SYSENTER_EIP, (sysenter_entry as *const () as u32) as u64);
+    // Set to the running thread's kernel stack on every switch
+    wrmsr(IA32_SYSENTER_ESP, 0);
+}
+
+// Whether a #DB at `eip` comes from a user that was single-stepping into sysenter, which keeps
+// trapping in the kernel until sysenter_entry cleared TF. The last one comes right after that
+pub fn stepping_into_sysenter(eip: u32) -> bool {
+    let start = sysenter_entry as *const () as u32;
+    let end = &raw const sysenter_flags_cleared as u32;
+    (start..=end).contains(&eip)
+}
+
+// sysenter doesn't look at the TSS, so it gets told about the kernel stack separately
+pub fn set_kernel_stack(top: u32) {
+    if SYSENTER.load(Ordering::Relaxed) {
+        wrmsr(IA32_SYSENTER_ESP, top as u64);
+    }
+}
+
+pub fn init() {
+    irq::reserve_vector(SYSCALL_VECTOR).expect("Syscall vector is already taken");
+    idt::allow_from_user(SYSCALL_VECTOR);
+
+    let sysenter = feature_present(&Features::Sep);
+    if sysenter {
+        init_sysenter();
+        SYSENTER.store(true, Ordering::Release);
+    }
+
+    info!("Initialized syscalls (int {SYSCALL_VECTOR:#x}, sysenter: {sysenter})");
+}
+
+pub fn init_ap() {
+    if SYSENTER.load(Ordering::Acquire) {
+        init_sysenter();
+    }
+}
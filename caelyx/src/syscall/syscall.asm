@@ -0,0 +1,92 @@
+; The sysenter way into the kernel, and the copy routine that's allowed to fault on user memory
+
+%include "gdt.inc"
+
+EFLAGS_RESERVED equ 1 << 1
+EFLAGS_TF equ 1 << 8
+EFLAGS_IF equ 1 << 9
+
+section .text
+
+extern caelyx_sysenter
+
+global sysenter_entry
+global sysenter_flags_cleared
+global copy_user
+global copy_user_start
+global copy_user_end
+global copy_user_fixup
+
+; sysenter gets here with interrupts off, the kernel's cs and ss and the stack from
+; IA32_SYSENTER_ESP, which the scheduler keeps pointed at the running thread's kernel stack.
+; The user passes the address to return to in edx and its stack pointer in ecx, which is where
+; sysexit takes them from again after popad restored them.
+;
+; sysenter leaves TF alone, so a user that single-steps into here gets a #DB after every
+; instruction until the flags are cleared. The handler ignores those, and they can't come from
+; anywhere past sysenter_flags_cleared
+sysenter_entry:
+    ; The user's flags, for the way out
+    pushfd
+    push dword EFLAGS_RESERVED
+    popfd
+sysenter_flags_cleared:
+    pushad
+    push ds
+    push es
+    push fs
+    push gs
+
+    mov ax, GDT_DATA
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    ; Same as in isr_common, the TSS is the only thing that tells us which CPU this is
+    str ax
+    sub ax, TSS_TO_PERCPU
+    mov gs, ax
+
+    ; The registers pushad saved, laid out like SyscallRegs
+    lea eax, [esp + 16]
+    push eax
+    call caelyx_sysenter
+    add esp, 4
+
+    pop gs
+    pop fs
+    pop es
+    pop ds
+    popad
+
+    ; The user's flags, but IF comes back through sti. sti only takes effect after the next
+    ; instruction, so nothing can come in between while gs is the user's already. TF stays off,
+    ; single-stepping through a syscall isn't supported
+    and dword [esp], ~(EFLAGS_IF | EFLAGS_TF)
+    popfd
+    sti
+    sysexit
+
+; uint32_t copy_user(void *dst, const void *src, size_t len)
+;
+; memcpy for when either side is user memory. Returns 0, or 1 if it faulted. A fault on the
+; rep movsb doesn't take the kernel down, the fault handler continues at copy_user_fixup instead
+copy_user:
+    push esi
+    push edi
+    mov edi, [esp + 12]
+    mov esi, [esp + 16]
+    mov ecx, [esp + 20]
+    cld
+copy_user_start:
+    rep movsb
+copy_user_end:
+    xor eax, eax
+    pop edi
+    pop esi
+    ret
+
+copy_user_fixup:
+    mov eax, 1
+    pop edi
+    pop esi
+    ret
@@ -0,0 +1,44 @@
+use crate::{ mm::vmm::{ USER_END, USER_START }, syscall::SyscallError };
+
+unsafe extern "C" {
+    fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> u32;
+    static copy_user_start: u8;
+    static copy_user_end: u8;
+    static copy_user_fixup: u8;
+}
+
+// Whether all of `addr` up to `addr + len` lies in the user part of the address space. Whether
+// it's mapped is only found out while copying
+pub fn check_range(addr: u32, len: usize) -> Result<(), SyscallError> {
+    let end = addr.checked_add(len.try_into().map_err(|_| SyscallError::Fault)?);
+    match end {
+        Some(end) if addr >= USER_START && end <= USER_END => Ok(()),
+        _ => Err(SyscallError::Fault),
+    }
+}
+
+pub fn copy_from_user(dst: &mut [u8], src: u32) -> Result<(), SyscallError> {
+    check_range(src, dst.len())?;
+    match unsafe { copy_user(dst.as_mut_ptr(), src as *const u8, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(SyscallError::Fault),
+    }
+}
+
+// Nothing hands data back to ring 3 yet
+#[allow(unused)]
+pub fn copy_to_user(dst: u32, src: &[u8]) -> Result<(), SyscallError> {
+    check_range(dst, src.len())?;
+    match unsafe { copy_user(dst as *mut u8, src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(SyscallError::Fault),
+    }
+}
+
+// Where to continue after a fault in the kernel at `eip`, if it happened while copying from or to
+// user memory
+pub fn fixup(eip: u32) -> Option<u32> {
+    let start = &raw const copy_user_start as u32;
+    let end = &raw const copy_user_end as u32;
+    (start..end).contains(&eip).then_some(&raw const copy_user_fixup as u32)
+}
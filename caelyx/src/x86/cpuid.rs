@@ -1,6 +1,6 @@
 use alloc::{ format, string::{ String }, vec::Vec };
 
-use crate::{ debug, misc::isituninit::IsItUninit, sync::mutex::Mutex };
+use crate::{ debug, sync::once::Once };
 
 #[repr(u32)]
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -104,13 +104,10 @@ impl Vendor {
     }
 }
 
-static CPUID_SUPPORTED: Mutex<IsItUninit<bool>> = Mutex::new(IsItUninit::uninit());
+static CPUID_SUPPORTED: Once<bool> = Once::new();
 
 fn check_for_cpuid() -> bool {
-    let lock = CPUID_SUPPORTED.lock();
-    if let Some(supported) = lock.try_get_ref() {
-        *supported
-    } else {
+    *CPUID_SUPPORTED.call_once(|| {
         unsafe {
             // Test for CPUID support. We can do this by changing the ID bit in EFLAGS and if it
             // actually changes then it's supported
@@ -132,7 +129,7 @@ fn check_for_cpuid() -> bool {
 
             supported != 0
         }
-    }
+    })
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
@@ -166,12 +163,14 @@ fn cpuid(gp: CpuidGp, gp_out: &mut CpuidGp) {
     _cpuid(gp, gp_out, false, false);
 }
 
-fn ensure_cpuid_leaf_supported(leaf: u32) {
+// Returns the highest supported leaf of the range `leaf` is in. Extended leaves start at
+// 0x80000000 and are enumerated separately from the basic ones
+fn max_leaf(leaf: u32) -> u32 {
     let mut out = CpuidGp::default();
 
     _cpuid(
         CpuidGp {
-            eax: 0,
+            eax: leaf & 0x80000000,
             ebx: 0,
             ecx: 0,
             edx: 0,
@@ -181,9 +180,59 @@ fn ensure_cpuid_leaf_supported(leaf: u32) {
         true
     );
 
-    if out.eax < leaf {
-        panic!("CPUID Leaf {leaf} requested but it's not supported on this machine!");
+    out.eax
+}
+
+fn ensure_cpuid_leaf_supported(leaf: u32) {
+    if !leaf_supported(leaf) {
+        panic!("CPUID Leaf {leaf:#x} requested but it's not supported on this machine!");
+    }
+}
+
+pub fn leaf_supported(leaf: u32) -> bool {
+    max_leaf(leaf) >= leaf
+}
+
+// Queries a leaf (and subleaf), returning None if the CPU doesn't have it
+pub fn cpuid_leaf(leaf: u32, subleaf: u32) -> Option<CpuidGp> {
+    if !check_for_cpuid() || !leaf_supported(leaf) {
+        return None;
+    }
+
+    let mut out = CpuidGp::default();
+    cpuid(
+        CpuidGp {
+            eax: leaf,
+            ebx: 0,
+            ecx: subleaf,
+            edx: 0,
+        },
+        &mut out
+    );
+
+    Some(out)
+}
+
+// An invariant TSC runs at a constant rate in all P-, C- and T-states
+pub fn invariant_tsc() -> bool {
+    cpuid_leaf(0x80000007, 0).is_some_and(|x| (x.edx & (1 << 8)) != 0)
+}
+
+// Whether the LAPIC timer can be armed with an absolute TSC value
+pub fn tsc_deadline_supported() -> bool {
+    cpuid_leaf(1, 0).is_some_and(|x| (x.ecx & (1 << 24)) != 0)
+}
+
+// The TSC frequency as enumerated by leaf 0x15, which only newer Intel CPUs fill in completely
+pub fn tsc_frequency() -> Option<u64> {
+    let leaf = cpuid_leaf(0x15, 0)?;
+
+    // EAX and EBX are the TSC/crystal ratio, ECX is the crystal frequency in Hz
+    if leaf.eax == 0 || leaf.ebx == 0 || leaf.ecx == 0 {
+        return None;
     }
+
+    Some(((leaf.ecx as u64) * (leaf.ebx as u64)) / (leaf.eax as u64))
 }
 
 pub fn get_vendor() -> Vendor {
@@ -1,7 +1,17 @@
-use crate::{ debug, info, sync::mutex::Mutex, trace };
+use crate::{ debug, info, sync::mutex::Mutex, trace, x86::percpu::MAX_CPUS };
 
-// This is just a minimal GDT i sticked together 50 decades ago
-static GDT: [u64; 3] = [0x0000000000000000, 0x00cf9a000000ffff, 0x00cf92000000ffff];
+// The flat segments are just a minimal GDT i sticked together 50 decades ago, the kernel's and then
+// the same for ring 3. After them comes one data segment per CPU, whose base is that CPU's per-CPU
+// block so that it can be reached through gs, and then every CPU's TSS
+const GDT_ENTRIES: usize = 5 + 2 * MAX_CPUS;
+static GDT: Mutex<[u64; GDT_ENTRIES]> = Mutex::new({
+    let mut gdt = [0; GDT_ENTRIES];
+    gdt[1] = 0x00cf9a000000ffff;
+    gdt[2] = 0x00cf92000000ffff;
+    gdt[3] = 0x00cffa000000ffff;
+    gdt[4] = 0x00cff2000000ffff;
+    gdt
+});
 #[allow(clippy::erasing_op)]
 // The byte index of the null descriptor
 pub const GDT_NULL: u16 = 0 * (core::mem::size_of::<u64>() as u16);
@@ -10,6 +20,30 @@ pub const GDT_NULL: u16 = 0 * (core::mem::size_of::<u64>() as u16);
 pub const GDT_CODE: u16 = 1 * (core::mem::size_of::<u64>() as u16);
 // The byte index of the data descriptor
 pub const GDT_DATA: u16 = 2 * (core::mem::size_of::<u64>() as u16);
+// sysexit takes the user segments from where the kernel code segment is, they have to come right
+// after the kernel's in this order
+// The byte index of the user code descriptor
+pub const GDT_USER_CODE: u16 = 3 * (core::mem::size_of::<u64>() as u16);
+// The byte index of the user data descriptor
+pub const GDT_USER_DATA: u16 = 4 * (core::mem::size_of::<u64>() as u16);
+// The byte index of the per-CPU descriptor of CPU 0, the others follow it
+pub const GDT_PERCPU: u16 = 5 * (core::mem::size_of::<u64>() as u16);
+// The byte index of the TSS descriptor of CPU 0, the others follow it. The ISR stubs rely on it
+// coming right after the per-CPU descriptors
+pub const GDT_TSS: u16 = GDT_PERCPU + (MAX_CPUS as u16) * (core::mem::size_of::<u64>() as u16);
+
+// gdt.inc spells the layout out for the assembly, with MAX_CPUS written down as a number
+const _: () = assert!(MAX_CPUS == 16, "MAX_CPUS changed, gdt.inc has to be updated to match");
+
+// Selectors for ring 3 have to ask for it in their lowest two bits
+pub const RPL_USER: u16 = 3;
+
+// Present, ring 0, writable data
+const ACCESS_DATA: u8 = 0x92;
+// Present, ring 0, available 32-bit TSS
+const ACCESS_TSS: u8 = 0x89;
+// 32-bit, byte granular limit
+const FLAGS_32BIT: u8 = 0x4;
 
 #[repr(C, packed)]
 #[derive(Clone)]
@@ -25,8 +59,8 @@ pub fn init() {
     let gdt_ptr;
     {
         let mut lock = GDTR.lock();
-        lock.base = &raw const GDT as u32;
-        lock.limit = (core::mem::size_of_val(&GDT) - 1) as u16;
+        lock.base = GDT.lock().as_ptr() as u32;
+        lock.limit = (core::mem::size_of::<[u64; GDT_ENTRIES]>() - 1) as u16;
         gdt_ptr = &raw const *lock;
     }
 
@@ -57,3 +91,34 @@ pub fn init() {
     debug!("Loaded GDTR & Reloaded segments");
     info!("Initialized GDT");
 }
+
+const fn descriptor(base: u32, limit: u32, access: u8, flags: u8) -> u64 {
+    ((limit as u64) & 0xffff) |
+        (((base as u64) & 0xffffff) << 16) |
+        ((access as u64) << 40) |
+        ((((limit >> 16) as u64) & 0xf) << 48) |
+        (((flags as u64) & 0xf) << 52) |
+        (((base >> 24) as u64) << 56)
+}
+
+pub const fn percpu_selector(cpu: usize) -> u16 {
+    GDT_PERCPU + (cpu as u16) * (core::mem::size_of::<u64>() as u16)
+}
+
+// Points the per-CPU segment of `cpu` at `size` bytes from `base`. The GDT is shared, so this only
+// has to be done once per CPU, before it loads the selector into gs
+pub fn set_percpu_segment(cpu: usize, base: u32, size: u32) {
+    assert!(cpu < MAX_CPUS, "CPU {cpu} is out of range for the GDT");
+    let idx = (percpu_selector(cpu) as usize) / core::mem::size_of::<u64>();
+    GDT.lock()[idx] = descriptor(base, size - 1, ACCESS_DATA, FLAGS_32BIT);
+}
+
+pub const fn tss_selector(cpu: usize) -> u16 {
+    GDT_TSS + (cpu as u16) * (core::mem::size_of::<u64>() as u16)
+}
+
+pub fn set_tss(cpu: usize, base: u32, size: u32) {
+    assert!(cpu < MAX_CPUS, "CPU {cpu} is out of range for the GDT");
+    let idx = (tss_selector(cpu) as usize) / core::mem::size_of::<u64>();
+    GDT.lock()[idx] = descriptor(base, size - 1, ACCESS_TSS, 0);
+}
@@ -1,5 +1,7 @@
 section .text
 
+%include "gdt.inc"
+
 %macro isr_err_stub 1
 isr%+%1%+_handler:
     cld
@@ -21,7 +23,27 @@ isr%+%1%+_handler:
 
 isr_common:
     pushad
+    push ds
+    push es
+    push fs
+    push gs
+
+    ; Coming from ring 3 only ss and esp got switched by the CPU, everything else is still the
+    ; user's. That goes for the kernel too when it's interrupted on its way in or out, like a #DB
+    ; in sysenter_entry, so the segments get set up no matter where we came from. Nothing in the
+    ; kernel works without gs pointing at the per-CPU data, and the only thing that tells us
+    ; which CPU this is is the TSS it loaded. Before there is one gs is still the boot CPU's
+    mov ax, GDT_DATA
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    str ax
+    test ax, ax
+    jz .no_tss
+    sub ax, TSS_TO_PERCPU
+    mov gs, ax
 
+.no_tss:
     mov eax, cr0
     push eax
 
@@ -52,6 +74,10 @@ isr_common:
     pop eax
     mov cr0, eax
 
+    pop gs
+    pop fs
+    pop es
+    pop ds
     popad
     
     add esp, 8
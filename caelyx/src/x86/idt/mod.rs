@@ -1,10 +1,16 @@
 use crate::misc::output::raw_print::print_line_ending;
-use crate::x86::gdt::GDT_CODE;
-use crate::x86::halt;
-use crate::{ debug, fatal, info, sync::mutex::Mutex, trace, x86::gdt::SharedGdtrAndIdtr };
+use crate::x86::gdt::{ GDT_CODE, RPL_USER };
+use crate::x86::{ halt, irq };
+use crate::{ debug, fatal, info, sched, sync::mutex::Mutex, trace, warning };
+use crate::syscall::{ self, SYSCALL_VECTOR, SyscallRegs, usercopy };
+use crate::x86::gdt::SharedGdtrAndIdtr;
 use core::ptr::read_unaligned;
 
 pub mod interrupt_control {
+    use core::{ marker::PhantomData, sync::atomic::Ordering };
+
+    use crate::{ sync::mutex::NegativeSendAndSync, x86::percpu };
+
     pub fn disable_interrupts() {
         unsafe {
             // On x86, we use the cli instruction to disable interrupts. It stands for (Cl)ear
@@ -61,6 +67,71 @@ pub mod interrupt_control {
         // The 9th bit in the eflags register is the interrupt enable flag.
         (eflags & (1 << 9)) != 0
     }
+
+    // Keeps interrupts disabled for as long as it lives. Every CPU counts the guards that are alive
+    // and remembers whether the outermost one found interrupts enabled. Only the last guard to go
+    // away turns them back on, whatever order the guards are dropped in
+    pub struct IrqGuard {
+        traits: PhantomData<NegativeSendAndSync>,
+    }
+
+    impl IrqGuard {
+        pub fn new() -> Self {
+            let were_enabled = interrupts_enabled();
+            disable_interrupts();
+            let cpu = percpu::cpu();
+            if cpu.irq_depth.fetch_add(1, Ordering::Relaxed) == 0 {
+                cpu.irq_restore.store(were_enabled, Ordering::Relaxed);
+            }
+
+            Self { traits: PhantomData }
+        }
+    }
+
+    impl Default for IrqGuard {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Drop for IrqGuard {
+        fn drop(&mut self) {
+            let cpu = percpu::cpu();
+            if
+                cpu.irq_depth.fetch_sub(1, Ordering::Relaxed) == 1 &&
+                cpu.irq_restore.load(Ordering::Relaxed)
+            {
+                enable_interrupts();
+            }
+        }
+    }
+
+    pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+        let _guard = IrqGuard::new();
+        f()
+    }
+
+    // The nesting state of a thread that got switched away from
+    pub struct IrqNesting {
+        depth: u32,
+        restore: bool,
+    }
+
+    // The nesting belongs to the running thread, so the scheduler takes it along across a switch.
+    // Leaves a clean slate for the next thread, which restores its own once it runs again
+    pub fn take_nesting() -> IrqNesting {
+        let cpu = percpu::cpu();
+        IrqNesting {
+            depth: cpu.irq_depth.swap(0, Ordering::Relaxed),
+            restore: cpu.irq_restore.swap(false, Ordering::Relaxed),
+        }
+    }
+
+    pub fn restore_nesting(nesting: IrqNesting) {
+        let cpu = percpu::cpu();
+        cpu.irq_depth.store(nesting.depth, Ordering::Relaxed);
+        cpu.irq_restore.store(nesting.restore, Ordering::Relaxed);
+    }
 }
 
 static IDTR: Mutex<SharedGdtrAndIdtr> = Mutex::new(SharedGdtrAndIdtr { limit: 0, base: 0 });
@@ -99,6 +170,10 @@ pub struct ISRFrame {
     cr3: u32,
     cr2: u32,
     cr0: u32,
+    gs: u32,
+    fs: u32,
+    es: u32,
+    ds: u32,
     edi: u32,
     esi: u32,
     ebp: u32,
@@ -114,10 +189,84 @@ pub struct ISRFrame {
     eflags: u32,
 }
 
+fn exception_name(int_no: u32) -> &'static str {
+    match int_no {
+        0 => "DIVISION ERROR",
+        1 => "DEBUG",
+        2 => "NON MASKABLE INTERRUPT",
+        3 => "BREAKPOINT",
+        4 => "OVERFLOW",
+        5 => "BOUND RANGE EXCEEDED",
+        6 => "INVALID OPCODE",
+        7 => "DEVICE NOT AVAILABLE",
+        8 => "DOUBLE FAULT",
+        9 => "COPROCESSOR SEGMENT OVERRUN",
+        10 => "INVALID TSS",
+        11 => "SEGMENT NOT PRESENT",
+        12 => "STACK SEGMENT FAULT",
+        13 => "GENERAL PROTECTION FAULT",
+        14 => "PAGE FAULT",
+        16 => "FLOATING POINT EXCEPTION",
+        17 => "ALIGNMENT CHECK",
+        18 => "MACHINE CHECK",
+        19 => "SIMD FLOATING POINT EXCEPTION",
+        20 => "VIRTUALIZATION EXCEPTION",
+        21 => "CONTROL PROTECTION EXCEPTION",
+        28 => "HYPERVISOR INJECTION EXCEPTION",
+        29 => "VMM COMMUNICATION EXCEPTION",
+        30 => "SECURITY EXCEPTION",
+        _ => "UNKNOWN EXCEPTION",
+    }
+}
+
+// A user thread that faults only takes itself down. Only returns if the fault came from the kernel
+// or wasn't the thread's doing, like an NMI or a machine check, which go down the fatal path
+fn kill_faulting_user_thread(frame: &ISRFrame) {
+    let cs = unsafe { read_unaligned(&raw const frame.cs) };
+    if (cs & 3) != (RPL_USER as u32) {
+        return;
+    }
+    if !matches!(frame.int_no, 0 | 1 | 3..=7 | 10..=14 | 16 | 17 | 19) {
+        return;
+    }
+
+    warning!(
+        "Killing user thread {} after {} at EIP={:#010X} (error {:#X}, CR2={:#010X})",
+        sched::current_name(),
+        exception_name(frame.int_no),
+        unsafe {
+            read_unaligned(&raw const frame.eip)
+        },
+        unsafe {
+            read_unaligned(&raw const frame.err_no)
+        },
+        unsafe {
+            read_unaligned(&raw const frame.cr2)
+        }
+    );
+    sched::exit();
+}
+
 #[unsafe(no_mangle)]
-extern "C" fn isr_general_handler(frame: *const ISRFrame) {
+extern "C" fn isr_general_handler(frame: *mut ISRFrame) {
     let isr_frame: &'static ISRFrame = unsafe { &*frame };
     if isr_frame.int_no < 32 {
+        kill_faulting_user_thread(isr_frame);
+
+        // TF survives sysenter until sysenter_entry clears it
+        let eip = unsafe { read_unaligned(&raw const isr_frame.eip) };
+        if isr_frame.int_no == 1 && syscall::stepping_into_sysenter(eip) {
+            return;
+        }
+
+        // Copying from or to user memory is allowed to fault, it just fails
+        if matches!(isr_frame.int_no, 13 | 14) && let Some(fixup) = usercopy::fixup(eip) {
+            unsafe {
+                (&raw mut (*frame).eip).write_unaligned(fixup);
+            }
+            return;
+        }
+
         print_line_ending();
         fatal!(r" -------------           -------------    ");
         fatal!(r"/             \          /             \  ");
@@ -132,33 +281,7 @@ extern "C" fn isr_general_handler(frame: *const ISRFrame) {
         fatal!(r" /                                     \  ");
         print_line_ending();
 
-        fatal!("{}", match isr_frame.int_no {
-            0 => "DIVISION ERROR",
-            1 => "DEBUG",
-            2 => "NON MASKABLE INTERRUPT",
-            3 => "BREAKPOINT",
-            4 => "OVERFLOW",
-            5 => "BOUND RANGE EXCEEDED",
-            6 => "INVALID OPCODE",
-            7 => "DEVICE NOT AVAILABLE",
-            8 => "DOUBLE FAULT",
-            9 => "COPROCESSOR SEGMENT OVERRUN",
-            10 => "INVALID TSS",
-            11 => "SEGMENT NOT PRESENT",
-            12 => "STACK SEGMENT FAULT",
-            13 => "GENERAL PROTECTION FAULT",
-            14 => "PAGE FAULT",
-            16 => "FLOATING POINT EXCEPTION",
-            17 => "ALIGNMENT CHECK",
-            18 => "MACHINE CHECK",
-            19 => "SIMD FLOATING POINT EXCEPTION",
-            20 => "VIRTUALIZATION EXCEPTION",
-            21 => "CONTROL PROTECTION EXCEPTION",
-            28 => "HYPERVISOR INJECTION EXCEPTION",
-            29 => "VMM COMMUNICATION EXCEPTION",
-            30 => "SECURITY EXCEPTION",
-            _ => "UNKNOWN EXCEPTION",
-        });
+        fatal!("{}", exception_name(isr_frame.int_no));
 
         fatal!(
             "EAX ={:#010X} EBX ={:#010X} ECX    ={:#010X} EDX={:#010X}",
@@ -208,6 +331,22 @@ extern "C" fn isr_general_handler(frame: *const ISRFrame) {
             }
         );
 
+        fatal!(
+            "DS  ={:#010X} ES  ={:#010X} FS     ={:#010X} GS ={:#010X}",
+            unsafe {
+                read_unaligned(&raw const isr_frame.ds) & 0xffff
+            },
+            unsafe {
+                read_unaligned(&raw const isr_frame.es) & 0xffff
+            },
+            unsafe {
+                read_unaligned(&raw const isr_frame.fs) & 0xffff
+            },
+            unsafe {
+                read_unaligned(&raw const isr_frame.gs) & 0xffff
+            }
+        );
+
         fatal!(
             "CR2 ={:#010X} CR3 ={:#010X} CR4    ={:#010X}",
             unsafe {
@@ -225,6 +364,11 @@ extern "C" fn isr_general_handler(frame: *const ISRFrame) {
         loop {
             halt();
         }
+    } else if isr_frame.int_no == (SYSCALL_VECTOR as u32) {
+        // The GP registers in the frame are laid out the same way
+        syscall::dispatch(unsafe { &mut *(&raw mut (*frame).edi).cast::<SyscallRegs>() });
+    } else {
+        irq::dispatch(isr_frame.int_no as u8);
     }
 }
 
@@ -232,6 +376,11 @@ pub fn set_interrupt_gate(gate: InterruptGate, idx: u8) {
     ISR_GATES.lock()[idx as usize] = gate.to_u64();
 }
 
+// Lets ring 3 raise `idx` with int, every gate only lets the kernel do that by default
+pub fn allow_from_user(idx: u8) {
+    ISR_GATES.lock()[idx as usize] |= 3 << 45;
+}
+
 pub fn load_idt() {
     unsafe {
         core::arch::asm!("lidt [{idt_reg:e}]", idt_reg = in(reg) &raw const *IDTR.lock());
@@ -0,0 +1,141 @@
+use alloc::vec::Vec;
+
+use crate::{
+    debug,
+    info,
+    misc::acpi,
+    mm::{ virt_page_alloc, vmm },
+    sync::mutex::Mutex,
+    trace,
+};
+
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+
+pub struct IoApic {
+    id: u8,
+    address: usize,
+    gsi_base: u32,
+    redirection_count: u32,
+}
+
+impl IoApic {
+    pub fn new(id: u8, phys_address: u32, gsi_base: u32) -> Self {
+        let virt_page = virt_page_alloc
+            ::allocate(1)
+            .expect("Could not allocate virtual page to map IOAPIC MMIO region");
+
+        vmm::map(phys_address, virt_page, false, true, true, true);
+        trace!("Mapped IOAPIC MMIO region ({phys_address:#08X} -> {virt_page:#08X})");
+
+        let mut io_apic = Self {
+            id,
+            address: virt_page as usize,
+            gsi_base,
+            redirection_count: 0,
+        };
+
+        // Bits 16-23 of the version register hold the index of the last redirection entry
+        io_apic.redirection_count = ((io_apic.read(REG_VERSION) >> 16) & 0xff) + 1;
+
+        for i in 0..io_apic.redirection_count {
+            io_apic.write_redirection(i, REDIRECTION_MASKED);
+        }
+
+        debug!(
+            "IOAPIC {id} handles GSIs {gsi_base}-{}",
+            gsi_base + io_apic.redirection_count - 1
+        );
+
+        io_apic
+    }
+
+    // The IOAPIC only exposes two registers: IOREGSEL at offset 0 selects which internal register
+    // IOWIN at offset 0x10 reads or writes
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            core::ptr::write_volatile(self.address as *mut u32, reg);
+            core::ptr::read_volatile((self.address + 0x10) as *const u32)
+        }
+    }
+
+    fn write(&self, reg: u32, val: u32) {
+        unsafe {
+            core::ptr::write_volatile(self.address as *mut u32, reg);
+            core::ptr::write_volatile((self.address + 0x10) as *mut u32, val);
+        }
+    }
+
+    fn read_redirection(&self, idx: u32) -> u64 {
+        let low = self.read(REG_REDIRECTION_TABLE + idx * 2);
+        let high = self.read(REG_REDIRECTION_TABLE + idx * 2 + 1);
+        ((high as u64) << 32) | (low as u64)
+    }
+
+    fn write_redirection(&self, idx: u32, val: u64) {
+        // Write the low half last since it holds the mask bit
+        self.write(REG_REDIRECTION_TABLE + idx * 2 + 1, (val >> 32) as u32);
+        self.write(REG_REDIRECTION_TABLE + idx * 2, val as u32);
+    }
+
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.redirection_count
+    }
+}
+
+static IO_APICS: Mutex<Vec<IoApic>> = Mutex::named("ioapic", Vec::new());
+
+pub fn init() {
+    let mut lock = IO_APICS.lock();
+    for io_apic in &acpi::madt().io_apics {
+        lock.push(IoApic::new(io_apic.id, io_apic.address, io_apic.gsi_base));
+    }
+
+    assert!(!lock.is_empty(), "No IOAPIC found");
+
+    info!("Initialized {} IOAPIC(s)", lock.len());
+}
+
+fn with_io_apic<T>(gsi: u32, f: impl FnOnce(&IoApic, u32) -> T) -> Option<T> {
+    let lock = IO_APICS.lock();
+    let io_apic = lock.iter().find(|x| x.handles(gsi))?;
+    Some(f(io_apic, gsi - io_apic.gsi_base))
+}
+
+// Routes a GSI to a vector on the LAPIC with the given ID. The entry stays masked until unmask()
+// is called
+pub fn route(gsi: u32, vector: u8, active_low: bool, level_triggered: bool, lapic_id: u8) -> bool {
+    with_io_apic(gsi, |io_apic, idx| {
+        let mut entry = (vector as u64) | REDIRECTION_MASKED | ((lapic_id as u64) << 56);
+        if active_low {
+            entry |= REDIRECTION_ACTIVE_LOW;
+        }
+        if level_triggered {
+            entry |= REDIRECTION_LEVEL_TRIGGERED;
+        }
+
+        io_apic.write_redirection(idx, entry);
+        trace!(
+            "IOAPIC {}: GSI {gsi} -> vector {vector:#x} (LAPIC {lapic_id}, {}, {})",
+            io_apic.id,
+            if active_low { "active low" } else { "active high" },
+            if level_triggered { "level" } else { "edge" }
+        );
+    }).is_some()
+}
+
+pub fn mask(gsi: u32) {
+    with_io_apic(gsi, |io_apic, idx| {
+        io_apic.write_redirection(idx, io_apic.read_redirection(idx) | REDIRECTION_MASKED);
+    });
+}
+
+pub fn unmask(gsi: u32) {
+    with_io_apic(gsi, |io_apic, idx| {
+        io_apic.write_redirection(idx, io_apic.read_redirection(idx) & !REDIRECTION_MASKED);
+    });
+}
@@ -0,0 +1,218 @@
+use core::sync::atomic::{ AtomicUsize, Ordering };
+
+use crate::{
+    info,
+    misc::acpi,
+    percpu,
+    sched,
+    sync::mutex::Mutex,
+    trace,
+    warning,
+    x86::{ ioapic, ioport::outb, lapic, percpu },
+};
+
+// The legacy PICs get remapped right above the exceptions so that their spurious interrupts
+// can't be mistaken for one
+pub const PIC_VECTOR_BASE: u8 = 0x20;
+pub const IRQ_VECTOR_BASE: u8 = 0x30;
+pub const IRQ_VECTOR_END: u8 = 0xf0;
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+pub type IrqHandler = fn(ctx: usize);
+
+#[derive(Clone, Copy)]
+struct IrqHandlerEntry {
+    handler: IrqHandler,
+    ctx: usize,
+}
+
+#[derive(Debug)]
+pub enum IrqError {
+    NoFreeVectors,
+    VectorInUse,
+    NoIoApicForGsi,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqRoute {
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+struct IrqTable {
+    handlers: [Option<IrqHandlerEntry>; 256],
+    allocated: [bool; 256],
+}
+
+percpu! {
+    // How many IRQ handlers are currently running on this CPU
+    static IRQ_DEPTH: AtomicUsize = AtomicUsize::new(0);
+}
+
+static IRQ_TABLE: Mutex<IrqTable> = Mutex::named("irq_table", IrqTable {
+    handlers: [None; 256],
+    allocated: [false; 256],
+});
+
+fn disable_pic() {
+    // ICW1: start initialization, expect ICW4
+    outb(0x20, 0x11);
+    outb(0xa0, 0x11);
+    // ICW2: vector offsets
+    outb(0x21, PIC_VECTOR_BASE);
+    outb(0xa1, PIC_VECTOR_BASE + 8);
+    // ICW3: the slave is on IRQ2 of the master
+    outb(0x21, 0b100);
+    outb(0xa1, 2);
+    // ICW4: 8086 mode
+    outb(0x21, 0x01);
+    outb(0xa1, 0x01);
+    // Mask everything, the IOAPIC takes over from here
+    outb(0x21, 0xff);
+    outb(0xa1, 0xff);
+}
+
+pub fn init() {
+    disable_pic();
+    trace!("Remapped and masked PIC");
+
+    lapic::init();
+    ioapic::init();
+
+    info!("Initialized IRQs");
+}
+
+pub fn allocate_vector() -> Result<u8, IrqError> {
+    let mut lock = IRQ_TABLE.lock();
+    for vector in IRQ_VECTOR_BASE..IRQ_VECTOR_END {
+        if !lock.allocated[vector as usize] {
+            lock.allocated[vector as usize] = true;
+            return Ok(vector);
+        }
+    }
+
+    Err(IrqError::NoFreeVectors)
+}
+
+// Keeps a vector that isn't used for IRQs from being handed out
+pub fn reserve_vector(vector: u8) -> Result<(), IrqError> {
+    let mut lock = IRQ_TABLE.lock();
+    if lock.allocated[vector as usize] {
+        return Err(IrqError::VectorInUse);
+    }
+
+    lock.allocated[vector as usize] = true;
+    Ok(())
+}
+
+pub fn free_vector(vector: u8) {
+    let mut lock = IRQ_TABLE.lock();
+    lock.handlers[vector as usize] = None;
+    lock.allocated[vector as usize] = false;
+}
+
+pub fn install_handler(vector: u8, handler: IrqHandler, ctx: usize) -> Result<(), IrqError> {
+    let mut lock = IRQ_TABLE.lock();
+    if lock.handlers[vector as usize].is_some() {
+        return Err(IrqError::VectorInUse);
+    }
+
+    lock.allocated[vector as usize] = true;
+    lock.handlers[vector as usize] = Some(IrqHandlerEntry { handler, ctx });
+    Ok(())
+}
+
+pub fn uninstall_handler(vector: u8) {
+    IRQ_TABLE.lock().handlers[vector as usize] = None;
+}
+
+// ISA IRQs are identity-mapped to GSIs, are edge-triggered and active high unless the MADT has an
+// interrupt source override for them
+pub fn isa_irq_route(irq: u8) -> IrqRoute {
+    irq_route(irq, false, false)
+}
+
+// The SCI is an ISA IRQ too, but the ACPI spec makes it level-triggered and active low unless the
+// MADT says otherwise
+pub fn sci_route(irq: u8) -> IrqRoute {
+    irq_route(irq, true, true)
+}
+
+// Applies the MADT's override for `irq`, if there is one, to the defaults the caller passes
+fn irq_route(irq: u8, active_low: bool, level_triggered: bool) -> IrqRoute {
+    let route = IrqRoute { gsi: irq as u32, active_low, level_triggered };
+    let Some(iso) = acpi
+        ::madt()
+        .overrides.iter()
+        .find(|x| x.bus == 0 && x.source == irq) else {
+        return route;
+    };
+
+    // Polarity and trigger mode are 2-bit fields where 0b00 means "conforms to the bus", which
+    // keeps the defaults. 0b01 means active high or edge-triggered and 0b11 the opposite
+    let polarity = iso.flags & 0b11;
+    let trigger = (iso.flags >> 2) & 0b11;
+
+    IrqRoute {
+        gsi: iso.gsi,
+        active_low: if polarity == 0b00 { active_low } else { polarity == 0b11 },
+        level_triggered: if trigger == 0b00 { level_triggered } else { trigger == 0b11 },
+    }
+}
+
+// Allocates a vector, installs the handler and routes the GSI to the bootstrap processor.
+// Returns the vector the GSI was routed to
+pub fn install_gsi_handler(
+    route: IrqRoute,
+    handler: IrqHandler,
+    ctx: usize
+) -> Result<u8, IrqError> {
+    let vector = allocate_vector()?;
+    install_handler(vector, handler, ctx)?;
+
+    if !ioapic::route(route.gsi, vector, route.active_low, route.level_triggered, lapic::id()) {
+        free_vector(vector);
+        return Err(IrqError::NoIoApicForGsi);
+    }
+
+    ioapic::unmask(route.gsi);
+    Ok(vector)
+}
+
+pub fn uninstall_gsi_handler(gsi: u32, vector: u8) {
+    ioapic::mask(gsi);
+    free_vector(vector);
+}
+
+// Called by the IDT code for every vector above the exceptions
+pub fn dispatch(vector: u8) {
+    if vector == SPURIOUS_VECTOR {
+        return;
+    }
+
+    if (PIC_VECTOR_BASE..IRQ_VECTOR_BASE).contains(&vector) {
+        // Spurious interrupt from the masked PIC, these don't get an EOI
+        return;
+    }
+
+    IRQ_DEPTH.get().fetch_add(1, Ordering::AcqRel);
+
+    // Copy the entry out so that the handler may install or uninstall handlers itself
+    let entry = IRQ_TABLE.lock().handlers[vector as usize];
+    if let Some(entry) = entry {
+        (entry.handler)(entry.ctx);
+    } else {
+        warning!("Unhandled interrupt on vector {vector:#x}");
+    }
+
+    IRQ_DEPTH.get().fetch_sub(1, Ordering::AcqRel);
+
+    lapic::eoi();
+
+    sched::preempt_from_irq();
+}
+
+pub fn in_interrupt() -> bool {
+    percpu::ready() && IRQ_DEPTH.get().load(Ordering::Acquire) != 0
+}
@@ -0,0 +1,182 @@
+use core::{ hint::spin_loop, sync::atomic::Ordering };
+
+use crate::{
+    debug,
+    info,
+    misc::acpi,
+    mm::{ virt_page_alloc, vmm },
+    sync::once::Once,
+    trace,
+    x86::{
+        idt::interrupt_control::IrqGuard,
+        irq::SPURIOUS_VECTOR,
+        msr::{ IA32_APIC_BASE, rdmsr, wrmsr },
+        percpu,
+    },
+};
+
+pub const REG_ID: usize = 0x20;
+pub const REG_VERSION: usize = 0x30;
+pub const REG_TPR: usize = 0x80;
+pub const REG_EOI: usize = 0xb0;
+pub const REG_SPURIOUS: usize = 0xf0;
+pub const REG_ESR: usize = 0x280;
+pub const REG_ICR_LOW: usize = 0x300;
+pub const REG_ICR_HIGH: usize = 0x310;
+pub const REG_LVT_TIMER: usize = 0x320;
+pub const REG_LVT_LINT0: usize = 0x350;
+pub const REG_LVT_LINT1: usize = 0x360;
+pub const REG_LVT_ERROR: usize = 0x370;
+pub const REG_TIMER_INITIAL_COUNT: usize = 0x380;
+pub const REG_TIMER_CURRENT_COUNT: usize = 0x390;
+pub const REG_TIMER_DIVIDE: usize = 0x3e0;
+
+// Setting this bit in a LVT entry masks the interrupt
+pub const LVT_MASKED: u32 = 1 << 16;
+// Bits 17-18 of the timer LVT select the timer mode, 0 being one-shot
+pub const LVT_TIMER_PERIODIC: u32 = 0b01 << 17;
+pub const LVT_TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+
+pub const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+pub const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+// Set while the LAPIC is still sending the previous IPI
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+pub const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+pub struct LocalApic {
+    address: usize,
+}
+
+impl LocalApic {
+    pub fn new(phys_address: u64) -> Self {
+        assert!(phys_address <= (u32::MAX as u64), "LAPIC address > 4GB");
+
+        let virt_page = virt_page_alloc
+            ::allocate(1)
+            .expect("Could not allocate virtual page to map LAPIC MMIO region");
+
+        vmm::map(phys_address as u32, virt_page, false, true, true, true);
+        trace!("Mapped LAPIC MMIO region ({phys_address:#08X} -> {virt_page:#08X})");
+
+        Self { address: virt_page as usize }
+    }
+
+    pub fn read(&self, reg: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.address + reg) as *const u32) }
+    }
+
+    pub fn write(&self, reg: usize, val: u32) {
+        unsafe {
+            core::ptr::write_volatile((self.address + reg) as *mut u32, val);
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        (self.read(REG_ID) >> 24) as u8
+    }
+
+    // Sets up the LAPIC of the CPU executing this
+    pub fn enable(&self) {
+        // The global enable bit lives in the APIC base MSR, the software enable bit lives in the
+        // spurious interrupt vector register
+        wrmsr(IA32_APIC_BASE, rdmsr(IA32_APIC_BASE) | (1 << 11));
+
+        self.write(REG_TPR, 0);
+        self.write(REG_LVT_TIMER, LVT_MASKED);
+        self.write(REG_LVT_ERROR, LVT_MASKED);
+        self.write(REG_LVT_LINT0, LVT_MASKED);
+        self.write(REG_LVT_LINT1, LVT_MASKED);
+
+        let id = self.id();
+        for nmi in &acpi::madt().lapic_nmis {
+            if nmi.processor_id != 0xff && !self.is_processor(nmi.processor_id, id) {
+                continue;
+            }
+
+            // Delivery mode NMI, polarity and trigger mode come from the MADT flags
+            let mut lvt = 0b100 << 8;
+            if (nmi.flags & 0b11) == 0b11 {
+                lvt |= 1 << 13;
+            }
+            if ((nmi.flags >> 2) & 0b11) == 0b11 {
+                lvt |= 1 << 15;
+            }
+
+            self.write(if nmi.lint == 0 { REG_LVT_LINT0 } else { REG_LVT_LINT1 }, lvt);
+        }
+
+        self.write(REG_SPURIOUS, (1 << 8) | (SPURIOUS_VECTOR as u32));
+
+        // The error status register has to be written before it is read
+        self.write(REG_ESR, 0);
+        self.write(REG_ESR, 0);
+
+        debug!("Enabled LAPIC {id} (version {:#x})", self.read(REG_VERSION) & 0xff);
+    }
+
+    fn is_processor(&self, processor_id: u8, apic_id: u8) -> bool {
+        acpi::madt()
+            .local_apics.iter()
+            .any(|x| x.processor_id == processor_id && x.apic_id == apic_id)
+    }
+
+    pub fn eoi(&self) {
+        self.write(REG_EOI, 0);
+    }
+}
+
+// Every CPU sees its own LAPIC at the same address, so a single mapping serves all of them
+static LAPIC: Once<LocalApic> = Once::new();
+
+fn lapic() -> &'static LocalApic {
+    LAPIC.get().expect("LAPIC used before lapic::init()")
+}
+
+pub fn init() {
+    let lapic = LAPIC.call_once(|| LocalApic::new(acpi::madt().lapic_address));
+    lapic.enable();
+    percpu::cpu().lapic_id.store(lapic.id() as u32, Ordering::Relaxed);
+    info!("Initialized LAPIC");
+}
+
+// Every AP has to enable its own LAPIC, the mapping is already there
+pub fn init_ap() {
+    lapic().enable();
+}
+
+pub fn initialized() -> bool {
+    LAPIC.is_completed()
+}
+
+pub fn eoi() {
+    lapic().eoi();
+}
+
+pub fn id() -> u8 {
+    lapic().id()
+}
+
+// Accesses a register of the LAPIC of the CPU executing this
+pub fn read(reg: usize) -> u32 {
+    lapic().read(reg)
+}
+
+pub fn write(reg: usize, val: u32) {
+    lapic().write(reg, val);
+}
+
+// Sends an IPI to the CPU with LAPIC ID `dest`. `icr` holds the delivery mode, vector and flags
+pub fn send_ipi(dest: u8, icr: u32) {
+    // Nothing may get in between writing the destination and the command
+    let _guard = IrqGuard::new();
+    while (read(REG_ICR_LOW) & ICR_DELIVERY_PENDING) != 0 {
+        spin_loop();
+    }
+
+    write(REG_ICR_HIGH, (dest as u32) << 24);
+    write(REG_ICR_LOW, icr);
+
+    while (read(REG_ICR_LOW) & ICR_DELIVERY_PENDING) != 0 {
+        spin_loop();
+    }
+}
@@ -1,7 +1,14 @@
 pub mod cpuid;
 pub mod gdt;
 pub mod idt;
+pub mod ioapic;
 pub mod ioport;
+pub mod irq;
+pub mod lapic;
+pub mod msr;
+pub mod percpu;
+pub mod smp;
+pub mod tss;
 
 // This halts the CPU (it can be woken up by a interrupt)
 pub fn halt() {
@@ -9,3 +16,11 @@ pub fn halt() {
         core::arch::asm!("hlt");
     }
 }
+
+// This enables interrupts and halts the CPU. sti only takes effect after the next instruction, so
+// no interrupt can sneak in between the two and leave us halted with nothing left to wake us up
+pub fn wait_for_interrupt() {
+    unsafe {
+        core::arch::asm!("sti", "hlt");
+    }
+}
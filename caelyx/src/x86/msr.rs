@@ -0,0 +1,27 @@
+// Model specific registers are read and written with the rdmsr/wrmsr instructions. The register
+// number goes in ecx and the 64-bit value is split between edx (high half) and eax (low half)
+pub fn rdmsr(msr: u32) -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        core::arch::asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high);
+    }
+    ((high as u64) << 32) | (low as u64)
+}
+
+pub fn wrmsr(msr: u32, val: u64) {
+    unsafe {
+        core::arch::asm!(
+            "wrmsr",
+            in("ecx") msr,
+            in("eax") val as u32,
+            in("edx") (val >> 32) as u32
+        );
+    }
+}
+
+pub const IA32_APIC_BASE: u32 = 0x1b;
+pub const IA32_SYSENTER_CS: u32 = 0x174;
+pub const IA32_SYSENTER_ESP: u32 = 0x175;
+pub const IA32_SYSENTER_EIP: u32 = 0x176;
+pub const IA32_TSC_DEADLINE: u32 = 0x6e0;
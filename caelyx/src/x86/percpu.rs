@@ -0,0 +1,219 @@
+use alloc::boxed::Box;
+use core::{
+    alloc::Layout,
+    cell::UnsafeCell,
+    ptr::{ null, null_mut },
+    sync::atomic::{ AtomicBool, AtomicPtr, AtomicU32, AtomicUsize, Ordering },
+};
+
+use crate::{ info, x86::{ gdt, idt::interrupt_control::IrqGuard } };
+
+pub const MAX_CPUS: usize = 16;
+
+// The .percpu section is only a template. Every CPU gets its own copy of it, the boot CPU's is
+// reserved by the linker script, so that it's there before the heap is
+unsafe extern "C" {
+    static PERCPU_START: u8;
+    static PERCPU_END: u8;
+    static PERCPU_BSP: u8;
+}
+
+// What gs points at on every CPU. The pointer to itself comes first, so that a normal reference
+// can be made out of gs:0
+#[repr(C)]
+pub struct CpuData {
+    self_ptr: *const CpuData,
+    // This CPU's copy of the .percpu section
+    area: *mut u8,
+    pub cpu_id: u32,
+    pub lapic_id: AtomicU32,
+    // Set once the CPU takes part in TLB shootdowns and IPIs in general
+    pub online: AtomicBool,
+    // ThreadId::to_usize() of the thread running on this CPU
+    pub current_thread: AtomicU32,
+    // How many IrqGuards are alive, and whether the outermost one found interrupts enabled
+    pub irq_depth: AtomicU32,
+    pub irq_restore: AtomicBool,
+    // For assembly that has to stash something before it can trust the stack
+    pub scratch: [AtomicUsize; 4],
+}
+
+// Everything in there is either only touched by its own CPU or atomic
+unsafe impl Sync for CpuData {}
+
+impl CpuData {
+    const fn new(self_ptr: *const CpuData, area: *mut u8, cpu_id: u32) -> Self {
+        Self {
+            self_ptr,
+            area,
+            cpu_id,
+            lapic_id: AtomicU32::new(0),
+            online: AtomicBool::new(false),
+            current_thread: AtomicU32::new(0),
+            irq_depth: AtomicU32::new(0),
+            irq_restore: AtomicBool::new(false),
+            scratch: [const { AtomicUsize::new(0) }; 4],
+        }
+    }
+}
+
+static BSP: CpuData = CpuData::new(&raw const BSP, &raw const PERCPU_BSP as *mut u8, 0);
+static CPUS: [AtomicPtr<CpuData>; MAX_CPUS] = [const { AtomicPtr::new(null_mut()) }; MAX_CPUS];
+// Until gs is set up everything runs on the boot CPU anyway
+static READY: AtomicBool = AtomicBool::new(false);
+
+// A variable with one instance per CPU, declared with percpu!
+#[repr(transparent)]
+pub struct PerCpuVar<T> {
+    template: UnsafeCell<T>,
+}
+
+// Each CPU only ever gets at its own copy, unless T is Sync
+unsafe impl<T> Sync for PerCpuVar<T> {}
+
+impl<T> PerCpuVar<T> {
+    pub const fn new(val: T) -> Self {
+        Self {
+            template: UnsafeCell::new(val),
+        }
+    }
+
+    fn ptr_in(&self, cpu: &CpuData) -> *mut T {
+        assert!(ready(), "Per-CPU variable used before percpu::init_bsp()");
+        let offset = (self.template.get() as usize) - (&raw const PERCPU_START as usize);
+        ((cpu.area as usize) + offset) as *mut T
+    }
+
+    // This CPU's copy. Only valid for as long as the thread can't end up on another CPU, so keep
+    // interrupts disabled while using it
+    pub fn as_ptr(&self) -> *mut T {
+        self.ptr_in(cpu())
+    }
+
+    // Runs `f` on this CPU's copy with interrupts disabled. Don't get at the same variable again
+    // from inside of `f`
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let _guard = IrqGuard::new();
+        f(unsafe { &mut *self.as_ptr() })
+    }
+}
+
+impl<T: Sync> PerCpuVar<T> {
+    // This CPU's copy, or the one of the CPU the thread was on when it called this
+    pub fn get(&self) -> &T {
+        unsafe { &*self.as_ptr() }
+    }
+
+    pub fn for_cpu(&self, cpu: usize) -> Option<&T> {
+        let cpu = CPUS.get(cpu)?.load(Ordering::Acquire);
+        if cpu.is_null() {
+            return None;
+        }
+
+        Some(unsafe { &*self.ptr_in(&*cpu) })
+    }
+}
+
+// Declares statics that every CPU has its own instance of
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            #[unsafe(link_section = ".percpu")]
+            $vis static $name: $crate::x86::percpu::PerCpuVar<$ty> =
+                $crate::x86::percpu::PerCpuVar::new($init);
+        )*
+    };
+}
+
+pub fn ready() -> bool {
+    READY.load(Ordering::Acquire)
+}
+
+pub fn cpu() -> &'static CpuData {
+    if !ready() {
+        return &BSP;
+    }
+
+    unsafe {
+        let data: *const CpuData;
+        core::arch::asm!(
+            "mov {data:e}, gs:[0]",
+            data = out(reg) data,
+            options(nostack, readonly, preserves_flags)
+        );
+        &*data
+    }
+}
+
+pub fn cpu_id() -> usize {
+    cpu().cpu_id as usize
+}
+
+pub fn for_cpu(cpu: usize) -> Option<&'static CpuData> {
+    let cpu = CPUS.get(cpu)?.load(Ordering::Acquire);
+    (!cpu.is_null()).then(|| unsafe { &*cpu })
+}
+
+fn template_size() -> usize {
+    (&raw const PERCPU_END as usize) - (&raw const PERCPU_START as usize)
+}
+
+// Points the per-CPU segment of the CPU at `data`. Returns the selector to load into gs
+fn register(data: &'static CpuData) -> u16 {
+    let cpu = data.cpu_id as usize;
+    gdt::set_percpu_segment(
+        cpu,
+        data as *const CpuData as u32,
+        core::mem::size_of::<CpuData>() as u32
+    );
+    CPUS[cpu].store(data as *const CpuData as *mut CpuData, Ordering::Release);
+
+    gdt::percpu_selector(cpu)
+}
+
+pub fn init_bsp() {
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            &raw const PERCPU_START,
+            &raw const PERCPU_BSP as *mut u8,
+            template_size()
+        );
+    }
+
+    let selector = register(&BSP);
+    unsafe {
+        core::arch::asm!(
+            "mov gs, {sel:x}",
+            sel = in(reg) selector,
+            options(nostack, preserves_flags)
+        );
+    }
+    READY.store(true, Ordering::Release);
+    BSP.online.store(true, Ordering::Release);
+
+    info!("Initialized per-CPU data ({} bytes per CPU)", template_size());
+}
+
+// Sets up the per-CPU data of an AP before it's started, since it needs gs to work before it runs
+// any Rust code. Returns the selector the AP has to load into gs
+pub fn prepare_ap(cpu: usize, lapic_id: u8) -> u16 {
+    assert!(cpu != 0 && cpu < MAX_CPUS, "Invalid AP number {cpu}");
+
+    let size = template_size();
+    let area = unsafe {
+        let area = alloc::alloc::alloc(
+            Layout::from_size_align(size.max(1), 64).expect("Invalid per-CPU area layout")
+        );
+        assert!(!area.is_null(), "Could not allocate per-CPU area for CPU {cpu}");
+        core::ptr::copy_nonoverlapping(&raw const PERCPU_START, area, size);
+        area
+    };
+
+    let data = Box::leak(Box::new(CpuData::new(null(), area, cpu as u32)));
+    data.self_ptr = data as *const CpuData;
+    data.lapic_id.store(lapic_id as u32, Ordering::Relaxed);
+
+    register(data)
+}
@@ -0,0 +1,197 @@
+use core::{ sync::atomic::{ AtomicBool, AtomicU32, Ordering }, time::Duration };
+
+use crate::{
+    debug,
+    info,
+    misc::acpi,
+    sched::stack::KernelStack,
+    syscall,
+    sync::timer::{ self, lapic_timer, monotonic_ns },
+    warning,
+    x86::{
+        gdt::SharedGdtrAndIdtr,
+        idt::{ self, interrupt_control::disable_interrupts },
+        lapic::{ self, ICR_DELIVERY_INIT, ICR_DELIVERY_STARTUP, ICR_LEVEL_ASSERT },
+        percpu::{ self, MAX_CPUS },
+        tss,
+        wait_for_interrupt,
+    },
+};
+
+// Has to match trampoline.asm. The page has to be below 1MB, and the PMM never hands out memory
+// from down there
+const TRAMPOLINE_BASE: usize = 0x8000;
+
+const INIT_DELAY: Duration = Duration::from_millis(10);
+const SIPI_TIMEOUT: Duration = Duration::from_millis(1);
+const STARTUP_TIMEOUT: Duration = Duration::from_millis(1000);
+
+// Bit 0 of the MADT LAPIC flags
+const LAPIC_ENABLED: u32 = 1 << 0;
+
+unsafe extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_params: u8;
+}
+
+// Filled in for every AP before it gets started, see the PARAM_* offsets in trampoline.asm
+#[repr(C, packed)]
+struct TrampolineParams {
+    gdtr: SharedGdtrAndIdtr,
+    _reserved: u16,
+    cr0: u32,
+    cr3: u32,
+    cr4: u32,
+    stack: u32,
+    gs: u32,
+    cpu: u32,
+}
+
+#[derive(Debug)]
+pub enum SmpError {
+    OutOfMemory,
+    Timeout,
+}
+
+static ONLINE: AtomicU32 = AtomicU32::new(1);
+// Set by an AP once it's running Rust code and done with the trampoline
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+pub fn cpu_count() -> usize {
+    ONLINE.load(Ordering::Acquire) as usize
+}
+
+fn trampoline_params() -> *mut TrampolineParams {
+    let start = &raw const ap_trampoline_start as usize;
+    let offset = (&raw const ap_trampoline_params as usize) - start;
+    (TRAMPOLINE_BASE + offset) as *mut TrampolineParams
+}
+
+fn wait_for_ap(timeout: Duration) -> bool {
+    let deadline_ns = monotonic_ns() + (timeout.as_nanos() as u64);
+    while monotonic_ns() < deadline_ns {
+        if AP_STARTED.load(Ordering::Acquire) {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+
+    AP_STARTED.load(Ordering::Acquire)
+}
+
+fn start_ap(cpu: usize, apic_id: u8) -> Result<(), SmpError> {
+    let stack = KernelStack::new().ok_or(SmpError::OutOfMemory)?;
+    let gs = percpu::prepare_ap(cpu, apic_id);
+
+    let mut gdtr = SharedGdtrAndIdtr { limit: 0, base: 0 };
+    let (cr0, cr3, cr4): (u32, u32, u32);
+    unsafe {
+        core::arch::asm!("sgdt [{}]", in(reg) &raw mut gdtr);
+        core::arch::asm!("mov {:e}, cr0", out(reg) cr0);
+        core::arch::asm!("mov {:e}, cr3", out(reg) cr3);
+        core::arch::asm!("mov {:e}, cr4", out(reg) cr4);
+
+        trampoline_params().write_volatile(TrampolineParams {
+            gdtr,
+            _reserved: 0,
+            cr0,
+            cr3,
+            cr4,
+            stack: stack.top(),
+            gs: gs as u32,
+            cpu: cpu as u32,
+        });
+    }
+
+    AP_STARTED.store(false, Ordering::Release);
+
+    // INIT resets the AP and leaves it waiting for a SIPI. The second SIPI is only for CPUs that
+    // missed the first one
+    lapic::send_ipi(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+    timer::delay(INIT_DELAY);
+    for _ in 0..2 {
+        lapic::send_ipi(apic_id, ICR_DELIVERY_STARTUP | ((TRAMPOLINE_BASE >> 12) as u32));
+        if wait_for_ap(SIPI_TIMEOUT) {
+            break;
+        }
+    }
+
+    let started = wait_for_ap(STARTUP_TIMEOUT);
+
+    // The AP runs on it for good. One that timed out may still have gotten as far as the stack
+    // before it gets parked, so that one isn't safe to give back either
+    core::mem::forget(stack);
+
+    if !started {
+        // Parks it in wait-for-SIPI, so that it can't wake up later and run into the next AP
+        lapic::send_ipi(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+        return Err(SmpError::Timeout);
+    }
+
+    Ok(())
+}
+
+pub fn init() {
+    let start = &raw const ap_trampoline_start;
+    let size = (&raw const ap_trampoline_end as usize) - (start as usize);
+    unsafe {
+        core::ptr::copy_nonoverlapping(start, TRAMPOLINE_BASE as *mut u8, size);
+    }
+    debug!("Copied {size} bytes of AP trampoline to {TRAMPOLINE_BASE:#x}");
+
+    let bsp = lapic::id();
+    let mut next_cpu = 1;
+    for cpu in &acpi::madt().local_apics {
+        if cpu.apic_id == bsp || (cpu.flags & LAPIC_ENABLED) == 0 {
+            continue;
+        }
+        if next_cpu == MAX_CPUS {
+            warning!("More than {MAX_CPUS} CPUs, ignoring the rest");
+            break;
+        }
+
+        match start_ap(next_cpu, cpu.apic_id) {
+            Ok(()) => {
+                next_cpu += 1;
+            }
+            Err(err) => {
+                warning!("Could not start CPU with LAPIC ID {}: {err:?}", cpu.apic_id);
+
+                // Its per-CPU data may still be in use by it, so the index is gone either way
+                if matches!(err, SmpError::Timeout) {
+                    next_cpu += 1;
+                }
+            }
+        }
+    }
+
+    info!("Initialized SMP, {} CPUs online", cpu_count());
+}
+
+// Where APs end up once the trampoline has them in protected mode with paging on
+#[unsafe(no_mangle)]
+extern "C" fn caelyx_ap_main(cpu: u32) -> ! {
+    idt::load_idt();
+    tss::init();
+    syscall::init_ap();
+    lapic::init_ap();
+    if lapic_timer::initialized() {
+        lapic_timer::init_cpu();
+    }
+    timer::start_cpu_tick();
+
+    percpu::cpu().online.store(true, Ordering::Release);
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+    AP_STARTED.store(true, Ordering::Release);
+    info!("CPU {cpu} is online (LAPIC ID {})", lapic::id());
+
+    // Nothing is scheduled on APs yet, so they're idle for good and only wake up for their own
+    // tick and IPIs
+    loop {
+        disable_interrupts();
+        timer::idle_enter();
+        wait_for_interrupt();
+        timer::idle_exit();
+    }
+}
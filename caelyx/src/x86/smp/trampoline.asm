@@ -0,0 +1,84 @@
+; Where application processors start out. The SIPI starts an AP off in real mode at a page below
+; 1MB, so smp.rs copies everything between ap_trampoline_start and ap_trampoline_end to
+; TRAMPOLINE_BASE first and fills in the parameters at the end. That code only switches to
+; protected mode and jumps into ap_protected_entry, which runs from the kernel image like the rest
+
+TRAMPOLINE_BASE equ 0x8000
+
+; Has to match TrampolineParams in smp/mod.rs
+PARAM_GDTR equ 0
+PARAM_CR0 equ 8
+PARAM_CR3 equ 12
+PARAM_CR4 equ 16
+PARAM_STACK equ 20
+PARAM_GS equ 24
+PARAM_CPU equ 28
+
+%include "gdt.inc"
+
+%define TRAMPOLINE_ADDR(label) (TRAMPOLINE_BASE + (label - ap_trampoline_start))
+
+section .rodata
+
+global ap_trampoline_start
+global ap_trampoline_end
+global ap_trampoline_params
+
+bits 16
+ap_trampoline_start:
+    cli
+    cld
+
+    xor ax, ax
+    mov ds, ax
+
+    ; The GDT is the kernel's, so the selectors are the same as on the BSP
+    o32 lgdt [TRAMPOLINE_ADDR(ap_trampoline_params) + PARAM_GDTR]
+
+    mov eax, cr0
+    or eax, 1
+    mov cr0, eax
+
+    jmp dword GDT_CODE:ap_protected_entry
+
+align 8
+ap_trampoline_params:
+    times 32 db 0
+ap_trampoline_end:
+
+extern caelyx_ap_main
+
+section .text
+bits 32
+ap_protected_entry:
+    mov ax, GDT_DATA
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov ss, ax
+
+    mov ebx, TRAMPOLINE_ADDR(ap_trampoline_params)
+
+    ; Per-CPU data has to work before any Rust code runs, since every lock goes through it
+    mov ax, [ebx + PARAM_GS]
+    mov gs, ax
+
+    ; Turn paging on the same way the BSP has it. The first 4MB are identity-mapped, so we keep
+    ; running from the same addresses
+    mov eax, [ebx + PARAM_CR4]
+    mov cr4, eax
+    mov eax, [ebx + PARAM_CR3]
+    mov cr3, eax
+    mov eax, [ebx + PARAM_CR0]
+    mov cr0, eax
+
+    mov esp, [ebx + PARAM_STACK]
+    xor ebp, ebp
+
+    push dword [ebx + PARAM_CPU]
+    call caelyx_ap_main
+
+.hang:
+    cli
+    hlt
+    jmp .hang
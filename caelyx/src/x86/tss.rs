@@ -0,0 +1,54 @@
+use core::sync::atomic::{ AtomicU32, Ordering };
+
+use crate::{ percpu, trace, x86::{ gdt::{ self, GDT_DATA }, percpu } };
+
+// We don't do hardware task switching, so all that's used of the TSS is the stack the CPU switches
+// to when an interrupt comes in while it's in ring 3
+#[repr(C)]
+struct Tss {
+    link: u32,
+    esp0: AtomicU32,
+    ss0: u32,
+    // esp1 up to the LDT selector
+    unused: [u32; 22],
+    trap: u16,
+    iomap_base: u16,
+}
+
+impl Tss {
+    const fn new() -> Self {
+        Self {
+            link: 0,
+            esp0: AtomicU32::new(0),
+            ss0: GDT_DATA as u32,
+            unused: [0; 22],
+            trap: 0,
+            // Past the end, so there's no I/O permission bitmap and ring 3 can't touch any port
+            iomap_base: core::mem::size_of::<Tss>() as u16,
+        }
+    }
+}
+
+percpu! {
+    static TSS: Tss = Tss::new();
+}
+
+// Has to run on every CPU once its per-CPU data is set up
+pub fn init() {
+    let cpu = percpu::cpu_id();
+    gdt::set_tss(cpu, TSS.as_ptr() as u32, core::mem::size_of::<Tss>() as u32);
+    unsafe {
+        core::arch::asm!(
+            "ltr {sel:x}",
+            sel = in(reg) gdt::tss_selector(cpu),
+            options(nostack, preserves_flags)
+        );
+    }
+
+    trace!("Loaded TSS of CPU {cpu}");
+}
+
+// The stack interrupts from ring 3 start out on, the top of the running thread's kernel stack
+pub fn set_kernel_stack(top: u32) {
+    TSS.get().esp0.store(top, Ordering::Relaxed);
+}
@@ -2,7 +2,7 @@
 
 set -xe
 
-FLAGS="-M q35 -no-reboot -serial stdio -cpu qemu64"
+FLAGS="-M q35 -no-reboot -serial stdio -cpu qemu64 -smp ${SMP:-4}"
 
 if [ "$GDB" == "true" ]; then
   FLAGS="${FLAGS} -S -s"